@@ -0,0 +1,201 @@
+use std::{
+    env,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use teloxide::types::UserId;
+
+/// Bot configuration read from the environment
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Bot operator, allowed to run admin commands in every chat
+    pub owner_id: Option<UserId>,
+    /// How many tag messages may be in flight at once across every chat
+    pub max_concurrent_sends: usize,
+    /// Most users tracked in a single chat, the least recently active ones are evicted past it
+    pub max_users_per_chat: Option<usize>,
+    /// Refresh the admins of every known chat when the bot starts
+    pub startup_admin_sync: bool,
+    /// During the startup admin sync, also remove tracked users that are no longer members
+    pub startup_admin_sync_prune: bool,
+    /// Unix permissions applied to the database file and its backups, such as 0o600
+    pub db_file_mode: Option<u32>,
+    /// JSON backup imported at startup when the database is empty
+    pub restore_from: Option<PathBuf>,
+    /// Import `restore_from` even into a database that already has data
+    pub restore_force: bool,
+    /// Longest message accepted along with /all, in characters
+    pub max_tag_text_len: usize,
+    /// Cooldown of /all in a chat after a tag, in seconds, before adding the per user part
+    pub tag_cooldown_base_secs: u64,
+    /// Seconds added to the /all cooldown for every user tagged
+    pub tag_cooldown_per_user_secs: f64,
+    /// Longest /all cooldown, in seconds, however big the chat is
+    pub tag_cooldown_max_secs: u64,
+    /// Most /all tags a chat can send in a day, counted from midnight in the chat's time zone
+    pub max_tags_per_day: Option<usize>,
+    /// Answer messages sent to the bot in a private chat with the commands available there
+    pub private_chat_menu: bool,
+    /// Minutes between two admin syncs of the chats with `auto_admin_sync` on
+    pub admin_sync_interval_mins: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            owner_id: None,
+            max_concurrent_sends: 10,
+            max_users_per_chat: None,
+            startup_admin_sync: false,
+            startup_admin_sync_prune: false,
+            db_file_mode: None,
+            restore_from: None,
+            restore_force: false,
+            max_tag_text_len: 1000,
+            tag_cooldown_base_secs: 0,
+            tag_cooldown_per_user_secs: 0.0,
+            tag_cooldown_max_secs: 3600,
+            max_tags_per_day: None,
+            private_chat_menu: true,
+            admin_sync_interval_mins: 60,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration from the environment, keeping defaults for unset variables
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        Self {
+            owner_id: parse_var("OWNER_ID").map(UserId),
+            max_concurrent_sends: parse_var("MAX_CONCURRENT_SENDS")
+                .filter(|n| *n > 0)
+                .unwrap_or(defaults.max_concurrent_sends),
+            max_users_per_chat: parse_var("MAX_USERS_PER_CHAT").filter(|n| *n > 0),
+            startup_admin_sync: parse_var("STARTUP_ADMIN_SYNC")
+                .unwrap_or(defaults.startup_admin_sync),
+            startup_admin_sync_prune: parse_var("STARTUP_ADMIN_SYNC_PRUNE")
+                .unwrap_or(defaults.startup_admin_sync_prune),
+            db_file_mode: parse_file_mode("DB_FILE_MODE"),
+            restore_from: parse_var::<PathBuf>("RESTORE_FROM")
+                .filter(|path| !path.as_os_str().is_empty()),
+            restore_force: parse_var("RESTORE_FORCE").unwrap_or(defaults.restore_force),
+            max_tag_text_len: parse_var("MAX_TAG_TEXT_LEN")
+                .filter(|n| *n > 0)
+                .unwrap_or(defaults.max_tag_text_len),
+            tag_cooldown_base_secs: parse_var("TAG_COOLDOWN_BASE_SECS")
+                .unwrap_or(defaults.tag_cooldown_base_secs),
+            tag_cooldown_per_user_secs: parse_var("TAG_COOLDOWN_PER_USER_SECS")
+                .filter(|n: &f64| n.is_finite() && *n >= 0.0)
+                .unwrap_or(defaults.tag_cooldown_per_user_secs),
+            tag_cooldown_max_secs: parse_var("TAG_COOLDOWN_MAX_SECS")
+                .unwrap_or(defaults.tag_cooldown_max_secs),
+            max_tags_per_day: parse_var("MAX_TAGS_PER_DAY").filter(|n| *n > 0),
+            private_chat_menu: parse_var("PRIVATE_CHAT_MENU").unwrap_or(defaults.private_chat_menu),
+            admin_sync_interval_mins: parse_var("ADMIN_SYNC_INTERVAL_MINS")
+                .filter(|n| *n > 0)
+                .unwrap_or(defaults.admin_sync_interval_mins),
+        }
+    }
+
+    /// Takes the hot-reloadable settings from `fresh` and keeps the startup-only ones,
+    /// also returning the startup-only settings that changed and need a restart
+    pub fn reloaded(&self, fresh: Config) -> (Config, Vec<&'static str>) {
+        let mut needs_restart = Vec::new();
+        if fresh.max_concurrent_sends != self.max_concurrent_sends {
+            needs_restart.push("MAX_CONCURRENT_SENDS");
+        }
+        if fresh.startup_admin_sync != self.startup_admin_sync {
+            needs_restart.push("STARTUP_ADMIN_SYNC");
+        }
+        if fresh.startup_admin_sync_prune != self.startup_admin_sync_prune {
+            needs_restart.push("STARTUP_ADMIN_SYNC_PRUNE");
+        }
+        if fresh.db_file_mode != self.db_file_mode {
+            needs_restart.push("DB_FILE_MODE");
+        }
+        if fresh.restore_from != self.restore_from {
+            needs_restart.push("RESTORE_FROM");
+        }
+        if fresh.restore_force != self.restore_force {
+            needs_restart.push("RESTORE_FORCE");
+        }
+
+        let config = Config {
+            owner_id: fresh.owner_id,
+            max_users_per_chat: fresh.max_users_per_chat,
+            max_tag_text_len: fresh.max_tag_text_len,
+            tag_cooldown_base_secs: fresh.tag_cooldown_base_secs,
+            tag_cooldown_per_user_secs: fresh.tag_cooldown_per_user_secs,
+            tag_cooldown_max_secs: fresh.tag_cooldown_max_secs,
+            max_tags_per_day: fresh.max_tags_per_day,
+            private_chat_menu: fresh.private_chat_menu,
+            admin_sync_interval_mins: fresh.admin_sync_interval_mins,
+            ..self.clone()
+        };
+        (config, needs_restart)
+    }
+
+    /// Cooldown of /all after tagging `user_count` users, so bigger chats are tagged less often
+    pub fn tag_cooldown(&self, user_count: usize) -> Duration {
+        let secs = self.tag_cooldown_base_secs as f64
+            + self.tag_cooldown_per_user_secs * user_count as f64;
+        Duration::from_secs_f64(secs.min(self.tag_cooldown_max_secs as f64))
+    }
+
+    /// Time between two admin syncs of the chats with `auto_admin_sync` on
+    pub fn admin_sync_interval(&self) -> Duration {
+        Duration::from_secs(self.admin_sync_interval_mins * 60)
+    }
+
+    /// Whether the given user is the configured bot owner
+    pub fn is_owner(&self, user_id: UserId) -> bool {
+        self.owner_id == Some(user_id)
+    }
+}
+
+/// Configuration shared with the handlers, replaced as a whole by /reload
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Config>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        Self(Arc::new(RwLock::new(config)))
+    }
+
+    /// Snapshot of the current configuration, so a handler sees consistent values while it runs
+    pub fn get(&self) -> Config {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replaces the configuration seen by handlers started from now on
+    pub fn set(&self, config: Config) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = config;
+    }
+}
+
+/// Parses an octal file mode such as `600` from an environment variable, warning about invalid values
+fn parse_file_mode(name: &str) -> Option<u32> {
+    let value = env::var(name).ok()?;
+    match u32::from_str_radix(value.trim().trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o777 => Some(mode),
+        _ => {
+            log::warn!("Ignoring invalid value for {}: {}", name, value);
+            None
+        }
+    }
+}
+
+/// Parses an environment variable, warning about (and ignoring) invalid values
+fn parse_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.trim().parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            log::warn!("Ignoring invalid value for {}: {}", name, value);
+            None
+        }
+    }
+}
@@ -1,16 +1,262 @@
-use rusqlite::{Connection, Result, params};
+use crate::{export::JsonBackup, settings::ChatSettings};
+use rusqlite::{Connection, OptionalExtension, Result, Row, params};
+use std::{
+    fmt,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Shared handle to the database connection
+///
+/// Queries are synchronous, so they run on the blocking thread pool instead of stalling the async workers.
+#[derive(Clone)]
+pub struct Db(Arc<Mutex<Connection>>);
+
+impl Db {
+    pub fn new(conn: Connection) -> Self {
+        Self(Arc::new(Mutex::new(conn)))
+    }
+
+    /// Runs `f` with the connection on the blocking thread pool and returns its result
+    pub async fn call<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Connection) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let conn = self.0.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&conn)
+        })
+        .await
+        .expect("database task panicked")
+    }
+
+    /// Runs `f` with the connection on the current thread, for code already off the async workers
+    pub fn blocking_call<R>(&self, f: impl FnOnce(&Connection) -> R) -> R {
+        let conn = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        f(&conn)
+    }
+}
+
+/// File the database is stored in
+pub const DB_FILE: &str = "tagger.db";
+
+/// How many days of sent tags are kept in the tag log
+const TAG_LOG_RETENTION_DAYS: i64 = 30;
+/// How many days of per-day activity are kept for /activity
+pub const ACTIVITY_RETENTION_DAYS: i64 = 90;
+
+/// How many days reactions to messages are kept for /tagreactors
+pub const REACTION_RETENTION_DAYS: i64 = 14;
+/// How many roster snapshots are kept per chat for /growth
+const SNAPSHOT_RETENTION: i64 = 100;
+
+/// Chat id used by the startup self-test, never a real Telegram chat
+const SELF_TEST_CHAT_ID: i64 = 0;
+
+/// Name Telegram shows for accounts that have been deleted
+pub const DELETED_ACCOUNT_NAME: &str = "Deleted Account";
 
 /// Represents a tracked user in a chat
 #[derive(Debug, Clone)]
 pub struct User {
     pub user_id: i64,
     pub first_name: String,
+    pub username: Option<String>,
+    /// Name pinned by an admin with /rename, takes precedence over `first_name`
+    pub override_name: Option<String>,
+    /// Label set by an admin with /nick, shown next to the name in mentions
+    pub label: Option<String>,
+    /// Moderation note set by an admin with /note, never shown in mentions
+    pub note: Option<String>,
+    /// How many messages the user sent in the chat since message counting was added
+    pub message_count: i64,
+    /// Whether /cantag found that id link mentions notify the user, `None` until tested
+    pub id_mention_works: Option<bool>,
+    /// Whether the last private message sent to the user failed because they blocked the bot
+    pub blocked_bot: bool,
+    /// Cohort the user belongs to, set by an admin or from the join month, tagged with /tagcohort
+    pub cohort: Option<String>,
+    /// Set by an admin with /alwaystag, tagged even when activity or membership filters would leave them out
+    pub always_tag: bool,
+    /// Unix timestamp of the last /all or /tag that mentioned the user, used by `--next` rounds
+    pub last_tagged: Option<i64>,
+    /// Unix timestamp of the last message sent by the user in the chat
+    pub last_seen: Option<i64>,
+    /// Unix timestamp of when the user was last seen joining the chat
+    pub joined_at: Option<i64>,
+    /// How the user wants to be reached by tags
+    pub notify_pref: NotifyPref,
+}
+
+/// How a user wants to be reached by tags, chosen with /prefs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NotifyPref {
+    /// Tagged with a regular notification
+    #[default]
+    Loud,
+    /// Tagged in messages sent without notification sound
+    Silent,
+    /// Not tagged at all
+    Off,
+}
+
+impl NotifyPref {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NotifyPref::Loud => "loud",
+            NotifyPref::Silent => "silent",
+            NotifyPref::Off => "off",
+        }
+    }
+}
+
+impl FromStr for NotifyPref {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "loud" => Ok(NotifyPref::Loud),
+            "silent" => Ok(NotifyPref::Silent),
+            "off" => Ok(NotifyPref::Off),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for NotifyPref {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Summary of a chat known to the bot
+#[derive(Debug, Clone)]
+pub struct ChatSummary {
+    pub chat_id: i64,
+    pub title: Option<String>,
+    pub user_count: i64,
+}
+
+/// Columns selected for every `User`, in the order expected by `user_from_row`
+const USER_COLUMNS: &str = "user_id, first_name, username, override_name, last_seen, joined_at, \
+    notify_pref, label, note, message_count, id_mention_works, blocked_bot, cohort, \
+    always_tag, last_tagged";
+
+fn user_from_row(row: &Row) -> Result<User> {
+    Ok(User {
+        user_id: row.get(0)?,
+        first_name: row.get(1)?,
+        username: row.get(2)?,
+        override_name: row.get(3)?,
+        last_seen: row.get(4)?,
+        joined_at: row.get(5)?,
+        notify_pref: row.get::<_, String>(6)?.parse().unwrap_or_default(),
+        label: row.get(7)?,
+        note: row.get(8)?,
+        message_count: row.get(9)?,
+        id_mention_works: row.get(10)?,
+        blocked_bot: row.get(11)?,
+        cohort: row.get(12)?,
+        always_tag: row.get(13)?,
+        last_tagged: row.get(14)?,
+    })
+}
+
+/// Current time as a unix timestamp
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl User {
+    /// A user that isn't stored yet, with everything but the Telegram data left empty
+    pub fn new(user_id: i64, first_name: &str, username: Option<&str>) -> Self {
+        Self {
+            user_id,
+            first_name: first_name.to_string(),
+            username: username.map(String::from),
+            override_name: None,
+            label: None,
+            note: None,
+            message_count: 0,
+            id_mention_works: None,
+            blocked_bot: false,
+            cohort: None,
+            always_tag: false,
+            last_tagged: None,
+            last_seen: None,
+            joined_at: None,
+            notify_pref: NotifyPref::default(),
+        }
+    }
+
+    /// Whether the stored name is the placeholder Telegram uses for deleted accounts
+    ///
+    /// This is only a heuristic, a real user could pick the same name.
+    pub fn looks_deleted(&self) -> bool {
+        self.first_name == DELETED_ACCOUNT_NAME
+    }
+
+    /// Name shown in mentions, honoring any admin override
+    pub fn display_name(&self) -> &str {
+        self.override_name.as_deref().unwrap_or(&self.first_name)
+    }
+}
+
+/// A tag sent every day at the same time, set up with /schedule
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub id: i64,
+    pub chat_id: i64,
+    /// Minutes after midnight, in the chat's time, at which the tag is sent
+    pub minute_of_day: i64,
+    pub message: String,
+    /// Date (YYYY-MM-DD, in the chat's time) the tag was last sent on
+    pub last_fired_on: Option<String>,
+}
+
+/// A tag sent once at a given time, set with /scheduletag
+#[derive(Debug, Clone)]
+pub struct PendingTag {
+    pub id: i64,
+    pub chat_id: i64,
+    /// Unix timestamp the tag is sent at
+    pub fire_at: i64,
+    pub message: String,
+}
+
+/// The size of a chat's roster at some point, taken with /snapshot
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Unix timestamp the snapshot was taken at
+    pub taken_at: i64,
+    pub user_count: i64,
+    /// Hash of the tracked user ids, to tell whether the roster changed at all
+    pub list_hash: String,
 }
 
 /// Initialize the database and create the users table if it doesn't exist
 pub fn init_db() -> Result<Connection> {
-    let conn = Connection::open("tagger.db")?;
+    check_db_path(Path::new(DB_FILE)).map_err(|reason| {
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CANTOPEN),
+            Some(reason),
+        )
+    })?;
+    let conn = Connection::open(DB_FILE)?;
+    create_schema(&conn)?;
+
+    Ok(conn)
+}
 
+/// Create the tables that don't exist yet and bring the older ones up to date
+pub fn create_schema(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS users (
             chat_id INTEGER NOT NULL,
@@ -21,42 +267,1890 @@ pub fn init_db() -> Result<Connection> {
         [],
     )?;
 
-    Ok(conn)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS chats (
+            chat_id INTEGER PRIMARY KEY,
+            title TEXT,
+            last_updated INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS chat_settings (
+            chat_id INTEGER PRIMARY KEY,
+            min_membership_hours INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schedules (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            chat_id INTEGER NOT NULL,
+            minute_of_day INTEGER NOT NULL,
+            message TEXT NOT NULL,
+            last_fired_on TEXT
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tag_groups (
+            chat_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            user_id INTEGER NOT NULL,
+            PRIMARY KEY (chat_id, name, user_id)
+        )",
+        [],
+    )?;
+
+    // Every /all sent, for the daily quota
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tag_log (
+            chat_id INTEGER NOT NULL,
+            sent_at INTEGER NOT NULL,
+            user_count INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS tag_log_chat ON tag_log (chat_id, sent_at)",
+        [],
+    )?;
+
+    // Which users wrote on each day (days since the unix epoch, in UTC), for /activity
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS daily_activity (
+            chat_id INTEGER NOT NULL,
+            day INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            PRIMARY KEY (chat_id, day, user_id)
+        )",
+        [],
+    )?;
+
+    // Only the latest /rollcall of each chat is kept
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rollcalls (
+            chat_id INTEGER PRIMARY KEY,
+            started_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rollcall_messages (
+            chat_id INTEGER NOT NULL,
+            message_id INTEGER NOT NULL,
+            PRIMARY KEY (chat_id, message_id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rollcall_users (
+            chat_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            reacted INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (chat_id, user_id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pending_tags (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            chat_id INTEGER NOT NULL,
+            fire_at INTEGER NOT NULL,
+            message TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS group_admins (
+            chat_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            user_id INTEGER NOT NULL,
+            PRIMARY KEY (chat_id, name, user_id)
+        )",
+        [],
+    )?;
+
+    // Who reacted to which message, for /tagreactors
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_reactions (
+            chat_id INTEGER NOT NULL,
+            message_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            reacted_at INTEGER NOT NULL,
+            PRIMARY KEY (chat_id, message_id, user_id)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS snapshots (
+            chat_id INTEGER NOT NULL,
+            taken_at INTEGER NOT NULL,
+            user_count INTEGER NOT NULL,
+            list_hash TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS snapshots_chat ON snapshots (chat_id, taken_at)",
+        [],
+    )?;
+
+    migrate(conn)
+}
+
+/// Bring older databases up to date by adding columns introduced after the first release
+fn migrate(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "users", "username", "TEXT")?;
+    add_column_if_missing(conn, "users", "override_name", "TEXT")?;
+    add_column_if_missing(conn, "users", "joined_at", "INTEGER")?;
+    add_column_if_missing(conn, "users", "last_seen", "INTEGER")?;
+    add_column_if_missing(conn, "users", "notify_pref", "TEXT NOT NULL DEFAULT 'loud'")?;
+    add_column_if_missing(conn, "users", "label", "TEXT")?;
+    add_column_if_missing(conn, "users", "note", "TEXT")?;
+    add_column_if_missing(conn, "users", "message_count", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "users", "id_mention_works", "INTEGER")?;
+    add_column_if_missing(conn, "users", "blocked_bot", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "users", "cohort", "TEXT")?;
+    add_column_if_missing(conn, "users", "always_tag", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "users", "last_tagged", "INTEGER")?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "skip_deleted_accounts",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "parse_mode",
+        "TEXT NOT NULL DEFAULT 'markdown'",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "mention_style",
+        "TEXT NOT NULL DEFAULT 'plain'",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "utc_offset_minutes",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(conn, "chat_settings", "exclude_pattern", "TEXT")?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "track_membership",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "auto_cohort",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "reply_to_command",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "verbosity",
+        "TEXT NOT NULL DEFAULT 'normal'",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "skip_author",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "compact_mentions",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "ephemeral",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(conn, "chat_settings", "timezone", "TEXT")?;
+    add_column_if_missing(conn, "chat_settings", "empty_roster_message", "TEXT")?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "interleave",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        conn,
+        "chat_settings",
+        "auto_admin_sync",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+
+    Ok(())
+}
+
+/// Makes sure the database can be opened for writing at `path`, explaining what's wrong otherwise
+///
+/// SQLite only reports "unable to open database file" whatever the cause, which is confusing
+/// when a container mounts a directory or a read-only volume in its place.
+fn check_db_path(path: &Path) -> std::result::Result<(), String> {
+    if path.is_dir() {
+        return Err(format!(
+            "{} is a directory, it should be the database file (or not exist yet)",
+            path.display()
+        ));
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if !parent.is_dir() {
+        return Err(format!(
+            "the directory {} of the database doesn't exist",
+            parent.display()
+        ));
+    }
+
+    // Opening for writing creates the file when missing, SQLite is fine with an empty file
+    match std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+    {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(format!(
+            "permission denied, {} isn't writable by the user running the bot",
+            if path.exists() {
+                path.display().to_string()
+            } else {
+                format!("the directory {}", parent.display())
+            }
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::ReadOnlyFilesystem => {
+            Err(format!("{} is on a read-only filesystem", path.display()))
+        }
+        Err(e) => Err(format!("can't open {} for writing: {}", path.display(), e)),
+    }
+}
+
+/// Add a column to a table unless it already exists
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+        log::info!("Migrated database: added {}.{}", table, column);
+    }
+
+    Ok(())
 }
 
 /// Insert or update a user in the database
-pub fn upsert_user(conn: &Connection, chat_id: i64, user_id: i64, first_name: &str) -> Result<()> {
+///
+/// With `max_users` set, a newly tracked user makes room for themselves by evicting the least
+/// recently active users of the chat.
+pub fn upsert_user(
+    conn: &Connection,
+    chat_id: i64,
+    user_id: i64,
+    first_name: &str,
+    username: Option<&str>,
+    max_users: Option<usize>,
+) -> Result<()> {
+    let is_new = max_users.is_some() && get_user(conn, chat_id, user_id)?.is_none();
+
     conn.execute(
-        "INSERT INTO users (chat_id, user_id, first_name)
-         VALUES (?1, ?2, ?3)
+        "INSERT INTO users (chat_id, user_id, first_name, username)
+         VALUES (?1, ?2, ?3, ?4)
          ON CONFLICT(chat_id, user_id) DO UPDATE SET
-            first_name = excluded.first_name",
-        params![chat_id, user_id, first_name],
+            first_name = excluded.first_name,
+            username = excluded.username",
+        params![chat_id, user_id, first_name, username],
     )?;
 
+    if let Some(max_users) = max_users
+        && is_new
+    {
+        evict_over_limit(conn, chat_id, user_id, max_users)?;
+    }
+
     Ok(())
 }
 
-/// Get all tracked users for a specific chat
-pub fn get_users_for_chat(conn: &Connection, chat_id: i64) -> Result<Vec<User>> {
-    let mut stmt = conn.prepare("SELECT user_id, first_name FROM users WHERE chat_id = ?1")?;
+/// Deletes the least recently active users of a chat (never `keep_user_id`) until at most `max_users` remain
+fn evict_over_limit(
+    conn: &Connection,
+    chat_id: i64,
+    keep_user_id: i64,
+    max_users: usize,
+) -> Result<()> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM users WHERE chat_id = ?1",
+        [chat_id],
+        |row| row.get(0),
+    )?;
+    let excess = count - max_users as i64;
+    if excess <= 0 {
+        return Ok(());
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT user_id, first_name FROM users
+         WHERE chat_id = ?1 AND user_id != ?2
+         ORDER BY COALESCE(last_seen, joined_at, 0), user_id
+         LIMIT ?3",
+    )?;
+    let evicted = stmt
+        .query_map(params![chat_id, keep_user_id, excess], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    for (user_id, first_name) in evicted {
+        delete_user(conn, chat_id, user_id)?;
+        log::info!(
+            "[{}] Evicted least recently active user {} (ID: {}) to stay within {} users",
+            chat_id,
+            first_name,
+            user_id,
+            max_users
+        );
+    }
+
+    Ok(())
+}
+
+/// Insert or update a chat and its title, marking it as seen now
+pub fn upsert_chat(conn: &Connection, chat_id: i64, title: Option<&str>) -> Result<()> {
+    conn.execute(
+        "INSERT INTO chats (chat_id, title, last_updated)
+         VALUES (?1, ?2, ?3)
+         ON CONFLICT(chat_id) DO UPDATE SET
+            title = COALESCE(excluded.title, chats.title),
+            last_updated = excluded.last_updated",
+        params![chat_id, title, unix_now()],
+    )?;
+
+    Ok(())
+}
+
+/// Get the stored title of a chat
+pub fn get_chat_title(conn: &Connection, chat_id: i64) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT title FROM chats WHERE chat_id = ?1",
+        [chat_id],
+        |row| row.get(0),
+    )
+    .optional()
+    .map(Option::flatten)
+}
+
+/// Get the settings of a chat, falling back to the defaults for chats that never changed them
+pub fn get_chat_settings(conn: &Connection, chat_id: i64) -> Result<ChatSettings> {
+    let settings = conn
+        .query_row(
+            "SELECT min_membership_hours, skip_deleted_accounts, parse_mode, mention_style,
+                utc_offset_minutes, exclude_pattern, track_membership, auto_cohort,
+                reply_to_command, verbosity, skip_author, compact_mentions, ephemeral,
+                timezone, empty_roster_message, interleave, auto_admin_sync
+             FROM chat_settings WHERE chat_id = ?1",
+            [chat_id],
+            |row| {
+                Ok(ChatSettings {
+                    min_membership_hours: row.get(0)?,
+                    skip_deleted_accounts: row.get(1)?,
+                    parse_mode: row.get::<_, String>(2)?.parse().unwrap_or_default(),
+                    mention_style: row.get::<_, String>(3)?.parse().unwrap_or_default(),
+                    utc_offset_minutes: row.get(4)?,
+                    exclude_pattern: row.get(5)?,
+                    track_membership: row.get(6)?,
+                    auto_cohort: row.get(7)?,
+                    reply_to_command: row.get(8)?,
+                    verbosity: row.get::<_, String>(9)?.parse().unwrap_or_default(),
+                    skip_author: row.get(10)?,
+                    compact_mentions: row.get(11)?,
+                    ephemeral: row.get(12)?,
+                    timezone: row
+                        .get::<_, Option<String>>(13)?
+                        .and_then(|zone| zone.parse().ok()),
+                    empty_roster_message: row.get(14)?,
+                    interleave: row.get(15)?,
+                    auto_admin_sync: row.get(16)?,
+                })
+            },
+        )
+        .optional()?;
+
+    Ok(settings.unwrap_or_default())
+}
+
+/// Store the settings of a chat
+pub fn save_chat_settings(conn: &Connection, chat_id: i64, settings: &ChatSettings) -> Result<()> {
+    conn.execute(
+        "INSERT INTO chat_settings (
+            chat_id, min_membership_hours, skip_deleted_accounts, parse_mode, mention_style,
+            utc_offset_minutes, exclude_pattern, track_membership, auto_cohort,
+            reply_to_command, verbosity, skip_author, compact_mentions, ephemeral, timezone,
+            empty_roster_message, interleave, auto_admin_sync
+         )
+         VALUES (
+            ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18
+         )
+         ON CONFLICT(chat_id) DO UPDATE SET
+            min_membership_hours = excluded.min_membership_hours,
+            skip_deleted_accounts = excluded.skip_deleted_accounts,
+            parse_mode = excluded.parse_mode,
+            mention_style = excluded.mention_style,
+            utc_offset_minutes = excluded.utc_offset_minutes,
+            exclude_pattern = excluded.exclude_pattern,
+            track_membership = excluded.track_membership,
+            auto_cohort = excluded.auto_cohort,
+            reply_to_command = excluded.reply_to_command,
+            verbosity = excluded.verbosity,
+            skip_author = excluded.skip_author,
+            compact_mentions = excluded.compact_mentions,
+            ephemeral = excluded.ephemeral,
+            timezone = excluded.timezone,
+            empty_roster_message = excluded.empty_roster_message,
+            interleave = excluded.interleave,
+            auto_admin_sync = excluded.auto_admin_sync",
+        params![
+            chat_id,
+            settings.min_membership_hours,
+            settings.skip_deleted_accounts,
+            settings.parse_mode.to_string(),
+            settings.mention_style.to_string(),
+            settings.utc_offset_minutes,
+            settings.exclude_pattern,
+            settings.track_membership,
+            settings.auto_cohort,
+            settings.reply_to_command,
+            settings.verbosity.to_string(),
+            settings.skip_author,
+            settings.compact_mentions,
+            settings.ephemeral,
+            settings.timezone.map(|tz| tz.name()),
+            settings.empty_roster_message,
+            settings.interleave,
+            settings.auto_admin_sync
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// List every chat in the database with its stored title and number of tracked users
+pub fn list_chats(conn: &Connection) -> Result<Vec<ChatSummary>> {
+    let mut stmt = conn.prepare(
+        "SELECT ids.chat_id, chats.title,
+            (SELECT COUNT(*) FROM users WHERE users.chat_id = ids.chat_id)
+         FROM (SELECT chat_id FROM users UNION SELECT chat_id FROM chats) ids
+         LEFT JOIN chats ON chats.chat_id = ids.chat_id
+         ORDER BY ids.chat_id",
+    )?;
 
-    let users = stmt.query_map([chat_id], |row| {
-        Ok(User {
-            user_id: row.get(0)?,
-            first_name: row.get(1)?,
+    let chats = stmt.query_map([], |row| {
+        Ok(ChatSummary {
+            chat_id: row.get(0)?,
+            title: row.get(1)?,
+            user_count: row.get(2)?,
         })
     })?;
 
+    chats.collect()
+}
+
+/// List the chats a user is tracked in as (chat id, title), sorted by title
+pub fn chats_for_user(conn: &Connection, user_id: i64) -> Result<Vec<(i64, Option<String>)>> {
+    let mut stmt = conn.prepare(
+        "SELECT users.chat_id, chats.title FROM users
+         LEFT JOIN chats ON chats.chat_id = users.chat_id
+         WHERE users.user_id = ?1
+         ORDER BY chats.title, users.chat_id",
+    )?;
+
+    let chats = stmt.query_map([user_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    chats.collect()
+}
+
+/// Get a value from the meta table, used to remember one-shot maintenance tasks
+pub fn get_meta(conn: &Connection, key: &str) -> Result<Option<String>> {
+    conn.query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+        row.get(0)
+    })
+    .optional()
+}
+
+/// Set a value in the meta table
+pub fn set_meta(conn: &Connection, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )?;
+
+    Ok(())
+}
+
+/// Get the ids of the chats whose admins are synced in the background
+pub fn get_auto_admin_sync_chat_ids(conn: &Connection) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare("SELECT chat_id FROM chat_settings WHERE auto_admin_sync = 1")?;
+    let chat_ids = stmt.query_map([], |row| row.get(0))?;
+
+    chat_ids.collect()
+}
+
+/// Get the ids of every chat that has tracked users
+pub fn get_tracked_chat_ids(conn: &Connection) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare("SELECT DISTINCT chat_id FROM users")?;
+
+    let chat_ids = stmt.query_map([], |row| row.get(0))?;
+
+    chat_ids.collect()
+}
+
+/// Get all tracked users for a specific chat
+pub fn get_users_for_chat(conn: &Connection, chat_id: i64) -> Result<Vec<User>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM users WHERE chat_id = ?1",
+        USER_COLUMNS
+    ))?;
+
+    let users = stmt.query_map([chat_id], user_from_row)?;
+
     users.collect()
 }
 
-/// Delete a user from a specific chat (when they leave)
-pub fn delete_user(conn: &Connection, chat_id: i64, user_id: i64) -> Result<()> {
+/// Remove every tracked user of a chat, returning them, when the chat switches to ephemeral mode
+pub fn take_chat_users(conn: &Connection, chat_id: i64) -> Result<Vec<User>> {
+    let tx = conn.unchecked_transaction()?;
+    let users = get_users_for_chat(&tx, chat_id)?;
+    for user in &users {
+        delete_user(&tx, chat_id, user.user_id)?;
+    }
+    tx.commit()?;
+
+    Ok(users)
+}
+
+/// Store users back with every column, such as the ones taken with `take_chat_users` when the chat
+/// leaves ephemeral mode
+///
+/// Like `upsert_user`, newly tracked users evict the least recently active ones beyond `max_users`.
+pub fn restore_chat_users(
+    conn: &Connection,
+    chat_id: i64,
+    users: &[User],
+    max_users: Option<usize>,
+) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for user in users {
+        let is_new = max_users.is_some() && get_user(&tx, chat_id, user.user_id)?.is_none();
+        tx.execute(
+            "INSERT INTO users (
+                chat_id, user_id, first_name, username, override_name, last_seen, joined_at,
+                notify_pref, label, note, message_count, id_mention_works, blocked_bot, cohort,
+                always_tag, last_tagged
+             )
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
+             ON CONFLICT(chat_id, user_id) DO UPDATE SET
+                first_name = excluded.first_name,
+                username = excluded.username,
+                override_name = excluded.override_name,
+                last_seen = excluded.last_seen,
+                joined_at = excluded.joined_at,
+                notify_pref = excluded.notify_pref,
+                label = excluded.label,
+                note = excluded.note,
+                message_count = excluded.message_count,
+                id_mention_works = excluded.id_mention_works,
+                blocked_bot = excluded.blocked_bot,
+                cohort = excluded.cohort,
+                always_tag = excluded.always_tag,
+                last_tagged = excluded.last_tagged",
+            params![
+                chat_id,
+                user.user_id,
+                user.first_name,
+                user.username,
+                user.override_name,
+                user.last_seen,
+                user.joined_at,
+                user.notify_pref.as_str(),
+                user.label,
+                user.note,
+                user.message_count,
+                user.id_mention_works,
+                user.blocked_bot,
+                user.cohort,
+                user.always_tag,
+                user.last_tagged
+            ],
+        )?;
+
+        if let Some(max_users) = max_users
+            && is_new
+        {
+            evict_over_limit(&tx, chat_id, user.user_id, max_users)?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Get the tracked users of a chat that belong to a tag group
+pub fn get_group_users(conn: &Connection, chat_id: i64, name: &str) -> Result<Vec<User>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM users WHERE chat_id = ?1 AND user_id IN \
+         (SELECT user_id FROM tag_groups WHERE chat_id = ?1 AND name = ?2)",
+        USER_COLUMNS
+    ))?;
+
+    let users = stmt.query_map(params![chat_id, name], user_from_row)?;
+
+    users.collect()
+}
+
+/// Get the tracked users of a cohort
+pub fn get_cohort_users(conn: &Connection, chat_id: i64, cohort: &str) -> Result<Vec<User>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM users WHERE chat_id = ?1 AND cohort = ?2",
+        USER_COLUMNS
+    ))?;
+
+    let users = stmt.query_map(params![chat_id, cohort], user_from_row)?;
+
+    users.collect()
+}
+
+/// Set (or clear with `None`) the cohort of a user
+pub fn set_cohort(
+    conn: &Connection,
+    chat_id: i64,
+    user_id: i64,
+    cohort: Option<&str>,
+) -> Result<()> {
     conn.execute(
-        "DELETE FROM users WHERE chat_id = ?1 AND user_id = ?2",
-        params![chat_id, user_id],
+        "UPDATE users SET cohort = ?3 WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id, cohort],
+    )?;
+
+    Ok(())
+}
+
+/// Set whether a user is tagged even when filters would leave them out
+pub fn set_always_tag(
+    conn: &Connection,
+    chat_id: i64,
+    user_id: i64,
+    always_tag: bool,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET always_tag = ?3 WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id, always_tag],
+    )?;
+
+    Ok(())
+}
+
+/// Put a user that just joined in a cohort, unless an admin already gave them one
+pub fn assign_join_cohort(
+    conn: &Connection,
+    chat_id: i64,
+    user_id: i64,
+    cohort: &str,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET cohort = ?3 WHERE chat_id = ?1 AND user_id = ?2 AND cohort IS NULL",
+        params![chat_id, user_id, cohort],
     )?;
 
     Ok(())
 }
+
+/// List the cohorts of a chat with how many tracked users each one has
+pub fn list_cohorts(conn: &Connection, chat_id: i64) -> Result<Vec<(String, i64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT cohort, COUNT(*) FROM users WHERE chat_id = ?1 AND cohort IS NOT NULL
+         GROUP BY cohort ORDER BY cohort",
+    )?;
+
+    let cohorts = stmt.query_map([chat_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    cohorts.collect()
+}
+
+/// Add a user to a tag group, returning whether they weren't in it already
+pub fn add_group_member(conn: &Connection, chat_id: i64, name: &str, user_id: i64) -> Result<bool> {
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO tag_groups (chat_id, name, user_id) VALUES (?1, ?2, ?3)",
+        params![chat_id, name, user_id],
+    )?;
+
+    Ok(added > 0)
+}
+
+/// Remove a user from a tag group, returning whether they were in it
+pub fn remove_group_member(
+    conn: &Connection,
+    chat_id: i64,
+    name: &str,
+    user_id: i64,
+) -> Result<bool> {
+    let removed = conn.execute(
+        "DELETE FROM tag_groups WHERE chat_id = ?1 AND name = ?2 AND user_id = ?3",
+        params![chat_id, name, user_id],
+    )?;
+
+    Ok(removed > 0)
+}
+
+/// List the tag groups of a chat with their member counts, sorted by name
+pub fn list_groups(conn: &Connection, chat_id: i64) -> Result<Vec<(String, i64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, COUNT(*) FROM tag_groups WHERE chat_id = ?1 GROUP BY name ORDER BY name",
+    )?;
+
+    let groups = stmt.query_map([chat_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    groups.collect()
+}
+
+/// Outcome of renaming a tag group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupRename {
+    /// The chat has no group with the old name
+    Missing,
+    /// The chat already has a group with the new name
+    Taken,
+    /// The group was renamed, moving this many members
+    Renamed(usize),
+}
+
+/// Rename a tag group of a chat along with its managers, unless the new name is already used
+pub fn rename_group(
+    conn: &Connection,
+    chat_id: i64,
+    old_name: &str,
+    new_name: &str,
+) -> Result<GroupRename> {
+    let tx = conn.unchecked_transaction()?;
+
+    let exists = |name: &str| -> Result<bool> {
+        tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM tag_groups WHERE chat_id = ?1 AND name = ?2)",
+            params![chat_id, name],
+            |row| row.get(0),
+        )
+    };
+    if !exists(old_name)? {
+        return Ok(GroupRename::Missing);
+    }
+    if exists(new_name)? {
+        return Ok(GroupRename::Taken);
+    }
+
+    let moved = tx.execute(
+        "UPDATE tag_groups SET name = ?3 WHERE chat_id = ?1 AND name = ?2",
+        params![chat_id, old_name, new_name],
+    )?;
+    // Managers of a group that had no members left may still be around under the new name
+    tx.execute(
+        "UPDATE OR IGNORE group_admins SET name = ?3 WHERE chat_id = ?1 AND name = ?2",
+        params![chat_id, old_name, new_name],
+    )?;
+    tx.execute(
+        "DELETE FROM group_admins WHERE chat_id = ?1 AND name = ?2",
+        params![chat_id, old_name],
+    )?;
+
+    tx.commit()?;
+    Ok(GroupRename::Renamed(moved))
+}
+
+/// Let a user manage a tag group, returning whether they couldn't already
+pub fn add_group_admin(conn: &Connection, chat_id: i64, name: &str, user_id: i64) -> Result<bool> {
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO group_admins (chat_id, name, user_id) VALUES (?1, ?2, ?3)",
+        params![chat_id, name, user_id],
+    )?;
+
+    Ok(added > 0)
+}
+
+/// Stop a user from managing a tag group, returning whether they could
+pub fn remove_group_admin(
+    conn: &Connection,
+    chat_id: i64,
+    name: &str,
+    user_id: i64,
+) -> Result<bool> {
+    let removed = conn.execute(
+        "DELETE FROM group_admins WHERE chat_id = ?1 AND name = ?2 AND user_id = ?3",
+        params![chat_id, name, user_id],
+    )?;
+
+    Ok(removed > 0)
+}
+
+/// Whether a user may manage a tag group without being a chat admin
+pub fn is_group_admin(conn: &Connection, chat_id: i64, name: &str, user_id: i64) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM group_admins WHERE chat_id = ?1 AND name = ?2 AND user_id = ?3)",
+        params![chat_id, name, user_id],
+        |row| row.get(0),
+    )
+}
+
+/// List the managers of every tag group of a chat as (group, user id), sorted by group
+pub fn list_group_admins(conn: &Connection, chat_id: i64) -> Result<Vec<(String, i64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, user_id FROM group_admins WHERE chat_id = ?1 ORDER BY name, user_id",
+    )?;
+
+    let admins = stmt.query_map([chat_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    admins.collect()
+}
+
+/// Get a single tracked user of a chat
+pub fn get_user(conn: &Connection, chat_id: i64, user_id: i64) -> Result<Option<User>> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM users WHERE chat_id = ?1 AND user_id = ?2",
+            USER_COLUMNS
+        ),
+        params![chat_id, user_id],
+        user_from_row,
+    )
+    .optional()
+}
+
+/// Get a tracked user of a chat by username, ignoring case
+pub fn get_user_by_username(
+    conn: &Connection,
+    chat_id: i64,
+    username: &str,
+) -> Result<Option<User>> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM users WHERE chat_id = ?1 AND username = ?2 COLLATE NOCASE",
+            USER_COLUMNS
+        ),
+        params![chat_id, username],
+        user_from_row,
+    )
+    .optional()
+}
+
+/// Search the tracked users of a chat whose name or username contains `query` (case-insensitive)
+pub fn search_users(
+    conn: &Connection,
+    chat_id: i64,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<User>> {
+    let escaped = query
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let pattern = format!("%{}%", escaped);
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM users
+         WHERE chat_id = ?1
+            AND (first_name LIKE ?2 ESCAPE '\\' OR username LIKE ?2 ESCAPE '\\')
+         ORDER BY first_name
+         LIMIT ?3",
+        USER_COLUMNS
+    ))?;
+
+    let users = stmt.query_map(params![chat_id, pattern, limit as i64], user_from_row)?;
+
+    users.collect()
+}
+
+/// Get the most recently joined users of a chat, newest first
+pub fn get_newest_users(conn: &Connection, chat_id: i64, limit: usize) -> Result<Vec<User>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM users
+         WHERE chat_id = ?1 AND joined_at IS NOT NULL
+         ORDER BY joined_at DESC
+         LIMIT ?2",
+        USER_COLUMNS
+    ))?;
+
+    let users = stmt.query_map(params![chat_id, limit as i64], user_from_row)?;
+
+    users.collect()
+}
+
+/// Set how a user wants to be reached by tags in a chat, returning whether the user is tracked
+pub fn set_notify_pref(
+    conn: &Connection,
+    chat_id: i64,
+    user_id: i64,
+    notify_pref: NotifyPref,
+) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE users SET notify_pref = ?3 WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id, notify_pref.as_str()],
+    )?;
+
+    Ok(updated > 0)
+}
+
+/// Record that a user just sent a message in a chat, counting it and marking them active today
+pub fn touch_last_seen(conn: &Connection, chat_id: i64, user_id: i64) -> Result<()> {
+    let now = unix_now();
+    conn.execute(
+        "UPDATE users SET last_seen = ?3, message_count = message_count + 1
+         WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id, now],
+    )?;
+
+    // Days follow the chat's time zone so the activity chart splits them at local midnight
+    let today = get_chat_settings(conn, chat_id)?.local_day(now);
+    let first_today = conn.execute(
+        "INSERT OR IGNORE INTO daily_activity (chat_id, day, user_id) VALUES (?1, ?2, ?3)",
+        params![chat_id, today, user_id],
+    )?;
+    // Once a day per user is plenty to keep the history trimmed
+    if first_today > 0 {
+        conn.execute(
+            "DELETE FROM daily_activity WHERE chat_id = ?1 AND day < ?2",
+            params![chat_id, today - ACTIVITY_RETENTION_DAYS],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Record that the given users of a chat were just mentioned by a tag
+pub fn mark_tagged(conn: &Connection, chat_id: i64, user_ids: &[i64]) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let now = unix_now();
+    for user_id in user_ids {
+        tx.execute(
+            "UPDATE users SET last_tagged = ?3 WHERE chat_id = ?1 AND user_id = ?2",
+            params![chat_id, user_id, now],
+        )?;
+    }
+    tx.commit()
+}
+
+/// Record that a tag of `user_count` users was just sent in a chat
+pub fn log_tag(conn: &Connection, chat_id: i64, user_count: usize) -> Result<()> {
+    conn.execute(
+        "INSERT INTO tag_log (chat_id, sent_at, user_count) VALUES (?1, ?2, ?3)",
+        params![chat_id, unix_now(), user_count as i64],
+    )?;
+
+    // Only the last day matters for the quota, keep a few more for reference
+    conn.execute(
+        "DELETE FROM tag_log WHERE chat_id = ?1 AND sent_at < ?2",
+        params![chat_id, unix_now() - TAG_LOG_RETENTION_DAYS * 86400],
+    )?;
+
+    Ok(())
+}
+
+/// How many tags a chat sent since its local midnight
+pub fn tags_today(conn: &Connection, chat_id: i64) -> Result<i64> {
+    let midnight = get_chat_settings(conn, chat_id)?.local_midnight();
+
+    conn.query_row(
+        "SELECT COUNT(*) FROM tag_log WHERE chat_id = ?1 AND sent_at >= ?2",
+        params![chat_id, midnight],
+        |row| row.get(0),
+    )
+}
+
+/// How many users wrote in a chat on each day from `since_day` on (days since the unix epoch),
+/// days nobody wrote on are left out
+pub fn get_daily_activity(
+    conn: &Connection,
+    chat_id: i64,
+    since_day: i64,
+) -> Result<Vec<(i64, i64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT day, COUNT(*) FROM daily_activity
+         WHERE chat_id = ?1 AND day >= ?2
+         GROUP BY day ORDER BY day",
+    )?;
+
+    let days = stmt.query_map(params![chat_id, since_day], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+
+    days.collect()
+}
+
+/// The roster of a chat as it is now, without storing it
+///
+/// `memory` holds the users of an ephemeral chat that aren't stored, counted along the others.
+pub fn current_snapshot(conn: &Connection, chat_id: i64, memory: &[i64]) -> Result<Snapshot> {
+    let mut stmt = conn.prepare("SELECT user_id FROM users WHERE chat_id = ?1")?;
+    let mut user_ids = stmt
+        .query_map([chat_id], |row| row.get::<_, i64>(0))?
+        .collect::<Result<Vec<_>>>()?;
+    user_ids.extend_from_slice(memory);
+    user_ids.sort_unstable();
+    user_ids.dedup();
+
+    // FNV-1a, stable across builds unlike the standard library's hasher
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in user_ids.iter().flat_map(|id| id.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    Ok(Snapshot {
+        taken_at: unix_now(),
+        user_count: user_ids.len() as i64,
+        list_hash: format!("{:016x}", hash),
+    })
+}
+
+/// Store the current roster of a chat as a snapshot and return it
+pub fn take_snapshot(conn: &Connection, chat_id: i64, memory: &[i64]) -> Result<Snapshot> {
+    let snapshot = current_snapshot(conn, chat_id, memory)?;
+    conn.execute(
+        "INSERT INTO snapshots (chat_id, taken_at, user_count, list_hash) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            chat_id,
+            snapshot.taken_at,
+            snapshot.user_count,
+            snapshot.list_hash
+        ],
+    )?;
+
+    conn.execute(
+        "DELETE FROM snapshots WHERE chat_id = ?1 AND rowid NOT IN (
+            SELECT rowid FROM snapshots WHERE chat_id = ?1 ORDER BY taken_at DESC LIMIT ?2
+        )",
+        params![chat_id, SNAPSHOT_RETENTION],
+    )?;
+
+    Ok(snapshot)
+}
+
+/// The newest snapshot of a chat taken at or before `before`
+pub fn get_snapshot_before(
+    conn: &Connection,
+    chat_id: i64,
+    before: i64,
+) -> Result<Option<Snapshot>> {
+    conn.query_row(
+        "SELECT taken_at, user_count, list_hash FROM snapshots
+         WHERE chat_id = ?1 AND taken_at <= ?2
+         ORDER BY taken_at DESC, rowid DESC LIMIT 1",
+        params![chat_id, before],
+        |row| {
+            Ok(Snapshot {
+                taken_at: row.get(0)?,
+                user_count: row.get(1)?,
+                list_hash: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Record that a user just joined a chat
+pub fn mark_joined(conn: &Connection, chat_id: i64, user_id: i64) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET joined_at = ?3 WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id, unix_now()],
+    )?;
+
+    Ok(())
+}
+
+/// Set (or clear with `None`) the moderation note of a user
+pub fn set_note(conn: &Connection, chat_id: i64, user_id: i64, note: Option<&str>) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET note = ?3 WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id, note],
+    )?;
+
+    Ok(())
+}
+
+/// Record whether id link mentions notify a user, as reported from /cantag
+pub fn set_id_mention_works(
+    conn: &Connection,
+    chat_id: i64,
+    user_id: i64,
+    works: bool,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET id_mention_works = ?3 WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id, works],
+    )?;
+
+    Ok(())
+}
+
+/// Why a private message couldn't be delivered to a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Undeliverable {
+    /// The account was deleted or banned by Telegram
+    Deactivated,
+    /// The user blocked the bot
+    BlockedBot,
+}
+
+/// Record that a private message couldn't reach a user, returning how many chats they were removed from
+///
+/// Deactivated accounts can't be tagged anymore so they are removed from every chat, users that
+/// only blocked the bot are still reached by tags in groups so they are just marked.
+pub fn mark_and_maybe_delete(
+    conn: &Connection,
+    user_id: i64,
+    reason: Undeliverable,
+) -> Result<usize> {
+    if reason == Undeliverable::BlockedBot {
+        conn.execute(
+            "UPDATE users SET blocked_bot = 1 WHERE user_id = ?1",
+            [user_id],
+        )?;
+        return Ok(0);
+    }
+
+    delete_user_everywhere(conn, user_id)
+}
+
+/// Remove a user and everything stored about them from every chat, returning how many chats they were in
+pub fn delete_user_everywhere(conn: &Connection, user_id: i64) -> Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let chat_ids = {
+        let mut stmt = tx.prepare("SELECT chat_id FROM users WHERE user_id = ?1")?;
+        stmt.query_map([user_id], |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>>>()?
+    };
+    for &chat_id in &chat_ids {
+        delete_user(&tx, chat_id, user_id)?;
+    }
+    // Reactions are recorded in chats the user isn't tracked in too
+    tx.execute(
+        "DELETE FROM message_reactions WHERE user_id = ?1",
+        [user_id],
+    )?;
+    tx.commit()?;
+
+    Ok(chat_ids.len())
+}
+
+/// Set (or clear with `None`) the label shown next to a user's name in mentions
+pub fn set_label(conn: &Connection, chat_id: i64, user_id: i64, label: Option<&str>) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET label = ?3 WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id, label],
+    )?;
+
+    Ok(())
+}
+
+/// Pin (or clear with `None`) the name used to mention a user, `upsert_user` never touches it
+pub fn set_override_name(
+    conn: &Connection,
+    chat_id: i64,
+    user_id: i64,
+    override_name: Option<&str>,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET override_name = ?3 WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id, override_name],
+    )?;
+
+    Ok(())
+}
+
+/// Move every tracked user of a chat to a new chat id (group upgraded to supergroup)
+///
+/// Users already tracked in the new chat keep their row there. Returns how many rows were moved.
+pub fn migrate_chat(conn: &Connection, old_chat_id: i64, new_chat_id: i64) -> Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+
+    let moved = tx.execute(
+        "UPDATE OR IGNORE users SET chat_id = ?2 WHERE chat_id = ?1",
+        params![old_chat_id, new_chat_id],
+    )?;
+    tx.execute("DELETE FROM users WHERE chat_id = ?1", [old_chat_id])?;
+    tx.execute(
+        "UPDATE schedules SET chat_id = ?2 WHERE chat_id = ?1",
+        params![old_chat_id, new_chat_id],
+    )?;
+    tx.execute(
+        "UPDATE OR IGNORE tag_groups SET chat_id = ?2 WHERE chat_id = ?1",
+        params![old_chat_id, new_chat_id],
+    )?;
+    tx.execute("DELETE FROM tag_groups WHERE chat_id = ?1", [old_chat_id])?;
+    tx.execute(
+        "UPDATE OR IGNORE group_admins SET chat_id = ?2 WHERE chat_id = ?1",
+        params![old_chat_id, new_chat_id],
+    )?;
+    tx.execute("DELETE FROM group_admins WHERE chat_id = ?1", [old_chat_id])?;
+    tx.execute(
+        "UPDATE pending_tags SET chat_id = ?2 WHERE chat_id = ?1",
+        params![old_chat_id, new_chat_id],
+    )?;
+    tx.execute(
+        "UPDATE tag_log SET chat_id = ?2 WHERE chat_id = ?1",
+        params![old_chat_id, new_chat_id],
+    )?;
+    tx.execute(
+        "UPDATE snapshots SET chat_id = ?2 WHERE chat_id = ?1",
+        params![old_chat_id, new_chat_id],
+    )?;
+    for table in [
+        "rollcalls",
+        "rollcall_messages",
+        "rollcall_users",
+        "daily_activity",
+        "message_reactions",
+        "chat_settings",
+    ] {
+        tx.execute(
+            &format!(
+                "UPDATE OR IGNORE {} SET chat_id = ?2 WHERE chat_id = ?1",
+                table
+            ),
+            params![old_chat_id, new_chat_id],
+        )?;
+        tx.execute(
+            &format!("DELETE FROM {} WHERE chat_id = ?1", table),
+            [old_chat_id],
+        )?;
+    }
+    tx.execute("DELETE FROM chats WHERE chat_id = ?1", [old_chat_id])?;
+
+    tx.commit()?;
+
+    Ok(moved)
+}
+
+fn schedule_from_row(row: &Row) -> Result<Schedule> {
+    Ok(Schedule {
+        id: row.get(0)?,
+        chat_id: row.get(1)?,
+        minute_of_day: row.get(2)?,
+        message: row.get(3)?,
+        last_fired_on: row.get(4)?,
+    })
+}
+
+/// Store a new recurring tag, returning its id
+pub fn add_schedule(
+    conn: &Connection,
+    chat_id: i64,
+    minute_of_day: i64,
+    message: &str,
+) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO schedules (chat_id, minute_of_day, message) VALUES (?1, ?2, ?3)",
+        params![chat_id, minute_of_day, message],
+    )?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+/// Get the recurring tags of a chat, earliest in the day first
+pub fn get_schedules_for_chat(conn: &Connection, chat_id: i64) -> Result<Vec<Schedule>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, chat_id, minute_of_day, message, last_fired_on FROM schedules
+         WHERE chat_id = ?1
+         ORDER BY minute_of_day, id",
+    )?;
+
+    let schedules = stmt.query_map([chat_id], schedule_from_row)?;
+
+    schedules.collect()
+}
+
+/// Get the recurring tags of every chat
+pub fn get_all_schedules(conn: &Connection) -> Result<Vec<Schedule>> {
+    let mut stmt =
+        conn.prepare("SELECT id, chat_id, minute_of_day, message, last_fired_on FROM schedules")?;
+
+    let schedules = stmt.query_map([], schedule_from_row)?;
+
+    schedules.collect()
+}
+
+/// Remember the local date a recurring tag last fired on, so it fires once a day
+pub fn mark_schedule_fired(conn: &Connection, id: i64, date: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE schedules SET last_fired_on = ?2 WHERE id = ?1",
+        params![id, date],
+    )?;
+
+    Ok(())
+}
+
+/// Delete a recurring tag of a chat, returning whether it existed
+pub fn delete_schedule(conn: &Connection, chat_id: i64, id: i64) -> Result<bool> {
+    let deleted = conn.execute(
+        "DELETE FROM schedules WHERE chat_id = ?1 AND id = ?2",
+        params![chat_id, id],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+/// Start a roll call in a chat, replacing the previous one: the tag messages users react to and who was tagged
+pub fn start_rollcall(
+    conn: &Connection,
+    chat_id: i64,
+    message_ids: &[i32],
+    user_ids: &[i64],
+) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+
+    for table in ["rollcalls", "rollcall_messages", "rollcall_users"] {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE chat_id = ?1", table),
+            [chat_id],
+        )?;
+    }
+    tx.execute(
+        "INSERT INTO rollcalls (chat_id, started_at) VALUES (?1, ?2)",
+        params![chat_id, unix_now()],
+    )?;
+    for message_id in message_ids {
+        tx.execute(
+            "INSERT OR IGNORE INTO rollcall_messages (chat_id, message_id) VALUES (?1, ?2)",
+            params![chat_id, message_id],
+        )?;
+    }
+    for user_id in user_ids {
+        tx.execute(
+            "INSERT OR IGNORE INTO rollcall_users (chat_id, user_id) VALUES (?1, ?2)",
+            params![chat_id, user_id],
+        )?;
+    }
+
+    tx.commit()
+}
+
+/// Record whether a user reacts to a message of the roll call, returning whether the message is one
+pub fn set_rollcall_reaction(
+    conn: &Connection,
+    chat_id: i64,
+    message_id: i32,
+    user_id: i64,
+    reacted: bool,
+) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE rollcall_users SET reacted = ?4
+         WHERE chat_id = ?1 AND user_id = ?3
+           AND EXISTS (SELECT 1 FROM rollcall_messages WHERE chat_id = ?1 AND message_id = ?2)",
+        params![chat_id, message_id, user_id, reacted],
+    )?;
+
+    Ok(updated > 0)
+}
+
+/// Record that a user reacted to a message, or took back every reaction to it
+pub fn set_message_reaction(
+    conn: &Connection,
+    chat_id: i64,
+    message_id: i32,
+    user_id: i64,
+    reacted: bool,
+) -> Result<()> {
+    if reacted {
+        conn.execute(
+            "INSERT OR REPLACE INTO message_reactions (chat_id, message_id, user_id, reacted_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![chat_id, message_id, user_id, unix_now()],
+        )?;
+    } else {
+        conn.execute(
+            "DELETE FROM message_reactions WHERE chat_id = ?1 AND message_id = ?2 AND user_id = ?3",
+            params![chat_id, message_id, user_id],
+        )?;
+    }
+
+    conn.execute(
+        "DELETE FROM message_reactions WHERE chat_id = ?1 AND reacted_at < ?2",
+        params![chat_id, unix_now() - REACTION_RETENTION_DAYS * 86400],
+    )?;
+
+    Ok(())
+}
+
+/// Ids of the users with a recorded reaction to a message
+pub fn get_message_reactors(conn: &Connection, chat_id: i64, message_id: i32) -> Result<Vec<i64>> {
+    let mut stmt = conn
+        .prepare("SELECT user_id FROM message_reactions WHERE chat_id = ?1 AND message_id = ?2")?;
+    let user_ids = stmt.query_map(params![chat_id, message_id], |row| row.get(0))?;
+
+    user_ids.collect()
+}
+
+/// A roll call started with /rollcall
+#[derive(Debug, Clone)]
+pub struct RollCall {
+    /// Unix timestamp of when the roll call was sent
+    pub started_at: i64,
+    /// Tagged users that reacted to it
+    pub present: Vec<User>,
+    /// Tagged users that didn't react yet
+    pub missing: Vec<User>,
+}
+
+/// Get the roll call of a chat, leaving out tagged users that aren't tracked anymore
+pub fn get_rollcall(conn: &Connection, chat_id: i64) -> Result<Option<RollCall>> {
+    let Some(started_at) = conn
+        .query_row(
+            "SELECT started_at FROM rollcalls WHERE chat_id = ?1",
+            [chat_id],
+            |row| row.get(0),
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM users WHERE chat_id = ?1 AND user_id IN \
+         (SELECT user_id FROM rollcall_users WHERE chat_id = ?1 AND reacted = ?2)",
+        USER_COLUMNS
+    ))?;
+    let present = stmt
+        .query_map(params![chat_id, true], user_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+    let missing = stmt
+        .query_map(params![chat_id, false], user_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Some(RollCall {
+        started_at,
+        present,
+        missing,
+    }))
+}
+
+fn pending_tag_from_row(row: &Row) -> Result<PendingTag> {
+    Ok(PendingTag {
+        id: row.get(0)?,
+        chat_id: row.get(1)?,
+        fire_at: row.get(2)?,
+        message: row.get(3)?,
+    })
+}
+
+/// Store a one-shot tag, returning its id
+pub fn add_pending_tag(
+    conn: &Connection,
+    chat_id: i64,
+    fire_at: i64,
+    message: &str,
+) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO pending_tags (chat_id, fire_at, message) VALUES (?1, ?2, ?3)",
+        params![chat_id, fire_at, message],
+    )?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+/// Get the one-shot tags of a chat that haven't been sent yet, soonest first
+pub fn get_pending_tags_for_chat(conn: &Connection, chat_id: i64) -> Result<Vec<PendingTag>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, chat_id, fire_at, message FROM pending_tags
+         WHERE chat_id = ?1
+         ORDER BY fire_at, id",
+    )?;
+
+    let tags = stmt.query_map([chat_id], pending_tag_from_row)?;
+
+    tags.collect()
+}
+
+/// Remove and return the one-shot tags that are due, so each one is only sent once
+pub fn take_due_pending_tags(conn: &Connection, now: i64) -> Result<Vec<PendingTag>> {
+    let tx = conn.unchecked_transaction()?;
+
+    let due = {
+        let mut stmt = tx.prepare(
+            "SELECT id, chat_id, fire_at, message FROM pending_tags
+             WHERE fire_at <= ?1
+             ORDER BY fire_at, id",
+        )?;
+        stmt.query_map([now], pending_tag_from_row)?
+            .collect::<Result<Vec<_>>>()?
+    };
+    tx.execute("DELETE FROM pending_tags WHERE fire_at <= ?1", [now])?;
+
+    tx.commit()?;
+
+    Ok(due)
+}
+
+/// Delete a one-shot tag of a chat, returning whether it was still pending
+pub fn delete_pending_tag(conn: &Connection, chat_id: i64, id: i64) -> Result<bool> {
+    let deleted = conn.execute(
+        "DELETE FROM pending_tags WHERE chat_id = ?1 AND id = ?2",
+        params![chat_id, id],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+/// Delete a user from a specific chat (when they leave)
+pub fn delete_user(conn: &Connection, chat_id: i64, user_id: i64) -> Result<()> {
+    conn.execute(
+        "DELETE FROM users WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id],
+    )?;
+    conn.execute(
+        "DELETE FROM tag_groups WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id],
+    )?;
+    conn.execute(
+        "DELETE FROM group_admins WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id],
+    )?;
+    conn.execute(
+        "DELETE FROM rollcall_users WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id],
+    )?;
+    conn.execute(
+        "DELETE FROM daily_activity WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id],
+    )?;
+    conn.execute(
+        "DELETE FROM message_reactions WHERE chat_id = ?1 AND user_id = ?2",
+        params![chat_id, user_id],
+    )?;
+
+    Ok(())
+}
+
+/// What was restored from a JSON backup
+#[derive(Debug, Default)]
+pub struct RestoreCounts {
+    pub chats: usize,
+    pub users: usize,
+    pub group_members: usize,
+}
+
+/// Whether the database has no tracked users and no known chats, as after a fresh deploy
+pub fn is_empty(conn: &Connection) -> Result<bool> {
+    conn.query_row(
+        "SELECT NOT EXISTS (SELECT 1 FROM users) AND NOT EXISTS (SELECT 1 FROM chats)",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Imports the chats, users and tag groups of a JSON backup, replacing the users already stored
+/// under the same ids, all at once or not at all
+pub fn import_json(conn: &Connection, backup: &JsonBackup) -> Result<RestoreCounts> {
+    let tx = conn.unchecked_transaction()?;
+    let mut counts = RestoreCounts::default();
+
+    for chat in &backup.chats {
+        upsert_chat(&tx, chat.chat_id, chat.title.as_deref())?;
+        counts.chats += 1;
+
+        for user in &chat.users {
+            let notify_pref = user
+                .notify_pref
+                .as_deref()
+                .and_then(|pref| pref.parse().ok())
+                .unwrap_or(NotifyPref::Loud);
+            tx.execute(
+                "INSERT OR REPLACE INTO users (
+                    chat_id, user_id, first_name, username, override_name, label, note, cohort,
+                    notify_pref, always_tag, last_seen, joined_at, message_count, last_tagged
+                 )
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+                params![
+                    chat.chat_id,
+                    user.user_id,
+                    user.first_name,
+                    user.username,
+                    user.override_name,
+                    user.label,
+                    user.note,
+                    user.cohort,
+                    notify_pref.as_str(),
+                    user.always_tag,
+                    user.last_seen,
+                    user.joined_at,
+                    user.message_count,
+                    user.last_tagged
+                ],
+            )?;
+            counts.users += 1;
+        }
+
+        for (name, user_ids) in &chat.groups {
+            for &user_id in user_ids {
+                if add_group_member(&tx, chat.chat_id, name, user_id)? {
+                    counts.group_members += 1;
+                }
+            }
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(counts)
+}
+
+/// Write, read back and delete a sentinel row to make sure the database is usable
+pub fn self_test(conn: &Connection) -> Result<()> {
+    upsert_user(conn, SELF_TEST_CHAT_ID, 0, "self-test", None, None)?;
+
+    let users = get_users_for_chat(conn, SELF_TEST_CHAT_ID)?;
+    if !users
+        .iter()
+        .any(|u| u.user_id == 0 && u.first_name == "self-test")
+    {
+        return Err(rusqlite::Error::QueryReturnedNoRows);
+    }
+
+    delete_user(conn, SELF_TEST_CHAT_ID, 0)?;
+
+    Ok(())
+}
+
+/// Problems found by SQLite's `PRAGMA integrity_check`, empty when the database is sound
+pub fn integrity_check(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+
+    // A sound database answers with a single "ok" row
+    let problems: Vec<String> = rows.collect::<Result<_>>()?;
+    Ok(problems.into_iter().filter(|row| row != "ok").collect())
+}
+
+/// Rows pointing to missing parent rows, as reported by `PRAGMA foreign_key_check`
+pub fn foreign_key_check(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA foreign_key_check")?;
+    let rows = stmt.query_map([], |row| {
+        let table: String = row.get(0)?;
+        let rowid: Option<i64> = row.get(1)?;
+        let parent: String = row.get(2)?;
+        Ok(format!(
+            "{} row {} references a missing row of {}",
+            table,
+            rowid.map_or_else(|| "?".to_string(), |id| id.to_string()),
+            parent
+        ))
+    })?;
+
+    rows.collect()
+}
+
+/// Copy the whole database to a file with SQLite's online backup API, safe while the bot is running
+pub fn backup_to(conn: &Connection, path: &Path) -> Result<()> {
+    conn.backup(rusqlite::MAIN_DB, path, None)
+}
+
+/// Logs who can read a database file and applies `mode` to it when given, so the roster isn't exposed to other users of the host
+#[cfg(unix)]
+pub fn secure_file(path: &Path, mode: Option<u32>) -> std::io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let metadata = std::fs::metadata(path)?;
+    let current = metadata.permissions().mode() & 0o777;
+    log::info!(
+        "{} has mode {:03o} (owner uid {}, gid {})",
+        path.display(),
+        current,
+        metadata.uid(),
+        metadata.gid()
+    );
+
+    match mode {
+        Some(mode) if mode != current => {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+            log::info!("Changed mode of {} to {:03o}", path.display(), mode);
+        }
+        None if current & 0o044 != 0 => log::warn!(
+            "{} can be read by other users of this host, set DB_FILE_MODE=600 to prevent it",
+            path.display()
+        ),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// File modes are a unix concept, elsewhere only warn that the setting is ignored
+#[cfg(not(unix))]
+pub fn secure_file(_path: &Path, mode: Option<u32>) -> std::io::Result<()> {
+    if mode.is_some() {
+        log::warn!("DB_FILE_MODE is only supported on unix systems, ignoring it");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        create_schema(&conn).unwrap();
+        conn
+    }
+
+    fn names(users: &[User]) -> Vec<(i64, String)> {
+        let mut names: Vec<_> = users
+            .iter()
+            .map(|u| (u.user_id, u.first_name.clone()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn migrate_chat_moves_everything_and_keeps_rows_of_the_new_chat() {
+        const OLD: i64 = -42;
+        const NEW: i64 = -1_000_000_000_042;
+        let conn = test_conn();
+
+        upsert_user(&conn, OLD, 1, "Only old", None, None).unwrap();
+        upsert_user(&conn, OLD, 2, "Overlap old", None, None).unwrap();
+        upsert_user(&conn, NEW, 2, "Overlap new", None, None).unwrap();
+        add_group_member(&conn, OLD, "devs", 1).unwrap();
+        add_group_member(&conn, OLD, "devs", 2).unwrap();
+        add_group_member(&conn, NEW, "devs", 2).unwrap();
+        let settings = ChatSettings {
+            min_membership_hours: 5,
+            interleave: true,
+            ..ChatSettings::default()
+        };
+        save_chat_settings(&conn, OLD, &settings).unwrap();
+
+        let moved = migrate_chat(&conn, OLD, NEW).unwrap();
+
+        // The overlapping user was already under the new id, that row is the one kept
+        assert_eq!(moved, 1);
+        assert_eq!(
+            names(&get_users_for_chat(&conn, NEW).unwrap()),
+            vec![(1, "Only old".to_string()), (2, "Overlap new".to_string())]
+        );
+        assert_eq!(
+            names(&get_group_users(&conn, NEW, "devs").unwrap()).len(),
+            2
+        );
+
+        let migrated = get_chat_settings(&conn, NEW).unwrap();
+        assert_eq!(migrated.min_membership_hours, 5);
+        assert!(migrated.interleave);
+
+        assert!(get_users_for_chat(&conn, OLD).unwrap().is_empty());
+        assert!(get_group_users(&conn, OLD, "devs").unwrap().is_empty());
+        let old_settings: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM chat_settings WHERE chat_id = ?1",
+                [OLD],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(old_settings, 0);
+    }
+
+    #[test]
+    fn restore_chat_users_brings_back_every_column_taken() {
+        const CHAT: i64 = -42;
+        let conn = test_conn();
+        let user = User {
+            user_id: 7,
+            first_name: "Alice".to_string(),
+            username: Some("alice".to_string()),
+            override_name: Some("Al".to_string()),
+            label: Some("mod".to_string()),
+            note: Some("nice".to_string()),
+            message_count: 12,
+            id_mention_works: Some(false),
+            blocked_bot: true,
+            cohort: Some("2026-01".to_string()),
+            always_tag: true,
+            last_tagged: Some(100),
+            last_seen: Some(200),
+            joined_at: Some(50),
+            notify_pref: NotifyPref::Silent,
+        };
+
+        restore_chat_users(&conn, CHAT, std::slice::from_ref(&user), None).unwrap();
+        let taken = take_chat_users(&conn, CHAT).unwrap();
+        assert!(get_users_for_chat(&conn, CHAT).unwrap().is_empty());
+
+        // Stored again over an older row of the same user
+        upsert_user(&conn, CHAT, 7, "Old name", None, None).unwrap();
+        restore_chat_users(&conn, CHAT, &taken, None).unwrap();
+        let restored = get_user(&conn, CHAT, 7).unwrap().unwrap();
+        assert_eq!(format!("{:?}", restored), format!("{:?}", user));
+    }
+
+    #[test]
+    fn import_json_keeps_message_counts_and_last_tags() {
+        let conn = test_conn();
+        let backup = crate::export::parse_json_backup(
+            r#"{"chats": [{"chat_id": -1, "users": [
+                {"user_id": 1, "first_name": "Alice", "message_count": 42, "last_tagged": 1700000000},
+                {"user_id": 2, "first_name": "Bob"}
+            ]}]}"#,
+        )
+        .unwrap();
+
+        import_json(&conn, &backup).unwrap();
+
+        let alice = get_user(&conn, -1, 1).unwrap().unwrap();
+        assert_eq!(alice.message_count, 42);
+        assert_eq!(alice.last_tagged, Some(1700000000));
+        let bob = get_user(&conn, -1, 2).unwrap().unwrap();
+        assert_eq!(bob.message_count, 0);
+        assert_eq!(bob.last_tagged, None);
+    }
+}
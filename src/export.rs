@@ -0,0 +1,86 @@
+use crate::db;
+use chrono::DateTime;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Serializes the roster of a chat as CSV (user_id, first_name, username, last_seen)
+pub fn roster_csv(users: &[db::User]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["user_id", "first_name", "username", "last_seen"])?;
+
+    for user in users {
+        let last_seen = user.last_seen.map(format_timestamp).unwrap_or_default();
+        writer.write_record([
+            user.user_id.to_string(),
+            user.first_name.clone(),
+            user.username.clone().unwrap_or_default(),
+            last_seen,
+        ])?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| csv::Error::from(e.into_error()))
+}
+
+/// Backup restored at startup from `RESTORE_FROM`, every chat with its users and tag groups
+#[derive(Debug, Deserialize)]
+pub struct JsonBackup {
+    pub chats: Vec<JsonChat>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JsonChat {
+    pub chat_id: i64,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub users: Vec<JsonUser>,
+    /// Members of each tag group, by user id
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<i64>>,
+}
+
+/// A tracked user, only the id and first name are required
+#[derive(Debug, Deserialize)]
+pub struct JsonUser {
+    pub user_id: i64,
+    pub first_name: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub override_name: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub cohort: Option<String>,
+    /// `loud`, `silent` or `off`, loud when missing or unknown
+    #[serde(default)]
+    pub notify_pref: Option<String>,
+    #[serde(default)]
+    pub always_tag: bool,
+    #[serde(default)]
+    pub last_seen: Option<i64>,
+    #[serde(default)]
+    pub joined_at: Option<i64>,
+    /// Messages counted for the user, shown by /whois and weighting /random
+    #[serde(default)]
+    pub message_count: i64,
+    /// When the user was last tagged, so `--next` rounds carry on where they were
+    #[serde(default)]
+    pub last_tagged: Option<i64>,
+}
+
+/// Parses a JSON backup, as read from the `RESTORE_FROM` file
+pub fn parse_json_backup(json: &str) -> serde_json::Result<JsonBackup> {
+    serde_json::from_str(json)
+}
+
+/// Formats a unix timestamp as a UTC date and time
+pub fn format_timestamp(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
@@ -1,20 +1,252 @@
+mod config;
 mod db;
+mod export;
+mod mentions;
+mod settings;
+mod state;
 
-use std::sync::Arc;
+use chrono::Timelike;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use teloxide::{
+    ApiError, RequestError,
+    dispatching::UpdateHandler,
     prelude::*,
-    types::{ChatMemberKind, ParseMode, ReplyParameters},
+    types::{
+        ChatMemberKind, InlineKeyboardButton, InlineKeyboardMarkup, InlineQueryResult,
+        InlineQueryResultArticle, InputFile, InputMessageContent, InputMessageContentText,
+        InputPollOption, Me, MessageEntityKind, MessageId, MessageReactionUpdated, Recipient,
+        ReplyParameters,
+    },
     utils::command::BotCommands,
 };
-use tokio::sync::Mutex;
+use tokio::sync::OwnedMutexGuard;
+
+use config::{Config, SharedConfig};
+use db::Db;
+use mentions::{Markup, TagFormat};
+use settings::Verbosity;
+use state::State;
 
-type Db = Arc<Mutex<rusqlite::Connection>>;
+/// Delay before the first dispatcher restart, doubled after each consecutive crash
+const RESTART_MIN_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for the delay between dispatcher restarts
+const RESTART_MAX_DELAY: Duration = Duration::from_secs(60);
+/// A dispatcher that ran at least this long is considered healthy and resets the backoff
+const RESTART_RESET_AFTER: Duration = Duration::from_secs(300);
+/// Pause between `get_chat_member` calls when checking every tracked user
+const MEMBER_CHECK_DELAY: Duration = Duration::from_millis(50);
+/// Tracked users seen writing within this many days are checked by /reconcile
+const RECONCILE_ACTIVE_DAYS: i64 = 30;
+/// Maximum number of names listed for each discrepancy reported by /reconcile
+const RECONCILE_MAX_NAMES: usize = 20;
+/// Longest lifetime accepted by `/all --ttl`
+const MAX_TTL_SECS: u64 = 24 * 3600;
+/// How often the countdown of a self-destructing tag is updated
+const TTL_EDIT_INTERVAL_SECS: u64 = 15;
+/// Directory where /backup writes the database copies
+const BACKUP_DIR: &str = "backups";
+/// Maximum number of users listed by /find
+const FIND_MAX_RESULTS: usize = 20;
+/// Maximum number of names listed for each reason by /excluded
+const EXCLUDED_MAX_NAMES: usize = 20;
+/// Longest /help message, below Telegram's limit of 4096 characters
+const HELP_MESSAGE_LEN: usize = 4000;
+/// Days shown by /activity when none are given
+const ACTIVITY_DEFAULT_DAYS: i64 = 14;
+/// Longest bar of the /activity chart, in characters
+const ACTIVITY_BAR_WIDTH: i64 = 20;
+/// Maximum number of missing users listed by /rollcallstatus
+const ROLLCALL_MAX_NAMES: usize = 50;
+/// Most an /all message may grow when escaped, relative to its raw size
+const MAX_ESCAPE_GROWTH: f64 = 1.5;
+/// Messages shorter than this are never rejected for their special characters
+const ESCAPE_GROWTH_MIN_LEN: usize = 32;
+/// Longest tag group name accepted
+const MAX_GROUP_NAME_LEN: usize = 32;
+/// Most results Telegram accepts in a single inline query answer
+const MAX_INLINE_RESULTS: usize = 50;
+/// Most invalid mentions listed by /mentioncheck
+const MENTIONCHECK_MAX_RESULTS: usize = 20;
+/// Number of chats listed per page by /chats
+const CHATS_PER_PAGE: usize = 20;
+/// Number of users listed per page by /roster
+const ROSTER_PER_PAGE: usize = 30;
+/// Maximum number of problems listed for each check by /integritycheck
+const INTEGRITY_MAX_PROBLEMS: usize = 20;
+/// Longest label /nick accepts, so mentions stay readable
+const MAX_NICK_LEN: usize = 32;
+/// How long /inactive can't be used again in a chat, pinging quiet members is best kept rare
+const INACTIVE_COOLDOWN: Duration = Duration::from_secs(6 * 3600);
+/// Largest number of days accepted by /inactive
+const INACTIVE_MAX_DAYS: i64 = 365;
+/// How long after a tag /undo can still delete it
+const UNDO_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Maximum number of recurring tags a chat can have
+const MAX_SCHEDULES_PER_CHAT: usize = 10;
+/// How often the recurring tags are checked
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// A recurring tag missed by more than this many minutes (e.g. while the bot was down) is skipped for the day
+const SCHEDULE_GRACE_MINUTES: i64 = 5;
+/// Most one-shot tags a single chat can have pending
+const MAX_PENDING_TAGS_PER_CHAT: usize = 10;
+/// Pause between chats during the startup admin sync, to stay well below Telegram's rate limits
+const STARTUP_SYNC_CHAT_DELAY: Duration = Duration::from_secs(1);
+
+/// Meta key set once the startup sweep for bots stored by older versions has checked every user
+const BOT_SWEEP_META_KEY: &str = "bot_sweep_done";
+/// Meta key listing the chats, comma separated, where the last bot sweep couldn't check every user
+const BOT_SWEEP_PENDING_META_KEY: &str = "bot_sweep_pending";
 
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase")]
 enum Command {
     #[command(description = "Tag all users in the group")]
     All(String),
+    #[command(
+        description = "Get the messages /all would send in a private chat, without tagging anyone"
+    )]
+    Preview(String),
+    #[command(description = "Override the stored name of the replied user (empty to reset)")]
+    Rename(String),
+    #[command(description = "Tag the N most recently joined members")]
+    Newcomers(String),
+    #[command(description = "Show tracking statistics for this chat")]
+    Stats,
+    #[command(description = "Remove bot accounts from the tracked users")]
+    CleanBots(String),
+    #[command(description = "Remove tracked users that left the chat or deleted their account")]
+    Prune(String),
+    #[command(description = "Export the tracked users as a CSV file")]
+    ExportCsv,
+    #[command(description = "Show or change the chat settings: /config <setting> <value>")]
+    Config(String),
+    #[command(description = "Tag only yourself to check that mentions notify you")]
+    TestTag,
+    #[command(description = "Tag all users with a poll: /pollall <question> | <option> | ...")]
+    PollAll(String),
+    #[command(description = "List every chat tracked by the bot (owner only)")]
+    Chats(String),
+    #[command(description = "Choose how you get tagged: /prefs loud|silent|off")]
+    Prefs(String),
+    #[command(description = "Compare the tracked users with the actual admins and recent members")]
+    Reconcile,
+    #[command(description = "Back up the database, /backup send also sends it to you (owner only)")]
+    Backup(String),
+    #[command(description = "Search tracked users by name or username")]
+    Find(String),
+    #[command(description = "List the users left out of tags and why")]
+    Excluded,
+    #[command(description = "Estimate how many of the users /all tags will actually be notified")]
+    Reach,
+    #[command(description = "Track the users mentioned in the pinned message of this chat")]
+    ImportPinned,
+    #[command(
+        description = "List the tracked users, the ones that wrote longest ago first: /roster [page]"
+    )]
+    Roster(String),
+    #[command(description = "Set a label shown next to the replied user's name (empty to clear)")]
+    Nick(String),
+    #[command(description = "Tag everyone every day at a time: /schedule HH:MM <message>")]
+    Schedule(String),
+    #[command(description = "List the recurring tags of this chat")]
+    Schedules,
+    #[command(description = "Delete a recurring tag: /unschedule <id>")]
+    Unschedule(String),
+    #[command(description = "Show when a user last wrote: reply or /lastseen @username")]
+    LastSeen(String),
+    #[command(description = "Show recent rate limits and send capacity (owner only)")]
+    RateLimit,
+    #[command(description = "Check the database for corruption (owner only)")]
+    IntegrityCheck,
+    #[command(description = "Tag users that haven't written for N days: /inactive <N> [message]")]
+    Inactive(String),
+    #[command(
+        description = "Show or set a moderation note on the replied user (--clear removes it)"
+    )]
+    Note(String),
+    #[command(description = "Show everything stored about a user: reply, @username or user id")]
+    Whois(String),
+    #[command(
+        description = "Tag everyone in another chat you're admin of: /tagin <chat id> <message>"
+    )]
+    TagIn(String),
+    #[command(description = "Delete the last tag sent in this chat, within 10 minutes")]
+    Undo,
+    #[command(
+        description = "Tag N random users: /random [--weighted|--weighted-inverse] <N> [message]"
+    )]
+    Random(String),
+    #[command(description = "Check that every tracked user's mention would parse")]
+    MentionCheck,
+    #[command(
+        description = "Tag the members of a tag group: /tag <group> [--active <days>] [message]"
+    )]
+    Tag(String),
+    #[command(description = "Add a user to a tag group: reply or /addtag <group> @username")]
+    AddTag(String),
+    #[command(description = "Remove a user from a tag group: reply or /remtag <group> @username")]
+    RemTag(String),
+    #[command(description = "List the tag groups of this chat")]
+    Groups,
+    #[command(description = "Rename a tag group: /renametag <old> <new>")]
+    RenameTag(String),
+    #[command(description = "Reload the settings from the .env file (owner only)")]
+    Reload,
+    #[command(
+        description = "Let a user manage a tag group: /groupadmin add|remove <group> [@username] or /groupadmin list"
+    )]
+    GroupAdmin(String),
+    #[command(description = "Tag everyone once at a time: /scheduletag HH:MM <message>")]
+    ScheduleTag(String),
+    #[command(description = "Put the replied user in a cohort: /setcohort <label>, none to clear")]
+    SetCohort(String),
+    #[command(description = "Tag the users of a cohort: /tagcohort <label> [message]")]
+    TagCohort(String),
+    #[command(description = "Tag everyone and ask them to react to confirm they're here")]
+    RollCall(String),
+    #[command(description = "Show who hasn't reacted to the last roll call yet")]
+    RollCallStatus,
+    #[command(
+        description = "Reply to a message to tag only the users that reacted to it: /tagreactors [flags] [message]"
+    )]
+    TagReactors(String),
+    #[command(description = "List the chats where the bot tracks you (in a private chat)")]
+    MyGroups,
+    #[command(description = "Delete everything the bot stores about you (in a private chat)")]
+    ForgetMe(String),
+    #[command(
+        description = "Tag a user even when filters would leave them out: reply or /alwaystag @username, add off to stop"
+    )]
+    AlwaysTag(String),
+    #[command(description = "Show how many users wrote on each of the last days: /activity [days]")]
+    Activity(String),
+    #[command(description = "Store the current number of tracked users, for /growth")]
+    Snapshot,
+    #[command(
+        description = "Compare the tracked users with the last snapshot, or the last one at least some days old: /growth [days]"
+    )]
+    Growth(String),
+    #[command(
+        description = "Show or set the time zone of this chat: /timezone <zone> such as Europe/Rome"
+    )]
+    Timezone(String),
+    #[command(description = "Cancel a pending one-shot tag: /canceltag <id>")]
+    CancelTag(String),
+    #[command(description = "List the pending one-shot tags of this chat")]
+    Pending,
+    #[command(description = "Show the version of the bot and when it was built")]
+    Version,
+    #[command(description = "List the commands you can use here")]
+    Help,
+    #[command(
+        description = "Check whether mentions notify the replied user, they confirm with a button"
+    )]
+    CanTag,
 }
 
 #[tokio::main]
@@ -23,278 +255,8002 @@ async fn main() {
     pretty_env_logger::init();
     log::info!("Starting tagger bot...");
 
-    let conn = db::init_db().expect("Failed to initialize database");
+    let config = Config::from_env();
+    if let Some(owner_id) = config.owner_id {
+        log::info!("Bot owner configured: {}", owner_id);
+    }
+
+    let conn = db::init_db().unwrap_or_else(|e| panic!("Failed to initialize database: {}", e));
     log::info!("Database initialized successfully");
-    let db: Db = Arc::new(Mutex::new(conn));
+    db::self_test(&conn).expect("Database self-test failed, is the database writable?");
+    log::info!("DB self-test passed");
+    if let Err(e) = db::secure_file(Path::new(db::DB_FILE), config.db_file_mode) {
+        log::warn!("Couldn't check the permissions of {}: {}", db::DB_FILE, e);
+    }
+    if let Some(path) = &config.restore_from {
+        restore_backup(&conn, path, config.restore_force);
+    }
+    let db = Db::new(conn);
+
+    let state = Arc::new(State::new(&config));
+    let startup_admin_sync = config.startup_admin_sync;
+    let config = SharedConfig::new(config);
 
     let bot = Bot::from_env();
     log::info!("Bot created, starting dispatcher...");
 
-    let handler = dptree::entry()
+    tokio::spawn(sweep_bots_once(bot.clone(), db.clone()));
+    if startup_admin_sync {
+        tokio::spawn(sync_all_admins(
+            bot.clone(),
+            db.clone(),
+            config.clone(),
+            state.clone(),
+        ));
+    }
+    tokio::spawn(run_schedules(
+        bot.clone(),
+        db.clone(),
+        config.clone(),
+        state.clone(),
+    ));
+    tokio::spawn(run_admin_syncs(
+        bot.clone(),
+        db.clone(),
+        config.clone(),
+        state.clone(),
+    ));
+
+    // Supervise the dispatcher, restarting it with exponential backoff if it crashes
+    let mut delay = RESTART_MIN_DELAY;
+    loop {
+        let started = Instant::now();
+        let dispatcher = tokio::spawn(run_dispatcher(
+            bot.clone(),
+            db.clone(),
+            config.clone(),
+            state.clone(),
+        ));
+
+        match dispatcher.await {
+            Ok(()) => {
+                log::info!("Dispatcher stopped, shutting down");
+                break;
+            }
+            Err(e) => log::error!("Dispatcher crashed: {}", e),
+        }
+
+        if started.elapsed() >= RESTART_RESET_AFTER {
+            delay = RESTART_MIN_DELAY;
+        }
+
+        let wait = with_jitter(delay);
+        log::warn!("Restarting dispatcher in {:.1}s", wait.as_secs_f64());
+        tokio::time::sleep(wait).await;
+
+        delay = (delay * 2).min(RESTART_MAX_DELAY);
+    }
+}
+
+/// Builds the update handler tree
+fn schema() -> UpdateHandler<RequestError> {
+    dptree::entry()
         // Handle user joins/leaves
         .branch(Update::filter_chat_member().endpoint(chat_member_handler))
+        // Offer tags through inline queries, for the owner
+        .branch(Update::filter_inline_query().endpoint(inline_query_handler))
+        // Answers to the /cantag buttons
+        .branch(Update::filter_callback_query().endpoint(callback_query_handler))
+        // Reactions to /rollcall tags
+        .branch(Update::filter_message_reaction_updated().endpoint(reaction_handler))
         // Handle messages
         .branch(
             Update::filter_message()
-                .branch(
-                    dptree::entry()
-                        .filter_command::<Command>()
-                        .endpoint(command_handler),
-                )
+                .branch(dptree::filter_map(parse_command).endpoint(command_handler))
                 .branch(dptree::endpoint(message_handler)),
-        );
+        )
+}
 
-    Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![db])
+/// Parses a command like `filter_command`, but also when a newline rather than a space follows it
+///
+/// teloxide only splits the command from its arguments on a space, so `/all` followed by a
+/// multi-line announcement on the next line would otherwise be an unknown command.
+fn parse_command(msg: Message, me: Me) -> Option<Command> {
+    let text = msg.text().or_else(|| msg.caption())?;
+    parse_command_text(text, me.user.username.as_deref().unwrap_or_default())
+}
+
+/// The text part of `parse_command`
+fn parse_command_text(text: &str, bot_name: &str) -> Option<Command> {
+    let text = match text.find(char::is_whitespace) {
+        Some(pos) if !text[pos..].starts_with(' ') => {
+            let separator_len = text[pos..].chars().next().map_or(0, char::len_utf8);
+            format!("{} {}", &text[..pos], &text[pos + separator_len..])
+        }
+        _ => text.to_string(),
+    };
+
+    Command::parse(&text, bot_name).ok()
+}
+
+/// Runs the dispatcher until it is stopped with ctrl-c
+async fn run_dispatcher(bot: Bot, db: Db, config: SharedConfig, state: Arc<State>) {
+    Dispatcher::builder(bot, schema())
+        .dependencies(dptree::deps![db, config, state])
         .enable_ctrlc_handler()
         .build()
         .dispatch()
         .await;
 }
 
-/// Handles chat member updates, tracks users joining the group
-async fn chat_member_handler(_bot: Bot, update: ChatMemberUpdated, db: Db) -> ResponseResult<()> {
-    let chat_id = update.chat.id.0;
-    let chat_name = update.chat.title().unwrap_or("Unknown");
-    let user = &update.new_chat_member.user;
+/// Randomizes a delay to somewhere between half and all of it, so restarts don't happen in lockstep
+fn with_jitter(delay: Duration) -> Duration {
+    let half = delay.as_millis() as u64 / 2;
+    Duration::from_millis(half + rand::random_range(0..=half))
+}
 
-    // Check if user joined or is still a member (not left/kicked/banned)
-    let is_member = matches!(
-        update.new_chat_member.kind,
-        ChatMemberKind::Member(_)
-            | ChatMemberKind::Administrator(_)
-            | ChatMemberKind::Owner(_)
-            | ChatMemberKind::Restricted(_)
-    );
+/// Purges bots from every tracked chat, once per database
+///
+/// Chats where some users couldn't be checked are swept again on the next start, until every
+/// user of every chat has been checked.
+async fn sweep_bots_once(bot: Bot, db: Db) {
+    let chat_ids = db
+        .call(|conn| {
+            if let Ok(Some(_)) = db::get_meta(conn, BOT_SWEEP_META_KEY) {
+                return None;
+            }
+            if let Ok(Some(pending)) = db::get_meta(conn, BOT_SWEEP_PENDING_META_KEY) {
+                return Some(
+                    pending
+                        .split(',')
+                        .filter_map(|id| id.parse().ok())
+                        .collect(),
+                );
+            }
+            Some(db::get_tracked_chat_ids(conn).unwrap_or_default())
+        })
+        .await;
+    let Some(chat_ids) = chat_ids else {
+        return;
+    };
 
-    if is_member && !user.is_bot {
-        let conn = db.lock().await;
-        let _ = db::upsert_user(&conn, chat_id, user.id.0 as i64, &user.first_name);
-        log::info!(
-            "[{}] Member update - joined/updated: {} (ID: {})",
-            chat_name,
-            user.first_name,
-            user.id.0
-        );
-    } else if !is_member {
-        // User left or was removed - delete from database
-        let conn = db.lock().await;
-        let _ = db::delete_user(&conn, chat_id, user.id.0 as i64);
-        log::info!(
-            "[{}] Member update - left/removed: {} (ID: {})",
-            chat_name,
-            user.first_name,
-            user.id.0
-        );
+    log::info!("Sweeping {} chats for tracked bots...", chat_ids.len());
+
+    let mut purged = 0;
+    let mut pending: Vec<i64> = Vec::new();
+    for chat_id in chat_ids {
+        let sweep = purge_bots(&bot, &db, ChatId(chat_id)).await;
+        purged += sweep.removed.len();
+        if sweep.unchecked > 0 {
+            pending.push(chat_id);
+        }
     }
 
-    Ok(())
+    if pending.is_empty() {
+        let _ = db
+            .call(|conn| db::set_meta(conn, BOT_SWEEP_META_KEY, &db::unix_now().to_string()))
+            .await;
+        log::info!("Bot sweep finished, purged {} bots", purged);
+    } else {
+        log::warn!(
+            "Bot sweep purged {} bots but couldn't check every user of {} chats, they are swept \
+             again on the next start",
+            purged,
+            pending.len()
+        );
+        let pending = pending
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let _ = db
+            .call(move |conn| db::set_meta(conn, BOT_SWEEP_PENDING_META_KEY, &pending))
+            .await;
+    }
 }
 
-/// Tracks user from a message and logs it
-async fn track_message_user(msg: &Message, db: &Db) {
-    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+/// Imports the JSON backup at `path` into an empty database, or into any database with `force`
+///
+/// A backup that can't be read or imported stops the bot, silently starting without the data
+/// would make the restore look successful.
+fn restore_backup(conn: &rusqlite::Connection, path: &Path, force: bool) {
+    let is_empty = db::is_empty(conn).unwrap_or(false);
+    if !is_empty && !force {
+        log::info!(
+            "Database already has data, not restoring {} (set RESTORE_FORCE to import it anyway)",
+            path.display()
+        );
         return;
     }
 
-    let chat_name = msg.chat.title().unwrap_or("Unknown");
+    let json = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed to read backup {}: {}", path.display(), e));
+    let mut backup = export::parse_json_backup(&json)
+        .unwrap_or_else(|e| panic!("Invalid backup {}: {}", path.display(), e));
+    let dropped = drop_untrackable(&mut backup);
+    if dropped > 0 {
+        log::warn!(
+            "Leaving {} users out of {}, they are Telegram placeholder accounts",
+            dropped,
+            path.display()
+        );
+    }
+    let counts = db::import_json(conn, &backup)
+        .unwrap_or_else(|e| panic!("Failed to restore backup {}: {}", path.display(), e));
 
-    if let Some(user) = &msg.from {
-        if !user.is_bot {
-            let conn = db.lock().await;
-            let _ = db::upsert_user(&conn, msg.chat.id.0, user.id.0 as i64, &user.first_name);
-            log::info!(
-                "[{}] Tracked user from message: {} (ID: {})",
-                chat_name,
-                user.first_name,
-                user.id.0
-            );
+    log::info!(
+        "Restored {} chats, {} users and {} tag group members from {}",
+        counts.chats,
+        counts.users,
+        counts.group_members,
+        path.display()
+    );
+}
+
+/// Removes the users `is_trackable_id` rejects from a backup, tag groups included, returning how many
+/// users were removed
+fn drop_untrackable(backup: &mut export::JsonBackup) -> usize {
+    let trackable =
+        |user_id: i64| u64::try_from(user_id).is_ok_and(|id| is_trackable_id(UserId(id)));
+
+    let mut dropped = 0;
+    for chat in &mut backup.chats {
+        let before = chat.users.len();
+        chat.users.retain(|user| trackable(user.user_id));
+        dropped += before - chat.users.len();
+        for user_ids in chat.groups.values_mut() {
+            user_ids.retain(|&user_id| trackable(user_id));
         }
     }
+    dropped
 }
 
-/// Handles regular messages, tracks users and handles join/leave events
-async fn message_handler(_bot: Bot, msg: Message, db: Db) -> ResponseResult<()> {
-    track_message_user(&msg, &db).await;
+/// Refreshes the admins of every tracked chat, and with pruning enabled drops users that left
+async fn sync_all_admins(bot: Bot, db: Db, config: SharedConfig, state: Arc<State>) {
+    let config = config.get();
+    let chat_ids = db
+        .call(|conn| db::get_tracked_chat_ids(conn).unwrap_or_default())
+        .await;
+    log::info!("Syncing admins of {} chats...", chat_ids.len());
 
-    // Only process join/leave in groups/supergroups
-    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
-        return Ok(());
+    let mut synced = 0;
+    let mut removed = 0;
+    for chat_id in chat_ids {
+        let chat_id = ChatId(chat_id);
+        match sync_admins(&bot, &db, &config, &state, chat_id).await {
+            Ok(sync) => {
+                log::info!("[{}] Synced {} admins to database", chat_id.0, sync.total());
+                synced += 1;
+                if config.startup_admin_sync_prune {
+                    removed += sweep_members(&bot, &db, chat_id, |member| {
+                        (!is_member_kind(&member.kind)).then_some("user that left")
+                    })
+                    .await
+                    .removed
+                    .len();
+                }
+            }
+            Err(e) => log::warn!("[{}] Failed to sync admins, skipping: {}", chat_id.0, e),
+        }
+        tokio::time::sleep(STARTUP_SYNC_CHAT_DELAY).await;
     }
 
-    let chat_name = msg.chat.title().unwrap_or("Unknown");
-    let conn = db.lock().await;
+    log::info!(
+        "Admin sync finished, synced {} chats and removed {} users",
+        synced,
+        removed
+    );
+}
 
-    // Track new members that joined (from the message's new_chat_members field)
-    if let Some(new_members) = msg.new_chat_members() {
-        for user in new_members {
-            if !user.is_bot {
-                let _ = db::upsert_user(&conn, msg.chat.id.0, user.id.0 as i64, &user.first_name);
-                log::info!(
-                    "[{}] New member joined: {} (ID: {})",
-                    chat_name,
-                    user.first_name,
-                    user.id.0
-                );
+/// Refreshes the admins of the chats with `auto_admin_sync` on, every `ADMIN_SYNC_INTERVAL_MINS`
+async fn run_admin_syncs(bot: Bot, db: Db, config: SharedConfig, state: Arc<State>) {
+    loop {
+        // Read every round so /reload can change the interval
+        tokio::time::sleep(config.get().admin_sync_interval()).await;
+        let current = config.get();
+
+        let chat_ids = db
+            .call(|conn| db::get_auto_admin_sync_chat_ids(conn).unwrap_or_default())
+            .await;
+        for chat_id in chat_ids {
+            let chat_id = ChatId(chat_id);
+            match sync_admins(&bot, &db, &current, &state, chat_id).await {
+                Ok(sync) => log::info!(
+                    "[{}] Background admin sync: {} added, {} updated",
+                    chat_id.0,
+                    sync.added,
+                    sync.updated
+                ),
+                // Leave the other chats for the next round rather than piling on more requests
+                Err(RequestError::RetryAfter(retry_after)) => {
+                    note_rate_limit(&state, chat_id, &RequestError::RetryAfter(retry_after));
+                    break;
+                }
+                Err(e) => log::warn!("[{}] Background admin sync failed: {}", chat_id.0, e),
             }
+            tokio::time::sleep(STARTUP_SYNC_CHAT_DELAY).await;
         }
     }
+}
 
-    // Track if someone left (from the message's left_chat_member field)
-    if let Some(user) = msg.left_chat_member() {
-        let _ = db::delete_user(&conn, msg.chat.id.0, user.id.0 as i64);
-        log::info!(
-            "[{}] Member left: {} (ID: {})",
-            chat_name,
-            user.first_name,
-            user.id.0
-        );
+/// What an admin sync stored
+#[derive(Default)]
+struct AdminSync {
+    /// Admins that weren't tracked before
+    added: usize,
+    /// Admins already tracked, whose name and username were refreshed
+    updated: usize,
+}
+
+impl AdminSync {
+    fn total(&self) -> usize {
+        self.added + self.updated
     }
+}
 
-    Ok(())
+/// Fetches the admins of a chat and adds them to the database
+async fn sync_admins(
+    bot: &Bot,
+    db: &Db,
+    config: &Config,
+    state: &State,
+    chat: ChatId,
+) -> Result<AdminSync, RequestError> {
+    let admins = bot.get_chat_administrators(chat).await?;
+    Ok(store_admins(db, config, state, chat, admins).await)
 }
 
-/// Handles the /all command - tags all tracked users (admin only)
-async fn command_handler(bot: Bot, msg: Message, cmd: Command, db: Db) -> ResponseResult<()> {
-    track_message_user(&msg, &db).await;
+/// Adds the admins of a chat to the database, or to memory for an ephemeral chat
+async fn store_admins(
+    db: &Db,
+    config: &Config,
+    state: &State,
+    chat: ChatId,
+    admins: Vec<teloxide::types::ChatMember>,
+) -> AdminSync {
+    let chat_id = chat.0;
+    let ephemeral = db
+        .call(move |conn| db::get_chat_settings(conn, chat_id).is_ok_and(|s| s.ephemeral))
+        .await;
+    if ephemeral {
+        let known: HashSet<i64> = state
+            .ephemeral_users(chat)
+            .iter()
+            .map(|u| u.user_id)
+            .collect();
+        let mut sync = AdminSync::default();
+        for admin in admins.iter().filter(|admin| is_trackable(&admin.user)) {
+            if known.contains(&(admin.user.id.0 as i64)) {
+                sync.updated += 1;
+            } else {
+                sync.added += 1;
+            }
+            state.remember_ephemeral(chat, ephemeral_user(&admin.user));
+        }
+        return sync;
+    }
 
-    match cmd {
-        Command::All(text) => handle_all_command(bot, msg, text, db).await,
+    let max_users = config.max_users_per_chat;
+    db.call(move |conn| {
+        let mut sync = AdminSync::default();
+        for admin in admins {
+            if is_trackable(&admin.user) {
+                let user_id = admin.user.id.0 as i64;
+                if matches!(db::get_user(conn, chat_id, user_id), Ok(Some(_))) {
+                    sync.updated += 1;
+                } else {
+                    sync.added += 1;
+                }
+                let _ = db::upsert_user(
+                    conn,
+                    chat_id,
+                    user_id,
+                    &admin.user.first_name,
+                    admin.user.username.as_deref(),
+                    max_users,
+                );
+            }
+        }
+        sync
+    })
+    .await
+}
+
+/// Sends the recurring and one-shot tags when they are due, checking every minute
+async fn run_schedules(bot: Bot, db: Db, config: SharedConfig, state: Arc<State>) {
+    let mut interval = tokio::time::interval(SCHEDULE_CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        let (due, one_shots) = db
+            .call(|conn| {
+                let now = chrono::Utc::now();
+                let mut due = Vec::new();
+                for schedule in db::get_all_schedules(conn).unwrap_or_default() {
+                    let settings =
+                        db::get_chat_settings(conn, schedule.chat_id).unwrap_or_default();
+                    let local = settings.to_local(now);
+                    let today = local.format("%Y-%m-%d").to_string();
+                    let minute = i64::from(local.hour() * 60 + local.minute());
+                    let late_by = minute - schedule.minute_of_day;
+
+                    if (0..SCHEDULE_GRACE_MINUTES).contains(&late_by)
+                        && schedule.last_fired_on.as_deref() != Some(today.as_str())
+                    {
+                        // Marked before sending so a slow tag isn't sent twice
+                        let _ = db::mark_schedule_fired(conn, schedule.id, &today);
+                        due.push(schedule);
+                    }
+                }
+                // Taken out of the table before sending so they can't fire twice
+                let one_shots =
+                    db::take_due_pending_tags(conn, now.timestamp()).unwrap_or_default();
+                (due, one_shots)
+            })
+            .await;
+
+        for schedule in due {
+            tokio::spawn(send_scheduled_tag(
+                bot.clone(),
+                db.clone(),
+                config.clone(),
+                state.clone(),
+                ChatId(schedule.chat_id),
+                schedule.message,
+                format!("recurring tag {}", schedule.id),
+            ));
+        }
+        for pending in one_shots {
+            tokio::spawn(send_scheduled_tag(
+                bot.clone(),
+                db.clone(),
+                config.clone(),
+                state.clone(),
+                ChatId(pending.chat_id),
+                pending.message,
+                format!("one-shot tag {}", pending.id),
+            ));
+        }
     }
 }
 
-async fn handle_all_command(bot: Bot, msg: Message, text: String, db: Db) -> ResponseResult<()> {
-    let chat_name = msg.chat.title().unwrap_or("Unknown");
+/// Tags everyone in the chat of a recurring or one-shot tag, like /all with the stored message
+async fn send_scheduled_tag(
+    bot: Bot,
+    db: Db,
+    config: SharedConfig,
+    state: Arc<State>,
+    chat_id: ChatId,
+    message: String,
+    what: String,
+) {
+    let config = config.get();
+    let chat_name = db
+        .call(move |conn| db::get_chat_title(conn, chat_id.0))
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| chat_id.0.to_string());
 
-    // Only works in groups/supergroups
-    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
-        log::debug!("Command /all used outside of group, ignoring");
-        bot.send_message(msg.chat.id, "This command only works in groups.")
-            .await?;
-        return Ok(());
+    if quota_refusal(chat_id, &chat_name, &db, &config)
+        .await
+        .is_some()
+    {
+        log::info!("[{}] Skipping {}, no tags left for today", chat_name, what);
+        return;
     }
 
-    let user = match &msg.from {
-        Some(u) => u,
-        None => return Ok(()),
+    let Some(_tag_guard) = state.try_lock_tag(chat_id) else {
+        log::info!("[{}] Tag already in progress, skipping {}", chat_name, what);
+        return;
     };
 
+    let targets = gather_tag_targets(
+        &bot,
+        chat_id,
+        &chat_name,
+        &db,
+        &config,
+        &state,
+        &AllOptions::default(),
+    )
+    .await;
+    if targets.users.is_empty() {
+        log::info!("[{}] Nobody to tag for {}", chat_name, what);
+        return;
+    }
+
     log::info!(
-        "[{}] /all command invoked by {} (ID: {})",
+        "[{}] Sending {} to {} users",
         chat_name,
-        user.first_name,
-        user.id.0
-    );
-
-    // Check if user is admin
-    let member = bot.get_chat_member(msg.chat.id, user.id).await?;
-    let is_admin = matches!(
-        member.kind,
-        ChatMemberKind::Administrator(_) | ChatMemberKind::Owner(_)
+        what,
+        targets.users.len()
     );
 
-    if !is_admin {
-        log::warn!(
-            "[{}] Non-admin {} (ID: {}) attempted to use /all",
-            chat_name,
-            user.first_name,
-            user.id.0
-        );
-        bot.send_message(msg.chat.id, "Only admins can use this command.")
-            .reply_parameters(ReplyParameters::new(msg.id))
-            .await?;
-        return Ok(());
+    let header = tag_header(&message, &targets.skipped, targets.format);
+    if let Err(e) = send_tag(
+        &bot,
+        chat_id,
+        None,
+        &state,
+        &header,
+        &targets.users,
+        targets.format,
+    )
+    .await
+    {
+        log::error!("[{}] Failed to send {}: {}", chat_name, what, e);
+        return;
     }
+    log_sent_tag(chat_id, &chat_name, &targets.users, &db, &state).await;
+}
 
-    // Fetch all administrators and add them to the database
-    // This ensures we at least have all admins tracked
-    if let Ok(admins) = bot.get_chat_administrators(msg.chat.id).await {
-        let conn = db.lock().await;
-        let mut admin_count = 0;
-        for admin in admins {
-            if !admin.user.is_bot {
-                let _ = db::upsert_user(
-                    &conn,
-                    msg.chat.id.0,
-                    admin.user.id.0 as i64,
-                    &admin.user.first_name,
+/// Formats minutes after midnight as HH:MM
+fn format_minute_of_day(minute_of_day: i64) -> String {
+    format!("{:02}:{:02}", minute_of_day / 60, minute_of_day % 60)
+}
+
+/// Removes tracked users of a chat that turn out to be bots
+async fn purge_bots(bot: &Bot, db: &Db, chat_id: ChatId) -> MemberSweep {
+    sweep_members(bot, db, chat_id, |member| {
+        member.user.is_bot.then_some("bot")
+    })
+    .await
+}
+
+/// Outcome of `sweep_members`
+struct MemberSweep {
+    /// Reasons of the removed users, one per user
+    removed: Vec<&'static str>,
+    /// How many users `get_chat_member` failed for, left in place unchecked
+    unchecked: usize,
+}
+
+/// Checks every tracked user of a chat with `get_chat_member`, removing the ones for which
+/// `removal_reason` returns a reason
+async fn sweep_members<F>(bot: &Bot, db: &Db, chat_id: ChatId, removal_reason: F) -> MemberSweep
+where
+    F: Fn(&teloxide::types::ChatMember) -> Option<&'static str>,
+{
+    let users = db
+        .call(move |conn| db::get_users_for_chat(conn, chat_id.0))
+        .await
+        .unwrap_or_default();
+
+    let mut removed = Vec::new();
+    let mut unchecked = 0;
+    for user in users {
+        match bot
+            .get_chat_member(chat_id, UserId(user.user_id as u64))
+            .await
+        {
+            Ok(member) => {
+                if let Some(reason) = removal_reason(&member) {
+                    let user_id = user.user_id;
+                    let _ = db
+                        .call(move |conn| db::delete_user(conn, chat_id.0, user_id))
+                        .await;
+                    log::info!(
+                        "[{}] Removed {}: {} (ID: {})",
+                        chat_id.0,
+                        reason,
+                        user.first_name,
+                        user.user_id
+                    );
+                    removed.push(reason);
+                }
+            }
+            Err(e) => {
+                log::debug!(
+                    "[{}] Couldn't check user {}: {}",
+                    chat_id.0,
+                    user.user_id,
+                    e
                 );
-                admin_count += 1;
+                unchecked += 1;
             }
         }
-        log::info!("[{}] Synced {} admins to database", chat_name, admin_count);
+        tokio::time::sleep(MEMBER_CHECK_DELAY).await;
     }
 
-    // Get all tracked users for this chat
-    let users = {
-        let conn = db.lock().await;
-        db::get_users_for_chat(&conn, msg.chat.id.0).unwrap_or_default()
-    };
+    MemberSweep { removed, unchecked }
+}
 
-    if users.is_empty() {
-        log::warn!("[{}] No users tracked yet", chat_name);
-        bot.send_message(
-            msg.chat.id,
-            "No users tracked yet. Users will be tracked as they send messages or join the group.",
-        )
-        .reply_parameters(ReplyParameters::new(msg.id))
-        .await?;
-        return Ok(());
-    }
+/// Handles inline queries - `@bot all [message]` offers a tag of every tracked chat (owner only)
+///
+/// Inline queries don't say which chat they're typed in, so there's no admin status to check and
+/// only the owner can use them.
+async fn inline_query_handler(
+    bot: Bot,
+    query: InlineQuery,
+    db: Db,
+    config: SharedConfig,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let config = config.get();
+    let text = query
+        .query
+        .trim()
+        .strip_prefix("all")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
 
-    log::info!(
-        "[{}] Tagging {} users{}",
-        chat_name,
-        users.len(),
-        if text.trim().is_empty() {
-            String::new()
-        } else {
-            format!(" with message: {}", text.trim())
+    let results = match text {
+        Some(text) if config.is_owner(query.from.id) => {
+            inline_tag_results(&db, &config, &state, text.trim()).await
         }
-    );
+        _ => Vec::new(),
+    };
 
-    // Build mentions using tg://user?id= links (works for all users)
-    let mentions: Vec<String> = users
-        .iter()
-        .map(|u| {
-            let escaped_name = escape_markdown_v2(&u.first_name);
-            format!("[{}](tg://user?id={})", escaped_name, u.user_id)
-        })
-        .collect();
+    bot.answer_inline_query(query.id, results)
+        .cache_time(0)
+        .is_personal(true)
+        .await?;
 
-    let mentions_str = mentions.join(" ");
+    Ok(())
+}
 
-    // Build the reply message
-    let reply = if text.trim().is_empty() {
-        format!("||{}||", mentions_str)
-    } else {
-        let escaped_text = escape_markdown_v2(text.trim());
-        format!("{}\n||{}||", escaped_text, mentions_str)
+/// Builds one inline result per tracked chat, each posting the tag of that chat
+///
+/// An inline result is a single message, so only the first batch of mentions is included. Chats
+/// over their daily quota, on cooldown or whose markup the text doesn't suit are left out, like
+/// /all would refuse them.
+async fn inline_tag_results(
+    db: &Db,
+    config: &Config,
+    state: &State,
+    text: &str,
+) -> Vec<InlineQueryResult> {
+    let chats = match state.inline_chats() {
+        Some(chats) => chats,
+        None => state.cache_inline_chats(load_inline_chats(db, config, state).await),
     };
 
-    bot.send_message(msg.chat.id, reply)
-        .parse_mode(ParseMode::MarkdownV2)
-        .reply_parameters(ReplyParameters::new(msg.id))
-        .await?;
+    let mut results = Vec::new();
+    for chat in chats.iter() {
+        if check_tag_text(text, chat.format.markup, config).is_err()
+            || state.cooldown_remaining(chat.chat_id, "all").is_some()
+        {
+            continue;
+        }
 
-    log::info!("[{}] Successfully sent tag message", chat_name);
+        let header = tag_header(text, &[], chat.format);
+        let chunks = mentions::build_tag_messages(&header, &chat.users, chat.format);
+        let Some(first) = chunks.into_iter().next() else {
+            continue;
+        };
 
-    Ok(())
+        let description = if first.user_count < chat.users.len() {
+            format!(
+                "Only the first {} of {} users fit in one message",
+                first.user_count,
+                chat.users.len()
+            )
+        } else {
+            format!("{} users", first.user_count)
+        };
+        let content = InputMessageContent::Text(
+            InputMessageContentText::new(first.text).parse_mode(chat.format.markup.parse_mode()),
+        );
+        results.push(InlineQueryResult::Article(
+            InlineQueryResultArticle::new(
+                chat.chat_id.to_string(),
+                format!("Tag everyone in {}", chat.title),
+                content,
+            )
+            .description(description),
+        ));
+    }
+
+    results
 }
 
-/// Escapes special characters for MarkdownV2 parsing
-fn escape_markdown_v2(text: &str) -> String {
-    let special_chars = [
-        '_', '*', '[', ']', '(', ')', '~', '`', '>', '#', '+', '-', '=', '|', '{', '}', '.', '!',
-    ];
-    let mut result = String::with_capacity(text.len() * 2);
+/// Loads the chats offered to inline queries with their tag targets, skipping the ones over quota
+async fn load_inline_chats(db: &Db, config: &Config, state: &State) -> Vec<state::InlineChat> {
+    let chats = db.call(db::list_chats).await.unwrap_or_default();
 
-    for c in text.chars() {
-        if special_chars.contains(&c) {
-            result.push('\\');
+    let mut loaded = Vec::new();
+    for chat in chats.into_iter().take(MAX_INLINE_RESULTS) {
+        let (chat_id, title) = (ChatId(chat.chat_id), chat.title);
+        let title = title.unwrap_or_else(|| "(unknown title)".to_string());
+        if quota_refusal(chat_id, &title, db, config).await.is_some() {
+            continue;
         }
-        result.push(c);
+
+        let targets = load_tag_targets(chat_id, &title, db, state, &AllOptions::default()).await;
+        loaded.push(state::InlineChat {
+            chat_id,
+            title,
+            users: targets.users,
+            format: targets.format,
+        });
+    }
+
+    loaded
+}
+
+/// Handles chat member updates, tracks users joining the group
+async fn chat_member_handler(
+    _bot: Bot,
+    update: ChatMemberUpdated,
+    db: Db,
+    config: SharedConfig,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let config = config.get();
+    let chat_id = update.chat.id.0;
+    let chat_name = update.chat.title().unwrap_or("Unknown");
+    let user = &update.new_chat_member.user;
+
+    // Check if user joined or is still a member (not left/kicked/banned)
+    let is_member = is_member_kind(&update.new_chat_member.kind);
+
+    let title = update.chat.title().map(String::from);
+    let _ = db
+        .call(move |conn| db::upsert_chat(conn, chat_id, title.as_deref()))
+        .await;
+
+    let settings = db
+        .call(move |conn| db::get_chat_settings(conn, chat_id).unwrap_or_default())
+        .await;
+    if !settings.track_membership {
+        log::debug!(
+            "[{}] Membership tracking disabled, ignoring update for {}",
+            chat_name,
+            user.id.0
+        );
+        return Ok(());
     }
 
-    result
+    let was_member = is_member_kind(&update.old_chat_member.kind);
+
+    if is_member && is_trackable(user) {
+        let is_repeat = !was_member && !state.note_join(update.chat.id, user.id);
+        let mark_joined = !was_member && !is_repeat;
+        let cohort = join_cohort(&settings);
+        if settings.ephemeral {
+            remember_ephemeral_join(&state, update.chat.id, user, mark_joined, cohort);
+        } else {
+            let tg_user = user.clone();
+            let max_users = config.max_users_per_chat;
+            db.call(move |conn| {
+                let _ = db::upsert_user(
+                    conn,
+                    chat_id,
+                    tg_user.id.0 as i64,
+                    &tg_user.first_name,
+                    tg_user.username.as_deref(),
+                    max_users,
+                );
+                if mark_joined {
+                    let _ = db::mark_joined(conn, chat_id, tg_user.id.0 as i64);
+                    if let Some(cohort) = &cohort {
+                        let _ = db::assign_join_cohort(conn, chat_id, tg_user.id.0 as i64, cohort);
+                    }
+                }
+            })
+            .await;
+        }
+        if !is_repeat {
+            log::info!(
+                "[{}] Member update - joined/updated: {} (ID: {})",
+                chat_name,
+                user.first_name,
+                user.id.0
+            );
+        }
+    } else if !is_member {
+        // User left or was removed - delete from database
+        let user_id = user.id.0 as i64;
+        let _ = db
+            .call(move |conn| db::delete_user(conn, chat_id, user_id))
+            .await;
+        state.forget_ephemeral(update.chat.id, user_id);
+        state.forget_join(update.chat.id, user.id);
+        log::info!(
+            "[{}] Member update - left/removed: {} (ID: {})",
+            chat_name,
+            user.first_name,
+            user.id.0
+        );
+    }
+
+    Ok(())
+}
+
+/// Adds the users of an ephemeral chat kept in memory to the stored ones, once each
+fn merge_ephemeral_users(users: &mut Vec<db::User>, memory: Vec<db::User>) {
+    let stored: HashSet<i64> = users.iter().map(|u| u.user_id).collect();
+    users.extend(memory.into_iter().filter(|u| !stored.contains(&u.user_id)));
+}
+
+/// Every user tracked in a chat, the stored ones and the ones kept in memory when it's ephemeral
+async fn load_chat_users(db: &Db, state: &State, chat: ChatId) -> Vec<db::User> {
+    let mut users = db
+        .call(move |conn| db::get_users_for_chat(conn, chat.0))
+        .await
+        .unwrap_or_default();
+    merge_ephemeral_users(&mut users, state.ephemeral_users(chat));
+    users
+}
+
+/// Ids of the users of an ephemeral chat kept in memory, counted in its roster snapshots
+fn ephemeral_user_ids(state: &State, chat: ChatId) -> Vec<i64> {
+    state
+        .ephemeral_users(chat)
+        .iter()
+        .map(|u| u.user_id)
+        .collect()
+}
+
+/// Whether a chat is in ephemeral mode, its users then only live in memory
+async fn is_ephemeral(db: &Db, chat: ChatId) -> bool {
+    db.call(move |conn| db::get_chat_settings(conn, chat.0).is_ok_and(|s| s.ephemeral))
+        .await
+}
+
+/// A Telegram user as kept in memory for a chat in ephemeral mode
+fn ephemeral_user(user: &teloxide::types::User) -> db::User {
+    db::User::new(user.id.0 as i64, &user.first_name, user.username.as_deref())
+}
+
+/// Whether a Telegram user is a person that can be tracked
+///
+/// Bots, this one included should it ever see its own messages, are never tracked, and neither are
+/// placeholder senders such as the Telegram account (777000) of forwarded posts, which aren't
+/// flagged as bots. Every tracking path checks this.
+fn is_trackable(user: &teloxide::types::User) -> bool {
+    !user.is_bot && is_trackable_id(user.id)
+}
+
+/// The part of `is_trackable` that only needs the id, for users known by their id alone such as
+/// the ones of a JSON backup, which can't tell bots apart
+fn is_trackable_id(id: UserId) -> bool {
+    !id.is_telegram() && !id.is_anonymous() && !id.is_channel()
+}
+
+/// Tracks a member that joined a chat in ephemeral mode, the in-memory version of `mark_joined`
+fn remember_ephemeral_join(
+    state: &State,
+    chat: ChatId,
+    user: &teloxide::types::User,
+    mark_joined: bool,
+    cohort: Option<String>,
+) {
+    if !is_trackable(user) {
+        return;
+    }
+    let mut member = ephemeral_user(user);
+    if mark_joined {
+        member.joined_at = Some(db::unix_now());
+        member.cohort = cohort;
+    }
+    state.remember_ephemeral(chat, member);
+}
+
+/// Reply to commands about a user that `store_user` rejected
+const UNTRACKABLE_USER_REPLY: &str = "Bots and Telegram's placeholder accounts can't be tracked.";
+
+/// Where `store_user` put a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StoredUser {
+    /// Written to the database
+    Stored,
+    /// Kept in memory, the chat is ephemeral
+    Ephemeral,
+    /// Not tracked at all, see `is_trackable`
+    Rejected,
+}
+
+/// Stores (or refreshes) a Telegram user as tracked in a chat
+///
+/// Users of ephemeral chats are only kept in memory, the caller then changes the in-memory copy
+/// instead of the database.
+async fn store_user(
+    db: &Db,
+    config: &Config,
+    state: &State,
+    chat_id: ChatId,
+    user: &teloxide::types::User,
+) -> StoredUser {
+    if !is_trackable(user) {
+        log::debug!("[{}] Not storing untrackable user {}", chat_id.0, user.id.0);
+        return StoredUser::Rejected;
+    }
+
+    let user = user.clone();
+    let max_users = config.max_users_per_chat;
+    let (ephemeral, user) = db
+        .call(move |conn| {
+            if db::get_chat_settings(conn, chat_id.0).is_ok_and(|s| s.ephemeral) {
+                return (true, user);
+            }
+            let _ = db::upsert_user(
+                conn,
+                chat_id.0,
+                user.id.0 as i64,
+                &user.first_name,
+                user.username.as_deref(),
+                max_users,
+            );
+            (false, user)
+        })
+        .await;
+    if ephemeral {
+        state.remember_ephemeral(chat_id, ephemeral_user(&user));
+        StoredUser::Ephemeral
+    } else {
+        StoredUser::Stored
+    }
+}
+
+/// Whether a chat member status means the user is in the chat (not left/kicked/banned)
+fn is_member_kind(kind: &ChatMemberKind) -> bool {
+    matches!(
+        kind,
+        ChatMemberKind::Member(_)
+            | ChatMemberKind::Administrator(_)
+            | ChatMemberKind::Owner(_)
+            | ChatMemberKind::Restricted(_)
+    )
+}
+
+/// Tracks user from a message, logging how many messages were tracked about once a minute
+async fn track_message_user(msg: &Message, db: &Db, config: &Config, state: &State) {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        return;
+    }
+
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+    let chat_id = msg.chat.id.0;
+    let title = msg.chat.title().map(String::from);
+    let ephemeral = db
+        .call(move |conn| {
+            let _ = db::upsert_chat(conn, chat_id, title.as_deref());
+            db::get_chat_settings(conn, chat_id).is_ok_and(|settings| settings.ephemeral)
+        })
+        .await;
+
+    let user = match tracked_sender(msg) {
+        Ok(user) => user,
+        Err(reason) => {
+            log::debug!("[{}] Not tracking message: {}", chat_name, reason);
+            return;
+        }
+    };
+
+    if ephemeral {
+        let mut seen = ephemeral_user(user);
+        seen.last_seen = Some(db::unix_now());
+        state.remember_ephemeral(msg.chat.id, seen);
+    } else {
+        let tg_user = user.clone();
+        let max_users = config.max_users_per_chat;
+        db.call(move |conn| {
+            let _ = db::upsert_user(
+                conn,
+                chat_id,
+                tg_user.id.0 as i64,
+                &tg_user.first_name,
+                tg_user.username.as_deref(),
+                max_users,
+            );
+            let _ = db::touch_last_seen(conn, chat_id, tg_user.id.0 as i64);
+        })
+        .await;
+    }
+    // One line per message would flood the logs of busy chats
+    log::debug!(
+        "[{}] Tracked user from message: {} (ID: {})",
+        chat_name,
+        user.first_name,
+        user.id.0
+    );
+    if let Some(count) = state.count_tracked_message(msg.chat.id) {
+        log::info!(
+            "[{}] Tracked {} messages since the last report",
+            chat_name,
+            count
+        );
+    }
+}
+
+/// The user a message should be tracked as, or why its sender isn't tracked
+fn tracked_sender(msg: &Message) -> Result<&teloxide::types::User, &'static str> {
+    // Channel posts forwarded into their linked discussion group come from Telegram itself
+    if msg.is_automatic_forward() {
+        return Err("automatic forward from the linked channel");
+    }
+
+    // Messages sent on behalf of a chat (anonymous admins, channels) don't identify a real user
+    match classify_sender(msg) {
+        Sender::User(user) if is_trackable(user) => Ok(user),
+        Sender::User(_) => Err("sent by a bot or a placeholder account"),
+        Sender::AnonymousAdmin | Sender::Chat(_) => Err("sent on behalf of a chat"),
+        Sender::Unknown => Err("no sender"),
+    }
+}
+
+/// Handles regular messages, tracks users and handles join/leave events
+async fn message_handler(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: SharedConfig,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let config = config.get();
+    track_message_user(&msg, &db, &config, &state).await;
+
+    // Nothing is tracked in private chats, answer with what can be done there instead of staying silent
+    if msg.chat.is_private() && config.private_chat_menu && msg.from.is_some() {
+        return handle_help_command(bot, msg, db, &config).await;
+    }
+
+    // Only process join/leave in groups/supergroups
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        return Ok(());
+    }
+
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+    let chat_id = msg.chat.id.0;
+
+    // Move the tracked users over when the group is upgraded to a supergroup
+    let migration = match (msg.migrate_to_chat_id(), msg.migrate_from_chat_id()) {
+        (Some(new_id), _) => Some((msg.chat.id, *new_id)),
+        (None, Some(old_id)) => Some((*old_id, msg.chat.id)),
+        (None, None) => None,
+    };
+    if let Some((old_id, new_id)) = migration {
+        match db
+            .call(move |conn| db::migrate_chat(conn, old_id.0, new_id.0))
+            .await
+        {
+            Ok(moved) => log::info!(
+                "[{}] Chat migrated from {} to {}, moved {} users",
+                chat_name,
+                old_id.0,
+                new_id.0,
+                moved
+            ),
+            Err(e) => log::error!(
+                "[{}] Failed to migrate chat {} to {}: {}",
+                chat_name,
+                old_id.0,
+                new_id.0,
+                e
+            ),
+        }
+    }
+
+    if msg.new_chat_members().is_none() && msg.left_chat_member().is_none() {
+        return Ok(());
+    }
+    let settings = db
+        .call(move |conn| db::get_chat_settings(conn, chat_id).unwrap_or_default())
+        .await;
+    if !settings.track_membership {
+        return Ok(());
+    }
+
+    // Track new members that joined (from the message's new_chat_members field)
+    if let Some(new_members) = msg.new_chat_members() {
+        for user in new_members {
+            if is_trackable(user) {
+                // Already handled through the chat member update
+                let mark_joined = state.note_join(msg.chat.id, user.id);
+                let cohort = join_cohort(&settings);
+                if settings.ephemeral {
+                    remember_ephemeral_join(&state, msg.chat.id, user, mark_joined, cohort);
+                } else {
+                    let tg_user = user.clone();
+                    let max_users = config.max_users_per_chat;
+                    db.call(move |conn| {
+                        let _ = db::upsert_user(
+                            conn,
+                            chat_id,
+                            tg_user.id.0 as i64,
+                            &tg_user.first_name,
+                            tg_user.username.as_deref(),
+                            max_users,
+                        );
+                        if mark_joined {
+                            let _ = db::mark_joined(conn, chat_id, tg_user.id.0 as i64);
+                            if let Some(cohort) = &cohort {
+                                let _ = db::assign_join_cohort(
+                                    conn,
+                                    chat_id,
+                                    tg_user.id.0 as i64,
+                                    cohort,
+                                );
+                            }
+                        }
+                    })
+                    .await;
+                }
+                if !mark_joined {
+                    continue;
+                }
+                log::info!(
+                    "[{}] New member joined: {} (ID: {})",
+                    chat_name,
+                    user.first_name,
+                    user.id.0
+                );
+            }
+        }
+    }
+
+    // Track if someone left (from the message's left_chat_member field)
+    if let Some(user) = msg.left_chat_member() {
+        let user_id = user.id.0 as i64;
+        let _ = db
+            .call(move |conn| db::delete_user(conn, chat_id, user_id))
+            .await;
+        state.forget_ephemeral(msg.chat.id, user_id);
+        state.forget_join(msg.chat.id, user.id);
+        log::info!(
+            "[{}] Member left: {} (ID: {})",
+            chat_name,
+            user.first_name,
+            user.id.0
+        );
+    }
+
+    Ok(())
+}
+
+/// Handles the /all command - tags all tracked users (admin only)
+async fn command_handler(
+    bot: Bot,
+    msg: Message,
+    cmd: Command,
+    db: Db,
+    shared_config: SharedConfig,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let config = shared_config.get();
+    track_message_user(&msg, &db, &config, &state).await;
+
+    match cmd {
+        Command::CanTag => handle_cantag_command(bot, msg, db, &config, &state).await,
+        Command::Version => handle_version_command(bot, msg).await,
+        Command::Help => handle_help_command(bot, msg, db, &config).await,
+        Command::Reload => handle_reload_command(bot, msg, &config, &shared_config).await,
+        Command::All(text) => handle_all_command(bot, msg, text, db, &config, &state).await,
+        Command::Preview(text) => handle_preview_command(bot, msg, text, db, &config, &state).await,
+        Command::Rename(name) => handle_rename_command(bot, msg, name, db, &config, &state).await,
+        Command::Nick(label) => handle_nick_command(bot, msg, label, db, &config, &state).await,
+        Command::Note(text) => handle_note_command(bot, msg, text, db, &config, &state).await,
+        Command::Random(args) => handle_random_command(bot, msg, args, db, &config, &state).await,
+        Command::MentionCheck => handle_mentioncheck_command(bot, msg, db, &config, &state).await,
+        Command::Tag(args) => handle_tag_command(bot, msg, args, db, &config, &state).await,
+        Command::AddTag(args) => handle_addtag_command(bot, msg, args, db, &config, &state).await,
+        Command::RemTag(args) => handle_remtag_command(bot, msg, args, db, &config, &state).await,
+        Command::GroupAdmin(args) => {
+            handle_groupadmin_command(bot, msg, args, db, &config, &state).await
+        }
+        Command::Groups => handle_groups_command(bot, msg, db, &config).await,
+        Command::RenameTag(args) => handle_renametag_command(bot, msg, args, db, &config).await,
+        Command::Undo => handle_undo_command(bot, msg, &config, &state).await,
+        Command::TagIn(args) => handle_tagin_command(bot, msg, args, db, &config, &state).await,
+        Command::Whois(arg) => handle_whois_command(bot, msg, arg, db, &config, &state).await,
+        Command::Newcomers(args) => {
+            handle_newcomers_command(bot, msg, args, db, &config, &state).await
+        }
+        Command::Stats => handle_stats_command(bot, msg, db, &config, &state).await,
+        Command::CleanBots(token) => {
+            handle_cleanbots_command(bot, msg, token, db, &config, &state).await
+        }
+        Command::Prune(token) => handle_prune_command(bot, msg, token, db, &config, &state).await,
+        Command::ExportCsv => handle_exportcsv_command(bot, msg, db, &config, &state).await,
+        Command::Timezone(zone) => handle_timezone_command(bot, msg, zone, db, &config).await,
+        Command::Config(args) => handle_config_command(bot, msg, args, db, &config, &state).await,
+        Command::TestTag => handle_testtag_command(bot, msg, db, &state).await,
+        Command::PollAll(args) => handle_pollall_command(bot, msg, args, db, &config, &state).await,
+        Command::Chats(page) => handle_chats_command(bot, msg, page, db, &config).await,
+        Command::Prefs(pref) => handle_prefs_command(bot, msg, pref, db, &state).await,
+        Command::Reconcile => handle_reconcile_command(bot, msg, db, &config, &state).await,
+        Command::Find(query) => handle_find_command(bot, msg, query, db, &config).await,
+        Command::Excluded => handle_excluded_command(bot, msg, db, &state, &config).await,
+        Command::Reach => handle_reach_command(bot, msg, db, &state, &config).await,
+        Command::ImportPinned => handle_importpinned_command(bot, msg, db, &config, &state).await,
+        Command::Roster(page) => handle_roster_command(bot, msg, page, db, &config, &state).await,
+        Command::Backup(args) => handle_backup_command(bot, msg, args, db, &config, &state).await,
+        Command::Schedule(args) => handle_schedule_command(bot, msg, args, db, &config).await,
+        Command::MyGroups => handle_mygroups_command(bot, msg, db).await,
+        Command::ForgetMe(token) => handle_forgetme_command(bot, msg, token, db, &state).await,
+        Command::AlwaysTag(args) => {
+            handle_alwaystag_command(bot, msg, args, db, &config, &state).await
+        }
+        Command::SetCohort(args) => {
+            handle_setcohort_command(bot, msg, args, db, &config, &state).await
+        }
+        Command::TagCohort(args) => {
+            handle_tagcohort_command(bot, msg, args, db, &config, &state).await
+        }
+        Command::RollCall(text) => {
+            handle_rollcall_command(bot, msg, text, db, &config, &state).await
+        }
+        Command::RollCallStatus => handle_rollcallstatus_command(bot, msg, db, &config).await,
+        Command::TagReactors(args) => {
+            handle_tagreactors_command(bot, msg, args, db, &config, &state).await
+        }
+        Command::Activity(days) => handle_activity_command(bot, msg, days, db, &config).await,
+        Command::Snapshot => handle_snapshot_command(bot, msg, db, &config, &state).await,
+        Command::Growth(days) => handle_growth_command(bot, msg, days, db, &config, &state).await,
+        Command::ScheduleTag(args) => handle_scheduletag_command(bot, msg, args, db, &config).await,
+        Command::CancelTag(id) => handle_canceltag_command(bot, msg, id, db, &config).await,
+        Command::Pending => handle_pending_command(bot, msg, db, &config).await,
+        Command::Schedules => handle_schedules_command(bot, msg, db, &config).await,
+        Command::Unschedule(id) => handle_unschedule_command(bot, msg, id, db, &config).await,
+        Command::Inactive(args) => {
+            handle_inactive_command(bot, msg, args, db, &config, &state).await
+        }
+        Command::RateLimit => handle_ratelimit_command(bot, msg, &config, &state).await,
+        Command::IntegrityCheck => handle_integritycheck_command(bot, msg, db, &config).await,
+        Command::LastSeen(username) => {
+            handle_lastseen_command(bot, msg, username, db, &config, &state).await
+        }
+    }
+}
+
+/// Checks that the sender of the message is an admin of the chat, replying to them otherwise
+async fn ensure_admin(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    command: &str,
+) -> ResponseResult<bool> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    let user = match classify_sender(msg) {
+        Sender::User(user) => user,
+        Sender::AnonymousAdmin => {
+            log::info!(
+                "[{}] Anonymous admin authorized for /{}",
+                chat_name,
+                command
+            );
+            return Ok(true);
+        }
+        // Nobody to answer or to authorize, so the command is dropped without a reply
+        Sender::Unknown => {
+            log::debug!(
+                "[{}] Ignoring /{}: the message has neither a sender nor a sender chat",
+                chat_name,
+                command
+            );
+            return Ok(false);
+        }
+        Sender::Chat(_) => {
+            log::warn!(
+                "[{}] {} attempted to use /{}",
+                chat_name,
+                describe_sender(msg),
+                command
+            );
+            bot.send_message(msg.chat.id, "Only admins can use this command.")
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(false);
+        }
+    };
+
+    if config.is_owner(user.id) {
+        log::info!(
+            "[{}] Bot owner {} (ID: {}) authorized for /{}",
+            chat_name,
+            user.first_name,
+            user.id.0,
+            command
+        );
+        return Ok(true);
+    }
+
+    let member = match bot.get_chat_member(msg.chat.id, user.id).await {
+        Ok(member) => member,
+        Err(e) => {
+            log::error!(
+                "[{}] Failed to check admin status of {} (ID: {}) for /{}: {}",
+                chat_name,
+                user.first_name,
+                user.id.0,
+                command,
+                e
+            );
+            bot.send_message(
+                msg.chat.id,
+                "Couldn't verify your admin status, try again in a moment.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(false);
+        }
+    };
+    let is_admin = matches!(
+        member.kind,
+        ChatMemberKind::Administrator(_) | ChatMemberKind::Owner(_)
+    );
+
+    if !is_admin {
+        log::warn!(
+            "[{}] Non-admin {} (ID: {}) attempted to use /{}",
+            chat_name,
+            user.first_name,
+            user.id.0,
+            command
+        );
+        bot.send_message(msg.chat.id, "Only admins can use this command.")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+    }
+
+    Ok(is_admin)
+}
+
+/// Who a message comes from, as far as authorizing commands and tracking users go
+#[derive(Debug, Clone, Copy)]
+enum Sender<'a> {
+    /// A user writing from their own account
+    User(&'a teloxide::types::User),
+    /// An admin posting anonymously, on behalf of the group itself
+    AnonymousAdmin,
+    /// Another chat such as a channel, `from` then only holds a placeholder bot
+    Chat(&'a teloxide::types::Chat),
+    /// Neither a user nor a chat
+    Unknown,
+}
+
+/// Tells who sent a message from its `sender_chat` and `from`
+fn classify_sender(msg: &Message) -> Sender<'_> {
+    match (msg.sender_chat.as_ref(), msg.from.as_ref()) {
+        (Some(chat), _) if chat.id == msg.chat.id => Sender::AnonymousAdmin,
+        (Some(chat), _) => Sender::Chat(chat),
+        (None, Some(user)) => Sender::User(user),
+        (None, None) => Sender::Unknown,
+    }
+}
+
+/// Who can use a command, as listed by /help
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// Any member, in groups and in private
+    Everyone,
+    /// Any member of a group
+    Members,
+    /// Anyone, in a private chat with the bot
+    Private,
+    /// Chat admins and the managers of a tag group
+    GroupManagers,
+    /// Chat admins
+    Admins,
+    /// The bot owner, preferably in private
+    Owner,
+}
+
+/// Who can use the command with the given name, every command not listed here is for admins
+fn command_access(command: &str) -> Access {
+    match command {
+        "version" | "help" => Access::Everyone,
+        "prefs" | "testtag" => Access::Members,
+        "mygroups" | "forgetme" => Access::Private,
+        "addtag" | "remtag" => Access::GroupManagers,
+        "chats" | "backup" | "ratelimit" | "reload" | "integritycheck" => Access::Owner,
+        _ => Access::Admins,
+    }
+}
+
+/// Whether the sender of a message is an admin of its chat (or the owner), without replying
+async fn is_sender_admin(bot: &Bot, msg: &Message, config: &Config) -> bool {
+    let user = match classify_sender(msg) {
+        Sender::User(user) => user,
+        Sender::AnonymousAdmin => return true,
+        Sender::Chat(_) | Sender::Unknown => return false,
+    };
+    if config.is_owner(user.id) {
+        return true;
+    }
+
+    bot.get_chat_member(msg.chat.id, user.id)
+        .await
+        .is_ok_and(|member| {
+            matches!(
+                member.kind,
+                ChatMemberKind::Administrator(_) | ChatMemberKind::Owner(_)
+            )
+        })
+}
+
+/// Handles the /help command - lists the commands the sender can use in this chat
+async fn handle_help_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    let in_group = msg.chat.is_group() || msg.chat.is_supergroup();
+    let sender = msg.from.as_ref().filter(|_| msg.sender_chat.is_none());
+    let is_owner = sender.is_some_and(|user| config.is_owner(user.id));
+    let is_admin = in_group && is_sender_admin(&bot, &msg, config).await;
+
+    // Tag groups the sender manages, which unlock /addtag and /remtag for them
+    let managed: Vec<String> = match sender {
+        Some(user) if in_group && !is_admin => {
+            let (chat_id, user_id) = (msg.chat.id.0, user.id.0 as i64);
+            db.call(move |conn| db::list_group_admins(conn, chat_id))
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(_, manager)| *manager == user_id)
+                .map(|(group, _)| group)
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+
+    let available = |access: Access| match access {
+        Access::Everyone => true,
+        Access::Members => in_group,
+        Access::Private => !in_group,
+        Access::GroupManagers => in_group && (is_admin || !managed.is_empty()),
+        Access::Admins => in_group && is_admin,
+        Access::Owner => is_owner,
+    };
+
+    let mut reply = String::from(match (in_group, is_admin) {
+        (false, _) => "Commands you can use in this private chat:",
+        (true, true) => "Commands you can use here as an admin:",
+        (true, false) => "Commands you can use here:",
+    });
+    for command in Command::bot_commands() {
+        let name = command.command.trim_start_matches('/');
+        if available(command_access(name)) {
+            reply.push_str(&format!("\n/{} - {}", name, command.description));
+        }
+    }
+    if !managed.is_empty() {
+        reply.push_str(&format!(
+            "\n\n/addtag and /remtag only work for the groups you manage: {}",
+            managed.join(", ")
+        ));
+    }
+    if !in_group {
+        reply.push_str("\n\nAdd me to a group to tag its members, most commands only work there.");
+    }
+
+    // The full list for admins can go past the length of a single message
+    let mut messages = vec![String::new()];
+    for line in reply.lines() {
+        let current = messages.last_mut().expect("messages is never empty");
+        if !current.is_empty() && current.len() + line.len() + 1 > HELP_MESSAGE_LEN {
+            messages.push(line.to_string());
+        } else {
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(line);
+        }
+    }
+    for text in messages {
+        bot.send_message(msg.chat.id, text)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Describes who sent a message, for logs
+fn describe_sender(msg: &Message) -> String {
+    match classify_sender(msg) {
+        Sender::AnonymousAdmin => "Anonymous admin".to_string(),
+        Sender::Chat(chat) => format!(
+            "Chat {} (ID: {})",
+            chat.title().unwrap_or("Unknown"),
+            chat.id.0
+        ),
+        Sender::User(user) => format!("{} (ID: {})", user.first_name, user.id.0),
+        Sender::Unknown => "Unknown sender".to_string(),
+    }
+}
+
+/// Checks that the sender of the message is the bot owner, replying to them otherwise
+async fn ensure_owner(
+    bot: &Bot,
+    msg: &Message,
+    config: &Config,
+    command: &str,
+) -> ResponseResult<bool> {
+    let is_owner = msg.from.as_ref().is_some_and(|u| config.is_owner(u.id));
+
+    if !is_owner {
+        log::warn!(
+            "[{}] Non-owner attempted to use /{}",
+            msg.chat.title().unwrap_or("Unknown"),
+            command
+        );
+        bot.send_message(msg.chat.id, "Only the bot owner can use this command.")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+    }
+
+    Ok(is_owner)
+}
+
+/// Handles the /rename command - pins a custom display name for the replied user (admin only)
+async fn handle_rename_command(
+    bot: Bot,
+    msg: Message,
+    name: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "rename").await? {
+        return Ok(());
+    }
+
+    let target = match msg.reply_to_message().and_then(|m| m.from.as_ref()) {
+        Some(u) if is_trackable(u) => u,
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                "Reply to a message of the user you want to rename.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let name = name.trim();
+    let override_name = if name.is_empty() { None } else { Some(name) };
+
+    let (chat_id, user_id) = (msg.chat.id.0, target.id.0 as i64);
+    let stored_name = override_name.map(String::from);
+    match store_user(&db, config, state, msg.chat.id, target).await {
+        StoredUser::Ephemeral => {
+            state.update_ephemeral(msg.chat.id, user_id, |u| u.override_name = stored_name);
+        }
+        StoredUser::Stored => {
+            let _ = db
+                .call(move |conn| {
+                    db::set_override_name(conn, chat_id, user_id, stored_name.as_deref())
+                })
+                .await;
+        }
+        StoredUser::Rejected => {
+            bot.send_message(msg.chat.id, UNTRACKABLE_USER_REPLY)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let reply = match override_name {
+        Some(name) => {
+            log::info!(
+                "[{}] Renamed {} (ID: {}) to {}",
+                chat_name,
+                target.first_name,
+                target.id.0,
+                name
+            );
+            format!("{} will now be tagged as {}.", target.first_name, name)
+        }
+        None => {
+            log::info!(
+                "[{}] Reset name of {} (ID: {})",
+                chat_name,
+                target.first_name,
+                target.id.0
+            );
+            format!(
+                "{} will now be tagged with their own name.",
+                target.first_name
+            )
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /nick command - sets a label shown next to the replied user's name (admin only)
+async fn handle_nick_command(
+    bot: Bot,
+    msg: Message,
+    label: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "nick").await? {
+        return Ok(());
+    }
+
+    let target = match msg.reply_to_message().and_then(|m| m.from.as_ref()) {
+        Some(u) if is_trackable(u) => u,
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                "Reply to a message of the user you want to label.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let label = label.trim();
+    if label.chars().count() > MAX_NICK_LEN {
+        bot.send_message(
+            msg.chat.id,
+            format!("Labels can be at most {} characters long.", MAX_NICK_LEN),
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    }
+    let label = if label.is_empty() { None } else { Some(label) };
+
+    let (chat_id, user_id) = (msg.chat.id.0, target.id.0 as i64);
+    let stored_label = label.map(String::from);
+    match store_user(&db, config, state, msg.chat.id, target).await {
+        StoredUser::Ephemeral => {
+            state.update_ephemeral(msg.chat.id, user_id, |u| u.label = stored_label);
+        }
+        StoredUser::Stored => {
+            let _ = db
+                .call(move |conn| db::set_label(conn, chat_id, user_id, stored_label.as_deref()))
+                .await;
+        }
+        StoredUser::Rejected => {
+            bot.send_message(msg.chat.id, UNTRACKABLE_USER_REPLY)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let reply = match label {
+        Some(label) => {
+            log::info!(
+                "[{}] Labeled {} (ID: {}) as {}",
+                chat_name,
+                target.first_name,
+                target.id.0,
+                label
+            );
+            format!(
+                "{} will now be tagged with the label {}.",
+                target.first_name, label
+            )
+        }
+        None => {
+            log::info!(
+                "[{}] Cleared label of {} (ID: {})",
+                chat_name,
+                target.first_name,
+                target.id.0
+            );
+            format!("{} will now be tagged without a label.", target.first_name)
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /note command - shows or sets a moderation note on the replied user (admin only)
+async fn handle_note_command(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "note").await? {
+        return Ok(());
+    }
+
+    let target = match msg.reply_to_message().and_then(|m| m.from.as_ref()) {
+        Some(u) if is_trackable(u) => u,
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                "Reply to a message of the user whose note you want to see or set.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(());
+        }
+    };
+    let (chat_id, user_id) = (msg.chat.id.0, target.id.0 as i64);
+
+    let reply = match text.trim() {
+        "" => {
+            let stored = db
+                .call(move |conn| db::get_user(conn, chat_id, user_id))
+                .await
+                .ok()
+                .flatten();
+            let note = stored
+                .or_else(|| state.ephemeral_user(msg.chat.id, user_id))
+                .and_then(|u| u.note);
+            match note {
+                Some(note) => format!("Note on {}: {}", target.first_name, note),
+                None => format!("There is no note on {}.", target.first_name),
+            }
+        }
+        "--clear" => {
+            state.update_ephemeral(msg.chat.id, user_id, |u| u.note = None);
+            let _ = db
+                .call(move |conn| db::set_note(conn, chat_id, user_id, None))
+                .await;
+            log::info!(
+                "[{}] Cleared note on {} (ID: {})",
+                chat_name,
+                target.first_name,
+                target.id.0
+            );
+            format!("Note on {} removed.", target.first_name)
+        }
+        note => {
+            let stored_note = note.to_string();
+            match store_user(&db, config, state, msg.chat.id, target).await {
+                StoredUser::Ephemeral => {
+                    state.update_ephemeral(msg.chat.id, user_id, |u| u.note = Some(stored_note));
+                }
+                StoredUser::Stored => {
+                    let _ = db
+                        .call(move |conn| db::set_note(conn, chat_id, user_id, Some(&stored_note)))
+                        .await;
+                }
+                StoredUser::Rejected => {
+                    bot.send_message(msg.chat.id, UNTRACKABLE_USER_REPLY)
+                        .reply_parameters(ReplyParameters::new(msg.id))
+                        .await?;
+                    return Ok(());
+                }
+            }
+            log::info!(
+                "[{}] Set note on {} (ID: {})",
+                chat_name,
+                target.first_name,
+                target.id.0
+            );
+            format!("Note on {} saved.", target.first_name)
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /newcomers command - tags the most recently joined members (admin only)
+async fn handle_newcomers_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "newcomers").await? {
+        return Ok(());
+    }
+
+    let args = args.trim();
+    let (count, greeting) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let count = match count.parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            bot.send_message(msg.chat.id, "Usage: /newcomers <N> [greeting]")
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if let Some(reason) = quota_refusal(msg.chat.id, chat_name, &db, config).await {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = lock_tag(&bot, &msg, state).await? else {
+        return Ok(());
+    };
+
+    let options = AllOptions {
+        newest: Some(count),
+        ..AllOptions::default()
+    };
+    let targets = load_tag_targets(msg.chat.id, chat_name, &db, state, &options).await;
+    if targets.tracked == 0 {
+        bot.send_message(
+            msg.chat.id,
+            "No joins recorded yet. Members are recorded as they join the group.",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    }
+    if report_empty_targets(&bot, &msg, &targets).await? {
+        return Ok(());
+    }
+
+    let greeting = match greeting.trim() {
+        "" => "Welcome to the group!",
+        greeting => greeting,
+    };
+
+    log::info!("[{}] Tagging {} newcomers", chat_name, targets.users.len());
+
+    let header = tag_header(greeting, &targets.skipped, targets.format);
+    send_tag(
+        &bot,
+        msg.chat.id,
+        Some(msg.id),
+        state,
+        &header,
+        &targets.users,
+        targets.format,
+    )
+    .await?;
+    log_sent_tag(msg.chat.id, chat_name, &targets.users, &db, state).await;
+
+    Ok(())
+}
+
+/// Handles the /inactive command - tags the users that haven't written for a number of days (admin only)
+async fn handle_inactive_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "inactive").await? {
+        return Ok(());
+    }
+
+    let args = args.trim();
+    let (days, message) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let days = match days.parse::<i64>() {
+        Ok(n) if (1..=INACTIVE_MAX_DAYS).contains(&n) => n,
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "Usage: /inactive <days> [message], with 1 to {} days",
+                    INACTIVE_MAX_DAYS
+                ),
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(());
+        }
+    };
+
+    if let Some(remaining) = state.cooldown_remaining(msg.chat.id, "inactive") {
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "/inactive was used recently, try again in {} minutes.",
+                remaining.as_secs().div_ceil(60)
+            ),
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    }
+
+    if let Some(reason) = quota_refusal(msg.chat.id, chat_name, &db, config).await {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = lock_tag(&bot, &msg, state).await? else {
+        return Ok(());
+    };
+
+    let targets = gather_tag_targets(
+        &bot,
+        msg.chat.id,
+        chat_name,
+        &db,
+        config,
+        state,
+        &AllOptions::default(),
+    )
+    .await;
+
+    // Users that only joined count as inactive since they joined, users without any record are left out
+    let cutoff = db::unix_now() - days * 86400;
+    let users: Vec<db::User> = targets
+        .users
+        .into_iter()
+        .filter(|u| u.always_tag || u.last_seen.or(u.joined_at).is_some_and(|at| at < cutoff))
+        .collect();
+
+    if users.is_empty() {
+        bot.send_message(
+            msg.chat.id,
+            format!("No tracked users have been inactive for {} days.", days),
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    }
+
+    log::info!(
+        "[{}] Tagging {} users inactive for {} days",
+        chat_name,
+        users.len(),
+        days
+    );
+
+    let message = match message.trim() {
+        "" => "We miss you!",
+        message => message,
+    };
+    let mut notes = vec![format!(
+        "{} users inactive for more than {} days",
+        users.len(),
+        days
+    )];
+    notes.extend(targets.skipped);
+
+    let header = tag_header(message, &notes, targets.format);
+    send_tag(
+        &bot,
+        msg.chat.id,
+        Some(msg.id),
+        state,
+        &header,
+        &users,
+        targets.format,
+    )
+    .await?;
+    // Only a tag that went out counts, a failed one can be retried right away
+    state.start_cooldown(msg.chat.id, "inactive", INACTIVE_COOLDOWN);
+    log_sent_tag(msg.chat.id, chat_name, &users, &db, state).await;
+
+    Ok(())
+}
+
+/// How /random picks users
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RandomWeighting {
+    /// Every user is equally likely
+    Uniform,
+    /// Users that wrote more messages are more likely
+    Active,
+    /// Users that wrote fewer messages are more likely
+    Quiet,
+}
+
+impl RandomWeighting {
+    /// Weight of a user, `message_count + 1` (or its inverse) so users with no messages can still be picked
+    fn weight(self, user: &db::User) -> f64 {
+        let count = user.message_count.max(0) as f64;
+        match self {
+            RandomWeighting::Uniform => 1.0,
+            RandomWeighting::Active => count + 1.0,
+            RandomWeighting::Quiet => 1.0 / (count + 1.0),
+        }
+    }
+}
+
+/// Picks `count` distinct users, each draw favoring heavier users (Efraimidis-Spirakis sampling)
+///
+/// Every user gets the key `random^(1 / weight)` and the users with the largest keys are picked.
+fn weighted_sample<R: rand::Rng>(
+    users: Vec<db::User>,
+    count: usize,
+    weighting: RandomWeighting,
+    rng: &mut R,
+) -> Vec<db::User> {
+    let mut keyed: Vec<(f64, db::User)> = users
+        .into_iter()
+        .map(|user| {
+            let key = rng.random::<f64>().powf(1.0 / weighting.weight(&user));
+            (key, user)
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    keyed
+        .into_iter()
+        .take(count)
+        .map(|(_, user)| user)
+        .collect()
+}
+
+/// Handles the /random command - tags a few random users, optionally favoring active or quiet ones (admin only)
+async fn handle_random_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "random").await? {
+        return Ok(());
+    }
+
+    let mut rest = args.trim();
+    let mut weighting = RandomWeighting::Uniform;
+    let (flag, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    if flag.starts_with("--") {
+        weighting = match flag {
+            "--weighted" => RandomWeighting::Active,
+            "--weighted-inverse" => RandomWeighting::Quiet,
+            _ => {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Unknown flag {}, use --weighted or --weighted-inverse",
+                        flag
+                    ),
+                )
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+                return Ok(());
+            }
+        };
+        rest = tail.trim_start();
+    }
+
+    let (count, text) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let count = match count.parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                "Usage: /random [--weighted|--weighted-inverse] <N> [message]",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(());
+        }
+    };
+
+    if let Some(reason) = quota_refusal(msg.chat.id, chat_name, &db, config).await {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = lock_tag(&bot, &msg, state).await? else {
+        return Ok(());
+    };
+
+    let targets = gather_tag_targets(
+        &bot,
+        msg.chat.id,
+        chat_name,
+        &db,
+        config,
+        state,
+        &AllOptions::default(),
+    )
+    .await;
+    if report_empty_targets(&bot, &msg, &targets).await? {
+        return Ok(());
+    }
+
+    let users = weighted_sample(targets.users, count, weighting, &mut rand::rng());
+
+    log::info!(
+        "[{}] Tagging {} random users ({:?})",
+        chat_name,
+        users.len(),
+        weighting
+    );
+
+    let header = tag_header(text, &[], targets.format);
+    send_tag(
+        &bot,
+        msg.chat.id,
+        Some(msg.id),
+        state,
+        &header,
+        &users,
+        targets.format,
+    )
+    .await?;
+    log_sent_tag(msg.chat.id, chat_name, &users, &db, state).await;
+
+    Ok(())
+}
+
+/// Normalizes a tag group name, `None` if it's empty, too long or has characters other than letters, digits, `_` or `-`
+fn parse_group_name(name: &str) -> Option<String> {
+    let name = name.trim().to_lowercase();
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_GROUP_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+    valid.then_some(name)
+}
+
+/// Usage of the commands taking a group and a user, such as /addtag and /remtag
+fn group_usage(command: &str) -> String {
+    format!(
+        "Usage: /{} <group> in reply to a message, or /{} <group> @username",
+        command, command
+    )
+}
+
+/// Splits the group off the arguments of /addtag and /remtag, returning it with the user argument
+fn split_group_arg<'a>(args: &'a str, command: &str) -> Result<(String, &'a str), String> {
+    let args = args.trim();
+    let (name, target) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let group = parse_group_name(name).ok_or_else(|| {
+        format!(
+            "Group names can only have letters, digits, _ and -, up to {} characters.\n{}",
+            MAX_GROUP_NAME_LEN,
+            group_usage(command)
+        )
+    })?;
+
+    Ok((group, target))
+}
+
+/// Finds the tracked user given after the group, or the reply explaining what's wrong
+async fn group_target_user(
+    bot: &Bot,
+    db: &Db,
+    state: &State,
+    msg: &Message,
+    target: &str,
+    command: &str,
+) -> Result<db::User, String> {
+    lookup_target_user(bot, db, state, msg, target)
+        .await
+        .ok_or_else(|| group_usage(command))?
+        .tracked()
+}
+
+/// Reads the group and the user of commands run by admins only, or the reply explaining what's wrong
+async fn parse_group_target(
+    bot: &Bot,
+    db: &Db,
+    state: &State,
+    msg: &Message,
+    args: &str,
+    command: &str,
+) -> Result<(String, db::User), String> {
+    let (group, target) = split_group_arg(args, command)?;
+    let user = group_target_user(bot, db, state, msg, target, command).await?;
+
+    Ok((group, user))
+}
+
+/// Cohort a member that just joined is put in when the chat has auto_cohort on: their join month
+fn join_cohort(settings: &settings::ChatSettings) -> Option<String> {
+    settings
+        .auto_cohort
+        .then(|| settings.local_now().format("%Y-%m").to_string())
+}
+
+/// Lists the cohorts of a chat for replies, or says there are none
+async fn describe_cohorts(db: &Db, chat_id: ChatId) -> String {
+    let cohorts = db
+        .call(move |conn| db::list_cohorts(conn, chat_id.0))
+        .await
+        .unwrap_or_default();
+    if cohorts.is_empty() {
+        return "This chat has no cohorts yet, add users with /setcohort <label>.".to_string();
+    }
+
+    let mut reply = String::from("Cohorts of this chat:");
+    for (cohort, count) in cohorts {
+        reply.push_str(&format!("\n- {} ({} users)", cohort, count));
+    }
+    reply
+}
+
+/// Handles the /setcohort command - puts a user in a cohort, or takes them out with `none` (admin only)
+async fn handle_setcohort_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "setcohort").await? {
+        return Ok(());
+    }
+
+    // Cohort labels follow the same rules as tag group names
+    let (cohort, user) = match parse_group_target(&bot, &db, state, &msg, &args, "setcohort").await
+    {
+        Ok(target) => target,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+    let cohort = (cohort != "none").then_some(cohort);
+
+    let (chat_id, user_id, label) = (msg.chat.id.0, user.user_id, cohort.clone());
+    let result = if state.update_ephemeral(msg.chat.id, user_id, |u| u.cohort = label.clone()) {
+        Ok(())
+    } else {
+        db.call(move |conn| db::set_cohort(conn, chat_id, user_id, label.as_deref()))
+            .await
+    };
+
+    let reply = match (result, &cohort) {
+        (Ok(()), Some(cohort)) => {
+            log::info!("[{}] Put {} in cohort {}", chat_name, user.user_id, cohort);
+            format!("{} is now in cohort {}.", user.display_name(), cohort)
+        }
+        (Ok(()), None) => {
+            log::info!("[{}] Cleared the cohort of {}", chat_name, user.user_id);
+            format!("{} is no longer in a cohort.", user.display_name())
+        }
+        (Err(e), _) => {
+            log::error!("[{}] Failed to set cohort: {}", chat_name, e);
+            "Failed to set the cohort.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /mygroups command - lists the chats where the sender is tracked (private chat only)
+async fn handle_mygroups_command(bot: Bot, msg: Message, db: Db) -> ResponseResult<()> {
+    let user = match msg.from.as_ref() {
+        Some(user) if msg.chat.is_private() => user,
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                "This command only works in a private chat with the bot.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(());
+        }
+    };
+
+    let user_id = user.id.0 as i64;
+    let chats = db
+        .call(move |conn| db::chats_for_user(conn, user_id))
+        .await
+        .unwrap_or_default();
+
+    let reply = if chats.is_empty() {
+        "I don't track you in any chat.".to_string()
+    } else {
+        let noun = if chats.len() == 1 { "chat" } else { "chats" };
+        let mut reply = format!("I track you in {} {}:", chats.len(), noun);
+        for (chat_id, title) in &chats {
+            reply.push_str(&format!(
+                "\n- {} ({})",
+                title.as_deref().unwrap_or("(unknown title)"),
+                chat_id
+            ));
+        }
+        reply.push_str(
+            "\n\nI store your name, username, when you last wrote and the settings admins \
+             gave you there, to be able to tag you.",
+        );
+        reply
+    };
+
+    bot.send_message(msg.chat.id, reply).await?;
+
+    Ok(())
+}
+
+/// Handles the /forgetme command - removes the sender from every chat the bot tracks (private chat only)
+async fn handle_forgetme_command(
+    bot: Bot,
+    msg: Message,
+    token: String,
+    db: Db,
+    state: &State,
+) -> ResponseResult<()> {
+    let user = match msg.from.as_ref() {
+        Some(user) if msg.chat.is_private() => user,
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                "This command only works in a private chat with the bot.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(());
+        }
+    };
+
+    if !require_confirmation(&bot, &msg, state, "forgetme", &token).await? {
+        return Ok(());
+    }
+
+    let user_id = user.id.0 as i64;
+    let forgotten = state.forget_ephemeral_everywhere(user_id);
+    let reply = match db
+        .call(move |conn| db::delete_user_everywhere(conn, user_id))
+        .await
+    {
+        Ok(chats) => {
+            let chats = chats + forgotten;
+            log::info!(
+                "Erased user {} (ID: {}) from {} chats at their request",
+                user.first_name,
+                user.id.0,
+                chats
+            );
+            format!(
+                "Deleted everything I stored about you in {} chats. Writing in a chat where I \
+                 am will make me track you there again.",
+                chats
+            )
+        }
+        Err(e) => {
+            log::error!("Failed to erase user {}: {}", user.id.0, e);
+            "Couldn't delete your data, try again later.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /alwaystag command - makes filters such as --active never leave a user out (admin only)
+async fn handle_alwaystag_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "alwaystag").await? {
+        return Ok(());
+    }
+
+    let args = args.trim();
+    let (target, always_tag) = match args.rsplit_once(char::is_whitespace) {
+        Some((target, "off")) => (target, false),
+        _ if args == "off" => ("", false),
+        _ => (args, true),
+    };
+
+    let usage = "Usage: /alwaystag in reply to a message, or /alwaystag @username, add off to stop";
+    let user = match lookup_target_user(&bot, &db, state, &msg, target).await {
+        Some(target) => target.tracked(),
+        None => Err(usage.to_string()),
+    };
+    let user = match user {
+        Ok(user) => user,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let (chat_id, user_id) = (msg.chat.id.0, user.user_id);
+    let result = if state.update_ephemeral(msg.chat.id, user_id, |u| u.always_tag = always_tag) {
+        Ok(())
+    } else {
+        db.call(move |conn| db::set_always_tag(conn, chat_id, user_id, always_tag))
+            .await
+    };
+
+    let reply = match result {
+        Ok(()) if always_tag => {
+            log::info!(
+                "[{}] {} (ID: {}) is now always tagged",
+                chat_name,
+                user.first_name,
+                user.user_id
+            );
+            format!(
+                "{} will be tagged even when filters such as --active or the exclude pattern would leave them out.",
+                user.display_name()
+            )
+        }
+        Ok(()) => {
+            log::info!(
+                "[{}] {} (ID: {}) is no longer always tagged",
+                chat_name,
+                user.first_name,
+                user.user_id
+            );
+            format!(
+                "{} will be left out by filters like everyone else.",
+                user.display_name()
+            )
+        }
+        Err(e) => {
+            log::error!("[{}] Failed to set always tag: {}", chat_name, e);
+            "Couldn't update the user, try again later.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /tagcohort command - tags the users of a cohort (admin only)
+async fn handle_tagcohort_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "tagcohort").await? {
+        return Ok(());
+    }
+
+    let args = args.trim();
+    let (label, text) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let Some(cohort) = parse_group_name(label) else {
+        let reply = format!(
+            "Usage: /tagcohort <label> [message]\n\n{}",
+            describe_cohorts(&db, msg.chat.id).await
+        );
+        bot.send_message(msg.chat.id, reply)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    };
+
+    if let Some(reason) = quota_refusal(msg.chat.id, chat_name, &db, config).await {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = lock_tag(&bot, &msg, state).await? else {
+        return Ok(());
+    };
+
+    let options = AllOptions {
+        cohort: Some(cohort.clone()),
+        ..AllOptions::default()
+    };
+    let targets =
+        gather_tag_targets(&bot, msg.chat.id, chat_name, &db, config, state, &options).await;
+    if targets.tracked == 0 {
+        let reply = format!(
+            "There is no cohort named {}.\n\n{}",
+            cohort,
+            describe_cohorts(&db, msg.chat.id).await
+        );
+        bot.send_message(msg.chat.id, reply)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+    if report_empty_targets(&bot, &msg, &targets).await? {
+        return Ok(());
+    }
+
+    log::info!(
+        "[{}] Tagging {} users of cohort {}",
+        chat_name,
+        targets.users.len(),
+        cohort
+    );
+
+    let header = tag_header(text, &targets.skipped, targets.format);
+    send_tag(
+        &bot,
+        msg.chat.id,
+        Some(msg.id),
+        state,
+        &header,
+        &targets.users,
+        targets.format,
+    )
+    .await?;
+    log_sent_tag(msg.chat.id, chat_name, &targets.users, &db, state).await;
+
+    Ok(())
+}
+
+/// Lets chat admins and the managers of a tag group change it, replying to anyone else
+async fn ensure_group_manager(
+    bot: &Bot,
+    msg: &Message,
+    db: &Db,
+    config: &Config,
+    group: &str,
+    command: &str,
+) -> ResponseResult<bool> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    // Messages sent on behalf of a chat can't be tied to a manager, leave them to ensure_admin
+    if let Some(user) = msg.from.as_ref().filter(|_| msg.sender_chat.is_none()) {
+        let (chat_id, user_id, name) = (msg.chat.id.0, user.id.0 as i64, group.to_string());
+        let is_manager = db
+            .call(move |conn| db::is_group_admin(conn, chat_id, &name, user_id))
+            .await
+            .unwrap_or(false);
+        if is_manager {
+            log::info!(
+                "[{}] Manager {} (ID: {}) of group {} authorized for /{}",
+                chat_name,
+                user.first_name,
+                user.id.0,
+                group,
+                command
+            );
+            return Ok(true);
+        }
+    }
+
+    ensure_admin(bot, msg, config, command).await
+}
+
+/// Handles the /groupadmin command - chooses who can manage a tag group besides the chat admins (admin only)
+async fn handle_groupadmin_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "groupadmin").await? {
+        return Ok(());
+    }
+
+    let args = args.trim();
+    let (action, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let chat_id = msg.chat.id.0;
+
+    let reply = match action {
+        "list" => {
+            let managers = db
+                .call(move |conn| {
+                    let mut managers = Vec::new();
+                    for (group, user_id) in db::list_group_admins(conn, chat_id).unwrap_or_default()
+                    {
+                        let name = match db::get_user(conn, chat_id, user_id) {
+                            Ok(Some(user)) => user.display_name().to_string(),
+                            _ => format!("User {}", user_id),
+                        };
+                        managers.push((group, name));
+                    }
+                    managers
+                })
+                .await;
+
+            if managers.is_empty() {
+                "No tag group has managers, only admins can change them.".to_string()
+            } else {
+                let mut reply = "Tag group managers:".to_string();
+                for (group, name) in &managers {
+                    reply.push_str(&format!("\n- {}: {}", group, name));
+                }
+                reply
+            }
+        }
+        "add" | "remove" => {
+            let command = format!("groupadmin {}", action);
+            match parse_group_target(&bot, &db, state, &msg, rest, &command).await {
+                Err(reply) => reply,
+                Ok((group, user)) => {
+                    let (user_id, name, add) = (user.user_id, group.clone(), action == "add");
+                    let result = db
+                        .call(move |conn| {
+                            if add {
+                                db::add_group_admin(conn, chat_id, &name, user_id)
+                            } else {
+                                db::remove_group_admin(conn, chat_id, &name, user_id)
+                            }
+                        })
+                        .await;
+
+                    match (result, add) {
+                        (Ok(true), true) => {
+                            log::info!(
+                                "[{}] {} (ID: {}) can now manage tag group {}",
+                                chat_name,
+                                user.first_name,
+                                user.user_id,
+                                group
+                            );
+                            format!("{} can now manage {}.", user.display_name(), group)
+                        }
+                        (Ok(true), false) => {
+                            log::info!(
+                                "[{}] {} (ID: {}) can no longer manage tag group {}",
+                                chat_name,
+                                user.first_name,
+                                user.user_id,
+                                group
+                            );
+                            format!("{} can no longer manage {}.", user.display_name(), group)
+                        }
+                        (Ok(false), true) => {
+                            format!("{} already manages {}.", user.display_name(), group)
+                        }
+                        (Ok(false), false) => {
+                            format!("{} doesn't manage {}.", user.display_name(), group)
+                        }
+                        (Err(e), _) => {
+                            log::error!("[{}] Failed to update group managers: {}", chat_name, e);
+                            "Couldn't update the group managers, try again later.".to_string()
+                        }
+                    }
+                }
+            }
+        }
+        _ => "Usage: /groupadmin add|remove <group> in reply to a message or with @username, \
+             or /groupadmin list"
+            .to_string(),
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /addtag command - adds the replied or named user to a tag group (admins and group managers)
+async fn handle_addtag_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    let (group, target) = match split_group_arg(&args, "addtag") {
+        Ok(group) => group,
+        Err(reply) => {
+            bot.send_message(msg.chat.id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // Only the allowed callers get to learn whether the user is tracked
+    if !ensure_group_manager(&bot, &msg, &db, config, &group, "addtag").await? {
+        return Ok(());
+    }
+
+    let user = match group_target_user(&bot, &db, state, &msg, target, "addtag").await {
+        Ok(user) => user,
+        Err(reply) => {
+            bot.send_message(msg.chat.id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // Groups are stored on disk, users of ephemeral chats must stay in memory
+    if state.ephemeral_user(msg.chat.id, user.user_id).is_some() {
+        bot.send_message(
+            msg.chat.id,
+            "This chat is ephemeral, its users are only kept in memory and can't be added to tag groups.",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    }
+
+    let (chat_id, user_id, name) = (msg.chat.id.0, user.user_id, group.clone());
+    let reply = match db
+        .call(move |conn| db::add_group_member(conn, chat_id, &name, user_id))
+        .await
+    {
+        Ok(true) => {
+            log::info!(
+                "[{}] Added {} (ID: {}) to tag group {}",
+                chat_name,
+                user.first_name,
+                user.user_id,
+                group
+            );
+            format!("Added {} to {}.", user.display_name(), group)
+        }
+        Ok(false) => format!("{} is already in {}.", user.display_name(), group),
+        Err(e) => {
+            log::error!("[{}] Failed to add to tag group: {}", chat_name, e);
+            "Couldn't update the group, try again later.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /remtag command - removes the replied or named user from a tag group (admins and group managers)
+async fn handle_remtag_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    let (group, target) = match split_group_arg(&args, "remtag") {
+        Ok(group) => group,
+        Err(reply) => {
+            bot.send_message(msg.chat.id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // Only the allowed callers get to learn whether the user is tracked
+    if !ensure_group_manager(&bot, &msg, &db, config, &group, "remtag").await? {
+        return Ok(());
+    }
+
+    let user = match group_target_user(&bot, &db, state, &msg, target, "remtag").await {
+        Ok(user) => user,
+        Err(reply) => {
+            bot.send_message(msg.chat.id, reply)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let (chat_id, user_id, name) = (msg.chat.id.0, user.user_id, group.clone());
+    let reply = match db
+        .call(move |conn| db::remove_group_member(conn, chat_id, &name, user_id))
+        .await
+    {
+        Ok(true) => {
+            log::info!(
+                "[{}] Removed {} (ID: {}) from tag group {}",
+                chat_name,
+                user.first_name,
+                user.user_id,
+                group
+            );
+            format!("Removed {} from {}.", user.display_name(), group)
+        }
+        Ok(false) => format!("{} isn't in {}.", user.display_name(), group),
+        Err(e) => {
+            log::error!("[{}] Failed to remove from tag group: {}", chat_name, e);
+            "Couldn't update the group, try again later.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /groups command - lists the tag groups of the chat with their sizes (admin only)
+async fn handle_groups_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "groups").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let groups = db
+        .call(move |conn| db::list_groups(conn, chat_id))
+        .await
+        .unwrap_or_default();
+
+    let reply = if groups.is_empty() {
+        "This chat has no tag groups yet. Reply to a message with /addtag <group> to create one."
+            .to_string()
+    } else {
+        let mut reply = format!("Tag groups ({}):", groups.len());
+        for (name, members) in &groups {
+            let noun = if *members == 1 { "member" } else { "members" };
+            reply.push_str(&format!("\n- {}: {} {}", name, members, noun));
+        }
+        reply.push_str("\nUse /tag <group> [message] to tag one of them.");
+        reply
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /renametag command - renames a tag group, keeping its members and managers (admin only)
+async fn handle_renametag_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "renametag").await? {
+        return Ok(());
+    }
+
+    let names: Vec<&str> = args.split_whitespace().collect();
+    let (old_name, new_name) = match names[..] {
+        [old_name, new_name] => match (parse_group_name(old_name), parse_group_name(new_name)) {
+            (Some(old_name), Some(new_name)) => (old_name, new_name),
+            _ => {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Group names can only have letters, digits, _ and -, up to {} characters.",
+                        MAX_GROUP_NAME_LEN
+                    ),
+                )
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+                return Ok(());
+            }
+        },
+        _ => {
+            bot.send_message(msg.chat.id, "Usage: /renametag <old> <new>")
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if old_name == new_name {
+        bot.send_message(msg.chat.id, format!("{} already has that name.", old_name))
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let (chat_id, old, new) = (msg.chat.id.0, old_name.clone(), new_name.clone());
+    let reply = match db
+        .call(move |conn| db::rename_group(conn, chat_id, &old, &new))
+        .await
+    {
+        Ok(db::GroupRename::Renamed(moved)) => {
+            log::info!(
+                "[{}] Renamed tag group {} to {} ({} members)",
+                chat_name,
+                old_name,
+                new_name,
+                moved
+            );
+            let noun = if moved == 1 { "member" } else { "members" };
+            format!(
+                "Renamed {} to {}, {} {} moved.",
+                old_name, new_name, moved, noun
+            )
+        }
+        Ok(db::GroupRename::Missing) => {
+            format!("There is no tag group named {}, see /groups.", old_name)
+        }
+        Ok(db::GroupRename::Taken) => format!(
+            "A tag group named {} already exists, pick another name or empty it first.",
+            new_name
+        ),
+        Err(e) => {
+            log::error!("[{}] Failed to rename tag group: {}", chat_name, e);
+            "Couldn't rename the group, try again later.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /tag command - tags the members of a tag group (admin only)
+async fn handle_tag_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "tag").await? {
+        return Ok(());
+    }
+
+    let args = args.trim();
+    let (name, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let Some(group) = parse_group_name(name) else {
+        bot.send_message(
+            msg.chat.id,
+            "Usage: /tag <group> [--active <days>] [other /all flags] [message]",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+
+    // Same flags as /all, so filters like --active combine with the group
+    let (options, text) = match AllOptions::parse(rest) {
+        Ok(parsed) => parsed,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    if let Some(reason) = quota_refusal(msg.chat.id, chat_name, &db, config).await {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = lock_tag(&bot, &msg, state).await? else {
+        return Ok(());
+    };
+
+    let options = AllOptions {
+        group: Some(group.clone()),
+        ..options
+    };
+    let targets =
+        gather_tag_targets(&bot, msg.chat.id, chat_name, &db, config, state, &options).await;
+    if targets.tracked == 0 {
+        bot.send_message(
+            msg.chat.id,
+            format!("There is no tag group named {}, see /groups.", group),
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    }
+    if report_empty_targets(&bot, &msg, &targets).await? {
+        return Ok(());
+    }
+
+    log::info!(
+        "[{}] Tagging {} users of group {}",
+        chat_name,
+        targets.users.len(),
+        group
+    );
+
+    let header = all_header(text, &targets.skipped, &options, targets.format);
+    let sent = send_tag(
+        &bot,
+        msg.chat.id,
+        Some(msg.id),
+        state,
+        &header,
+        &targets.users,
+        targets.format,
+    )
+    .await?;
+    log_sent_tag(msg.chat.id, chat_name, &targets.users, &db, state).await;
+
+    if options.pin
+        && let Some(first) = sent.first()
+    {
+        pin_tag_message(&bot, &msg, &first.message).await?;
+    }
+
+    if let Some(ttl) = options.ttl {
+        tokio::spawn(self_destruct(bot.clone(), sent, ttl, targets.format.markup));
+    }
+
+    Ok(())
+}
+
+/// Handles the /mentioncheck command - reports users whose mention wouldn't parse, without tagging (admin only)
+async fn handle_mentioncheck_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "mentioncheck").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let users = load_chat_users(&db, state, msg.chat.id).await;
+    let settings = db
+        .call(move |conn| db::get_chat_settings(conn, chat_id).unwrap_or_default())
+        .await;
+    let format = settings.tag_format();
+
+    let invalid: Vec<(&db::User, String)> = users
+        .iter()
+        .filter_map(|user| {
+            let mention = mentions::best_mention(user, format);
+            mentions::validate_markup(format.markup, &mention)
+                .err()
+                .map(|e| (user, e))
+        })
+        .collect();
+
+    log::info!(
+        "[{}] Mention check: {} of {} mentions invalid as {}",
+        chat_name,
+        invalid.len(),
+        users.len(),
+        format.markup
+    );
+
+    let reply = if invalid.is_empty() {
+        format!("All {} mentions are valid {}.", users.len(), format.markup)
+    } else {
+        let mut reply = format!(
+            "{} of {} mentions wouldn't parse as {}:",
+            invalid.len(),
+            users.len(),
+            format.markup
+        );
+        for (user, error) in invalid.iter().take(MENTIONCHECK_MAX_RESULTS) {
+            reply.push_str(&format!(
+                "\n- {} (ID {}): {}",
+                user.display_name(),
+                user.user_id,
+                error
+            ));
+        }
+        if invalid.len() > MENTIONCHECK_MAX_RESULTS {
+            reply.push_str(&format!(
+                "\n...and {} more",
+                invalid.len() - MENTIONCHECK_MAX_RESULTS
+            ));
+        }
+        reply
+    };
+
+    // Plain text so the names themselves can't break the reply
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /stats command - reports what the bot tracks for this chat (admin only)
+async fn handle_stats_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "stats").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let title = db
+        .call(move |conn| db::get_chat_title(conn, chat_id).ok().flatten())
+        .await;
+    let users = load_chat_users(&db, state, msg.chat.id).await;
+
+    let title = title.unwrap_or_else(|| msg.chat.id.0.to_string());
+    let with_username = users.iter().filter(|u| u.username.is_some()).count();
+
+    let reply = format!(
+        "Stats for {}\nTracked users: {}\nWith a username: {}",
+        title,
+        users.len(),
+        with_username
+    );
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Makes a destructive command wait for its confirmation: the first time it replies with a token
+/// the admin has to send back within a minute, returning true once the right token is sent
+async fn require_confirmation(
+    bot: &Bot,
+    msg: &Message,
+    state: &State,
+    command: &'static str,
+    token: &str,
+) -> ResponseResult<bool> {
+    const TOKEN_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+    // Messages sent on behalf of a chat can't be tied to whoever confirms them
+    let Some(user) = msg.from.as_ref().filter(|_| msg.sender_chat.is_none()) else {
+        bot.send_message(
+            msg.chat.id,
+            format!("/{} has to be confirmed, send it as yourself.", command),
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(false);
+    };
+
+    let token = token.trim();
+    if !token.is_empty() && state.confirm(msg.chat.id, user.id, command, token) {
+        return Ok(true);
+    }
+
+    let new_token: String = (0..4)
+        .map(|_| TOKEN_CHARS[rand::random_range(0..TOKEN_CHARS.len())] as char)
+        .collect();
+    let reply = format!(
+        "{}Send /{} {} within a minute to confirm.",
+        if token.is_empty() {
+            ""
+        } else {
+            "Wrong or expired token. "
+        },
+        command,
+        new_token
+    );
+    state.request_confirmation(msg.chat.id, user.id, command, new_token);
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(false)
+}
+
+/// Handles the /cleanbots command - removes bots that slipped into the tracked users (admin only)
+async fn handle_cleanbots_command(
+    bot: Bot,
+    msg: Message,
+    token: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "cleanbots").await? {
+        return Ok(());
+    }
+
+    if !require_confirmation(&bot, &msg, state, "cleanbots", &token).await? {
+        return Ok(());
+    }
+
+    let purged = purge_bots(&bot, &db, msg.chat.id).await.removed.len();
+    log::info!("[{}] Purged {} bots", chat_name, purged);
+
+    bot.send_message(
+        msg.chat.id,
+        format!("Removed {} bots from the tracked users.", purged),
+    )
+    .reply_parameters(ReplyParameters::new(msg.id))
+    .await?;
+
+    Ok(())
+}
+
+/// Handles the /prune command - removes tracked users that are gone from the chat (admin only)
+async fn handle_prune_command(
+    bot: Bot,
+    msg: Message,
+    token: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "prune").await? {
+        return Ok(());
+    }
+
+    if !require_confirmation(&bot, &msg, state, "prune", &token).await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let settings = db
+        .call(move |conn| db::get_chat_settings(conn, chat_id))
+        .await
+        .unwrap_or_default();
+
+    let removed = sweep_members(&bot, &db, msg.chat.id, |member| {
+        if !is_member_kind(&member.kind) {
+            Some("departed member")
+        } else if settings.skip_deleted_accounts
+            && member.user.first_name == db::DELETED_ACCOUNT_NAME
+        {
+            Some("deleted account")
+        } else {
+            None
+        }
+    })
+    .await
+    .removed;
+
+    let departed = removed.iter().filter(|r| **r == "departed member").count();
+    let deleted = removed.len() - departed;
+    log::info!(
+        "[{}] Pruned {} departed members and {} deleted accounts",
+        chat_name,
+        departed,
+        deleted
+    );
+
+    bot.send_message(
+        msg.chat.id,
+        format!(
+            "Removed {} users that left the chat and {} deleted accounts.",
+            departed, deleted
+        ),
+    )
+    .reply_parameters(ReplyParameters::new(msg.id))
+    .await?;
+
+    Ok(())
+}
+
+/// Handles the /find command - lists tracked users matching a name, without pinging them (admin only)
+async fn handle_find_command(
+    bot: Bot,
+    msg: Message,
+    query: String,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "find").await? {
+        return Ok(());
+    }
+
+    let query = query.trim().trim_start_matches('@');
+    if query.is_empty() {
+        bot.send_message(msg.chat.id, "Usage: /find <name>")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    // Fetch one extra result to know whether the list was truncated
+    let (chat_id, search) = (msg.chat.id.0, query.to_string());
+    let users = db
+        .call(move |conn| db::search_users(conn, chat_id, &search, FIND_MAX_RESULTS + 1))
+        .await
+        .unwrap_or_default();
+
+    let reply = if users.is_empty() {
+        format!("No tracked users match \"{}\".", query)
+    } else {
+        let mut reply = format!("Tracked users matching \"{}\":", query);
+        for user in users.iter().take(FIND_MAX_RESULTS) {
+            reply.push_str(&format!("\n- {}", user.first_name));
+            if let Some(username) = &user.username {
+                reply.push_str(&format!(" ({})", username));
+            }
+            reply.push_str(&format!(" - ID {}", user.user_id));
+        }
+        if users.len() > FIND_MAX_RESULTS {
+            reply.push_str(&format!(
+                "\nOnly the first {} results are shown, refine the search to see more.",
+                FIND_MAX_RESULTS
+            ));
+        }
+        reply
+    };
+
+    // Plain text without @ keeps the list from pinging anyone
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Whether the exclude pattern of the chat matches the name or username of a user
+fn matches_exclude_pattern(regex: &regex::Regex, user: &db::User) -> bool {
+    regex.is_match(user.display_name())
+        || regex.is_match(&user.first_name)
+        || user
+            .username
+            .as_deref()
+            .is_some_and(|name| regex.is_match(name))
+}
+
+/// Handles the /reach command - estimates how many of the users /all would tag get notified (admin only)
+async fn handle_reach_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    state: &State,
+    config: &Config,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "reach").await? {
+        return Ok(());
+    }
+
+    let targets =
+        load_tag_targets(msg.chat.id, chat_name, &db, state, &AllOptions::default()).await;
+    if report_empty_targets(&bot, &msg, &targets).await? {
+        return Ok(());
+    }
+
+    let count = |reach: mentions::Reach| {
+        targets
+            .users
+            .iter()
+            .filter(|u| mentions::reach(u, targets.format) == reach)
+            .count()
+    };
+    let username = count(mentions::Reach::Username);
+    let confirmed = count(mentions::Reach::ConfirmedLink);
+    let uncertain = count(mentions::Reach::UncertainLink);
+    let unlikely = count(mentions::Reach::UnlikelyLink);
+    let silent = targets
+        .users
+        .iter()
+        .filter(|u| u.notify_pref == db::NotifyPref::Silent)
+        .count();
+
+    let mut reply = format!(
+        "Of the {} users /all would tag now:\n\
+         - {} are mentioned as @username, which always notifies\n\
+         - {} are mentioned by id and /cantag confirmed it notifies them\n\
+         - {} are mentioned by id without a test, Telegram may not notify them depending on their privacy settings\n\
+         - {} are mentioned by id although /cantag found it doesn't notify them, and have no username\n\
+         Estimated reach: {} to {} users.",
+        targets.users.len(),
+        username,
+        confirmed,
+        uncertain,
+        unlikely,
+        username + confirmed,
+        username + confirmed + uncertain
+    );
+    if silent > 0 {
+        reply.push_str(&format!(
+            "\n{} of them prefer silent tags, they're notified without a sound.",
+            silent
+        ));
+    }
+
+    log::info!(
+        "[{}] Reach estimate: {} sure, {} uncertain, {} unlikely of {}",
+        chat_name,
+        username + confirmed,
+        uncertain,
+        unlikely,
+        targets.users.len()
+    );
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /excluded command - lists the tracked users /all leaves out, grouped by reason (admin only)
+async fn handle_excluded_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    state: &State,
+    config: &Config,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "excluded").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let users = load_chat_users(&db, state, msg.chat.id).await;
+    let settings = db
+        .call(move |conn| db::get_chat_settings(conn, chat_id).unwrap_or_default())
+        .await;
+
+    let regex = settings
+        .exclude_pattern
+        .as_deref()
+        .and_then(|pattern| state.exclude_regex(pattern));
+    let cutoff = db::unix_now() - settings.min_membership_hours * 3600;
+
+    // Same checks and order as /all, each user is listed under the first reason that applies
+    let mut reasons: [(&str, Vec<&db::User>); 4] = [
+        ("Opted out with /prefs off", Vec::new()),
+        ("Deleted accounts", Vec::new()),
+        ("Joined too recently", Vec::new()),
+        ("Matching the exclude pattern", Vec::new()),
+    ];
+    for user in &users {
+        let reason = if user.notify_pref == db::NotifyPref::Off {
+            0
+        } else if settings.skip_deleted_accounts && user.looks_deleted() {
+            1
+        } else if user.always_tag {
+            continue;
+        } else if settings.min_membership_hours > 0
+            && user.joined_at.is_some_and(|joined_at| joined_at > cutoff)
+        {
+            2
+        } else if regex
+            .as_ref()
+            .is_some_and(|regex| matches_exclude_pattern(regex, user))
+        {
+            3
+        } else {
+            continue;
+        };
+        reasons[reason].1.push(user);
+    }
+
+    let excluded: usize = reasons.iter().map(|(_, users)| users.len()).sum();
+    let reply = if excluded == 0 {
+        format!(
+            "Nobody is left out, /all tags all {} tracked users.",
+            users.len()
+        )
+    } else {
+        let mut reply = format!(
+            "{} of {} tracked users are left out of /all:",
+            excluded,
+            users.len()
+        );
+        for (reason, users) in reasons.iter().filter(|(_, users)| !users.is_empty()) {
+            reply.push_str(&format!("\n\n{} ({}):", reason, users.len()));
+            for user in users.iter().take(EXCLUDED_MAX_NAMES) {
+                reply.push_str(&format!("\n- {}", user.display_name()));
+                if let Some(username) = &user.username {
+                    reply.push_str(&format!(" ({})", username));
+                }
+            }
+            if users.len() > EXCLUDED_MAX_NAMES {
+                reply.push_str(&format!(
+                    "\n...and {} more",
+                    users.len() - EXCLUDED_MAX_NAMES
+                ));
+            }
+        }
+        reply
+    };
+
+    // Plain text without @ keeps the list from pinging anyone
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// A user mentioned in a message, as (user id, name, username)
+type MentionedUser = (i64, String, Option<String>);
+
+/// Users mentioned in a message by text mention, tg://user link or @username, in order and without duplicates
+///
+/// Usernames are looked up the same way as command arguments, the ones nobody knows are returned apart.
+async fn mentioned_users(
+    bot: &Bot,
+    db: &Db,
+    chat_id: ChatId,
+    message: &Message,
+) -> (Vec<MentionedUser>, Vec<String>) {
+    let entities = message
+        .parse_entities()
+        .or_else(|| message.parse_caption_entities())
+        .unwrap_or_default();
+
+    let mut users: Vec<MentionedUser> = Vec::new();
+    let mut unresolved = Vec::new();
+    for entity in &entities {
+        let user = match entity.kind() {
+            MessageEntityKind::TextMention { user } if is_trackable(user) => Some((
+                user.id.0 as i64,
+                user.first_name.clone(),
+                user.username.clone(),
+            )),
+            MessageEntityKind::TextLink { url } => url
+                .as_str()
+                .strip_prefix("tg://user?id=")
+                .and_then(|id| id.parse().ok())
+                .map(|id| (id, entity.text().to_string(), None)),
+            MessageEntityKind::Mention => {
+                let username = entity.text().trim_start_matches('@').to_string();
+                let lookup = username.clone();
+                let stored = db
+                    .call(move |conn| db::get_user_by_username(conn, chat_id.0, &lookup))
+                    .await
+                    .ok()
+                    .flatten();
+                let recipient = Recipient::ChannelUsername(format!("@{}", username));
+                match stored {
+                    Some(user) => Some((user.user_id, user.first_name, user.username)),
+                    // Telegram only resolves some usernames, see `resolve_user`
+                    None => match bot.get_chat(recipient).await {
+                        Ok(chat) if chat.is_private() => Some((
+                            chat.id.0,
+                            chat.first_name().unwrap_or(&username).to_string(),
+                            Some(username),
+                        )),
+                        _ => {
+                            unresolved.push(username);
+                            None
+                        }
+                    },
+                }
+            }
+            _ => None,
+        };
+
+        if let Some(user) = user
+            && !users.iter().any(|(user_id, _, _)| *user_id == user.0)
+        {
+            users.push(user);
+        }
+    }
+
+    (users, unresolved)
+}
+
+/// Handles the /importpinned command - tracks the members mentioned in the pinned message (admin only)
+async fn handle_importpinned_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "importpinned").await? {
+        return Ok(());
+    }
+
+    let Some(pinned) = bot.get_chat(msg.chat.id).await?.pinned_message else {
+        bot.send_message(msg.chat.id, "This chat has no pinned message.")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    };
+
+    let (mentioned, unresolved) = mentioned_users(&bot, &db, msg.chat.id, &pinned).await;
+    if mentioned.is_empty() && unresolved.is_empty() {
+        bot.send_message(msg.chat.id, "The pinned message doesn't mention anyone.")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let ephemeral = db
+        .call(move |conn| db::get_chat_settings(conn, chat_id).is_ok_and(|s| s.ephemeral))
+        .await;
+
+    let (mut imported, mut known, mut gone) = (0, 0, 0);
+    for (user_id, first_name, username) in mentioned {
+        // The list may be older than the chat, only users still in it are tracked
+        match bot
+            .get_chat_member(msg.chat.id, UserId(user_id as u64))
+            .await
+        {
+            Ok(member) if is_member_kind(&member.kind) && is_trackable(&member.user) => {}
+            _ => {
+                gone += 1;
+                continue;
+            }
+        }
+
+        if ephemeral {
+            let user = db::User::new(user_id, &first_name, username.as_deref());
+            state.remember_ephemeral(msg.chat.id, user);
+            imported += 1;
+            continue;
+        }
+
+        // Names taken from links are only a fallback, tracked users keep their own
+        let max_users = config.max_users_per_chat;
+        let added = db
+            .call(move |conn| {
+                if db::get_user(conn, chat_id, user_id)?.is_some() {
+                    return Ok(false);
+                }
+                db::upsert_user(
+                    conn,
+                    chat_id,
+                    user_id,
+                    &first_name,
+                    username.as_deref(),
+                    max_users,
+                )
+                .map(|()| true)
+            })
+            .await;
+        match added {
+            Ok(true) => imported += 1,
+            Ok(false) => known += 1,
+            Err(e) => log::error!("[{}] Failed to import {}: {}", chat_name, user_id, e),
+        }
+    }
+
+    log::info!(
+        "[{}] Imported {} users from the pinned message",
+        chat_name,
+        imported
+    );
+    let mut reply = format!(
+        "Imported {} users from the pinned message, {} were already tracked.",
+        imported, known
+    );
+    if gone > 0 {
+        reply.push_str(&format!(
+            "\n{} mentioned users aren't in the chat anymore.",
+            gone
+        ));
+    }
+    if !unresolved.is_empty() {
+        reply.push_str(&format!(
+            "\nCouldn't find who these usernames belong to: {}",
+            unresolved.join(", ")
+        ));
+    }
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /roster command - lists the tracked users by when they last wrote, stalest first (admin only)
+async fn handle_roster_command(
+    bot: Bot,
+    msg: Message,
+    page: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "roster").await? {
+        return Ok(());
+    }
+
+    let mut users = load_chat_users(&db, state, msg.chat.id).await;
+
+    if users.is_empty() {
+        bot.send_message(msg.chat.id, "No users tracked in this chat yet.")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    // Users never seen writing sort first, they are the likeliest to be gone
+    users.sort_by_key(|u| u.last_seen);
+
+    let pages = users.len().div_ceil(ROSTER_PER_PAGE);
+    let page = page.trim().parse::<usize>().unwrap_or(1).clamp(1, pages);
+    let now = db::unix_now();
+
+    let mut reply = format!(
+        "Tracked users ({}), stalest first, page {}/{}:",
+        users.len(),
+        page,
+        pages
+    );
+    for user in users
+        .iter()
+        .skip((page - 1) * ROSTER_PER_PAGE)
+        .take(ROSTER_PER_PAGE)
+    {
+        let seen = match user.last_seen {
+            Some(last_seen) => format_ago(now - last_seen),
+            None => "never seen writing".to_string(),
+        };
+        reply.push_str(&format!("\n- {}", user.display_name()));
+        if let Some(username) = &user.username {
+            reply.push_str(&format!(" ({})", username));
+        }
+        reply.push_str(&format!(": {}", seen));
+    }
+    if page < pages {
+        reply.push_str(&format!("\nUse /roster {} for the next page.", page + 1));
+    }
+
+    // Plain text without @ keeps the list from pinging anyone
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /schedule command - stores a tag sent to everyone every day at the given time (admin only)
+async fn handle_schedule_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "schedule").await? {
+        return Ok(());
+    }
+
+    let args = args.trim();
+    let (time, message) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let Ok(time) = chrono::NaiveTime::parse_from_str(time, "%H:%M") else {
+        bot.send_message(
+            msg.chat.id,
+            "Usage: /schedule HH:MM <message>, the time is in the chat's time zone (see /timezone).",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+    let minute_of_day = i64::from(time.hour() * 60 + time.minute());
+    let message = message.trim().to_string();
+
+    // Checked now rather than when it's sent, nobody would be there to read the refusal
+    let markup = chat_tag_format(&db, msg.chat.id).await.markup;
+    if let Err(reason) = check_tag_text(&message, markup, config) {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let stored = {
+        let message = message.clone();
+        db.call(move |conn| {
+            if db::get_schedules_for_chat(conn, chat_id)?.len() >= MAX_SCHEDULES_PER_CHAT {
+                return Ok(None);
+            }
+            db::add_schedule(conn, chat_id, minute_of_day, &message).map(Some)
+        })
+        .await
+    };
+
+    let reply = match stored {
+        Ok(Some(id)) => {
+            log::info!(
+                "[{}] Scheduled recurring tag {} at {}",
+                chat_name,
+                id,
+                format_minute_of_day(minute_of_day)
+            );
+            format!(
+                "Everyone will be tagged every day at {} (schedule {}).",
+                format_minute_of_day(minute_of_day),
+                id
+            )
+        }
+        Ok(None) => format!(
+            "This chat already has {} recurring tags, delete one with /unschedule first.",
+            MAX_SCHEDULES_PER_CHAT
+        ),
+        Err(e) => {
+            log::error!("[{}] Failed to store schedule: {}", chat_name, e);
+            "Failed to store the schedule.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /schedules command - lists the recurring tags of the chat (admin only)
+async fn handle_schedules_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "schedules").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let (schedules, settings) = db
+        .call(move |conn| {
+            (
+                db::get_schedules_for_chat(conn, chat_id).unwrap_or_default(),
+                db::get_chat_settings(conn, chat_id).unwrap_or_default(),
+            )
+        })
+        .await;
+
+    let reply = if schedules.is_empty() {
+        "No recurring tags. Add one with /schedule HH:MM <message>.".to_string()
+    } else {
+        let mut reply = format!("Recurring tags ({}):", settings.timezone_name());
+        for schedule in &schedules {
+            reply.push_str(&format!(
+                "\n{}. {} {}",
+                schedule.id,
+                format_minute_of_day(schedule.minute_of_day),
+                schedule.message
+            ));
+        }
+        reply
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /unschedule command - deletes a recurring tag of the chat (admin only)
+async fn handle_unschedule_command(
+    bot: Bot,
+    msg: Message,
+    id: String,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "unschedule").await? {
+        return Ok(());
+    }
+
+    let Ok(id) = id.trim().parse::<i64>() else {
+        bot.send_message(
+            msg.chat.id,
+            "Usage: /unschedule <id>, see /schedules for the ids.",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+
+    let chat_id = msg.chat.id.0;
+    let reply = match db
+        .call(move |conn| db::delete_schedule(conn, chat_id, id))
+        .await
+    {
+        Ok(true) => {
+            log::info!("[{}] Deleted recurring tag {}", chat_name, id);
+            format!("Schedule {} deleted.", id)
+        }
+        Ok(false) => format!("No schedule {} in this chat.", id),
+        Err(e) => {
+            log::error!("[{}] Failed to delete schedule: {}", chat_name, e);
+            "Failed to delete the schedule.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /scheduletag command - stores a tag sent to everyone once, at the next given time (admin only)
+async fn handle_scheduletag_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "scheduletag").await? {
+        return Ok(());
+    }
+
+    let args = args.trim();
+    let (time, message) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let Ok(time) = chrono::NaiveTime::parse_from_str(time, "%H:%M") else {
+        bot.send_message(
+            msg.chat.id,
+            "Usage: /scheduletag HH:MM <message>, the time is in the chat's time zone (see /timezone).",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+    let message = message.trim().to_string();
+
+    // Checked now rather than when it's sent, nobody would be there to read the refusal
+    let markup = chat_tag_format(&db, msg.chat.id).await.markup;
+    if let Err(reason) = check_tag_text(&message, markup, config) {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let stored = {
+        let message = message.clone();
+        db.call(move |conn| {
+            if db::get_pending_tags_for_chat(conn, chat_id)?.len() >= MAX_PENDING_TAGS_PER_CHAT {
+                return Ok(None);
+            }
+
+            // The next time the clock of the chat shows the given time, today or tomorrow
+            let settings = db::get_chat_settings(conn, chat_id)?;
+            let now = settings.local_now();
+            let mut fire_at = now.date_naive().and_time(time);
+            if fire_at <= now.naive_local() {
+                fire_at += chrono::Duration::days(1);
+            }
+            let fire_at = settings.local_timestamp(fire_at);
+
+            db::add_pending_tag(conn, chat_id, fire_at, &message).map(|id| Some((id, fire_at)))
+        })
+        .await
+    };
+
+    let reply = match stored {
+        Ok(Some((id, fire_at))) => {
+            log::info!(
+                "[{}] Scheduled one-shot tag {} at {}",
+                chat_name,
+                id,
+                fire_at
+            );
+            let minutes_left = (fire_at - db::unix_now()).max(0) / 60;
+            format!(
+                "Everyone will be tagged at {} (in {}h {}m), cancel it with /canceltag {}.",
+                time.format("%H:%M"),
+                minutes_left / 60,
+                minutes_left % 60,
+                id
+            )
+        }
+        Ok(None) => format!(
+            "This chat already has {} pending tags, cancel one with /canceltag first.",
+            MAX_PENDING_TAGS_PER_CHAT
+        ),
+        Err(e) => {
+            log::error!("[{}] Failed to store one-shot tag: {}", chat_name, e);
+            "Failed to store the tag.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /pending command - lists the one-shot tags of the chat that weren't sent yet (admin only)
+async fn handle_pending_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "pending").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let (pending, settings) = db
+        .call(move |conn| {
+            (
+                db::get_pending_tags_for_chat(conn, chat_id).unwrap_or_default(),
+                db::get_chat_settings(conn, chat_id).unwrap_or_default(),
+            )
+        })
+        .await;
+
+    let reply = if pending.is_empty() {
+        "No pending tags. Add one with /scheduletag HH:MM <message>.".to_string()
+    } else {
+        let mut reply = format!("Pending tags ({}):", settings.timezone_name());
+        for tag in &pending {
+            let at = chrono::DateTime::from_timestamp(tag.fire_at, 0)
+                .map(|at| settings.to_local(at).format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            reply.push_str(&format!("\n{}. {} {}", tag.id, at, tag.message));
+        }
+        reply
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /canceltag command - deletes a pending one-shot tag of the chat (admin only)
+async fn handle_canceltag_command(
+    bot: Bot,
+    msg: Message,
+    id: String,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "canceltag").await? {
+        return Ok(());
+    }
+
+    let Ok(id) = id.trim().parse::<i64>() else {
+        bot.send_message(
+            msg.chat.id,
+            "Usage: /canceltag <id>, see /pending for the ids.",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+
+    let chat_id = msg.chat.id.0;
+    let reply = match db
+        .call(move |conn| db::delete_pending_tag(conn, chat_id, id))
+        .await
+    {
+        Ok(true) => {
+            log::info!("[{}] Cancelled one-shot tag {}", chat_name, id);
+            format!("Pending tag {} cancelled.", id)
+        }
+        Ok(false) => format!("No pending tag {} in this chat.", id),
+        Err(e) => {
+            log::error!("[{}] Failed to cancel one-shot tag: {}", chat_name, e);
+            "Failed to cancel the tag.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /activity command - charts how many users wrote on each of the last days (admin only)
+async fn handle_activity_command(
+    bot: Bot,
+    msg: Message,
+    days: String,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "activity").await? {
+        return Ok(());
+    }
+
+    let days = match days.trim() {
+        "" => ACTIVITY_DEFAULT_DAYS,
+        days => match days.parse::<i64>() {
+            Ok(days) if (1..=db::ACTIVITY_RETENTION_DAYS).contains(&days) => days,
+            _ => {
+                bot.send_message(
+                    msg.chat.id,
+                    format!(
+                        "Usage: /activity [days], up to {} days.",
+                        db::ACTIVITY_RETENTION_DAYS
+                    ),
+                )
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+                return Ok(());
+            }
+        },
+    };
+
+    let chat_id = msg.chat.id.0;
+    let (counts, settings) = db
+        .call(move |conn| {
+            let settings = db::get_chat_settings(conn, chat_id).unwrap_or_default();
+            let since = settings.local_day(db::unix_now()) - days + 1;
+            (
+                db::get_daily_activity(conn, chat_id, since).unwrap_or_default(),
+                settings,
+            )
+        })
+        .await;
+    let counts: HashMap<i64, i64> = counts.into_iter().collect();
+    let today = settings.local_day(db::unix_now());
+    let since = today - days + 1;
+
+    let peak = counts.values().copied().max().unwrap_or(0);
+    let reply = if peak == 0 {
+        format!(
+            "Nobody wrote in the last {} days, or it wasn't recorded yet.",
+            days
+        )
+    } else {
+        let mut reply = format!(
+            "Users that wrote each day ({}), last {} days:",
+            settings.timezone_name(),
+            days
+        );
+        for day in since..=today {
+            let count = counts.get(&day).copied().unwrap_or(0);
+            let date = chrono::DateTime::from_timestamp(day * 86400, 0)
+                .map(|at| at.format("%a %m-%d").to_string())
+                .unwrap_or_default();
+            // Any activity gets at least one block so quiet days stand out from empty ones
+            let width = (count * ACTIVITY_BAR_WIDTH + peak - 1) / peak;
+            reply.push_str(&format!(
+                "\n{} {} {}",
+                date,
+                "█".repeat(width as usize),
+                count
+            ));
+        }
+        reply
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /snapshot command - stores the current size of the roster for /growth (admin only)
+async fn handle_snapshot_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "snapshot").await? {
+        return Ok(());
+    }
+
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+    let chat_id = msg.chat.id.0;
+    let memory = ephemeral_user_ids(state, msg.chat.id);
+    let reply = match db
+        .call(move |conn| db::take_snapshot(conn, chat_id, &memory))
+        .await
+    {
+        Ok(snapshot) => {
+            log::info!(
+                "[{}] Roster snapshot taken: {} users",
+                chat_name,
+                snapshot.user_count
+            );
+            format!(
+                "Snapshot taken: {} tracked users. Use /growth later to see how it changed.",
+                snapshot.user_count
+            )
+        }
+        Err(e) => {
+            log::error!("[{}] Failed to take a roster snapshot: {}", chat_name, e);
+            "Couldn't take the snapshot, try again later.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /growth command - compares the roster with an earlier snapshot (admin only)
+async fn handle_growth_command(
+    bot: Bot,
+    msg: Message,
+    days: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "growth").await? {
+        return Ok(());
+    }
+
+    let days = match days.trim() {
+        "" => 0,
+        days => match days.parse::<i64>() {
+            Ok(days) if days >= 1 => days,
+            _ => {
+                bot.send_message(msg.chat.id, "Usage: /growth [days]")
+                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .await?;
+                return Ok(());
+            }
+        },
+    };
+
+    let chat_id = msg.chat.id.0;
+    let before = db::unix_now() - days * 86400;
+    let memory = ephemeral_user_ids(state, msg.chat.id);
+    let loaded = db
+        .call(move |conn| -> rusqlite::Result<_> {
+            Ok((
+                db::current_snapshot(conn, chat_id, &memory)?,
+                db::get_snapshot_before(conn, chat_id, before)?,
+                db::get_chat_settings(conn, chat_id).unwrap_or_default(),
+            ))
+        })
+        .await;
+
+    let reply = match loaded {
+        Err(e) => {
+            log::error!(
+                "[{}] Failed to load the roster snapshots: {}",
+                msg.chat.title().unwrap_or("Unknown"),
+                e
+            );
+            "Couldn't load the snapshots, try again later.".to_string()
+        }
+        Ok((_, None, _)) if days == 0 => "No snapshot taken yet, use /snapshot first.".to_string(),
+        Ok((_, None, _)) => format!(
+            "No snapshot is at least {} days old, use /growth for the last one.",
+            days
+        ),
+        Ok((current, Some(previous), settings)) => {
+            let taken = chrono::DateTime::from_timestamp(previous.taken_at, 0)
+                .map(|at| settings.to_local(at).format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            let mut reply = format!(
+                "Tracked users: {} now, {} on {} ({}), {:+}.",
+                current.user_count,
+                previous.user_count,
+                taken,
+                format_ago(current.taken_at - previous.taken_at),
+                current.user_count - previous.user_count
+            );
+            if current.list_hash == previous.list_hash {
+                reply.push_str("\nThe tracked users are exactly the same.");
+            } else if current.user_count == previous.user_count {
+                reply.push_str("\nSame count, but some users were replaced by others.");
+            }
+            reply
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /lastseen command - tells when the replied or named user last wrote in the chat (admin only)
+async fn handle_lastseen_command(
+    bot: Bot,
+    msg: Message,
+    username: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "lastseen").await? {
+        return Ok(());
+    }
+
+    let Some(target) = lookup_target_user(&bot, &db, state, &msg, &username).await else {
+        bot.send_message(
+            msg.chat.id,
+            "Reply to a message of the user or use /lastseen @username.",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+
+    let name = target.name.clone();
+    let reply = match target.tracked() {
+        Err(reason) => reason,
+        Ok(user) => match user.last_seen {
+            Some(last_seen) => format!(
+                "{} was last active {}.",
+                name,
+                format_ago(db::unix_now() - last_seen)
+            ),
+            // Users tracked before activity was recorded, or that only joined
+            None => format!(
+                "{} hasn't been seen writing since activity is recorded.",
+                name
+            ),
+        },
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// The user a command is about, as found by `lookup_target_user`
+struct TargetUser {
+    /// Name to refer to the user in replies
+    name: String,
+    /// Telegram id of the user, `None` when an @username couldn't be resolved
+    user_id: Option<i64>,
+    /// Stored row of the user, if tracked in the chat
+    stored: Option<db::User>,
+}
+
+impl TargetUser {
+    /// The stored row of the user, or a reply explaining why there is none
+    fn tracked(self) -> Result<db::User, String> {
+        match (self.stored, self.user_id) {
+            (Some(user), _) => Ok(user),
+            (None, Some(_)) => Err(format!("{} isn't tracked in this chat.", self.name)),
+            (None, None) => Err(format!(
+                "I don't know who {} is, I only know the usernames of users I've seen in this chat \
+                 and the ones Telegram lets me look up. Reply to one of their messages instead.",
+                self.name
+            )),
+        }
+    }
+}
+
+/// Finds the user a command is about: the replied user, or the @username or user id given as argument
+///
+/// Returns `None` when neither is given.
+async fn lookup_target_user(
+    bot: &Bot,
+    db: &Db,
+    state: &State,
+    msg: &Message,
+    arg: &str,
+) -> Option<TargetUser> {
+    let chat_id = msg.chat.id.0;
+    let arg = arg.trim();
+
+    let (user_id, name) = if arg.is_empty() {
+        let user = msg
+            .reply_to_message()
+            .and_then(|m| m.from.as_ref())
+            .filter(|u| is_trackable(u))?;
+        (Some(user.id.0 as i64), user.first_name.clone())
+    } else {
+        match resolve_user(bot, db, state, msg.chat.id, arg).await {
+            Some((user_id, name)) => (Some(user_id), name),
+            None => (None, format!("@{}", arg.trim_start_matches('@'))),
+        }
+    };
+
+    let stored = match user_id {
+        Some(user_id) => db
+            .call(move |conn| db::get_user(conn, chat_id, user_id))
+            .await
+            .ok()
+            .flatten()
+            // Users of ephemeral chats only live in memory
+            .or_else(|| state.ephemeral_user(msg.chat.id, user_id)),
+        None => None,
+    };
+    Some(TargetUser {
+        name,
+        user_id,
+        stored,
+    })
+}
+
+/// Resolves a user id or @username argument to a user id and a name to refer to them
+///
+/// Usernames stored for the chat, or kept in memory when it's ephemeral, are tried first, then
+/// Telegram is asked with get_chat, which only knows some users (the ones that talked to the bot or
+/// are otherwise reachable by it).
+async fn resolve_user(
+    bot: &Bot,
+    db: &Db,
+    state: &State,
+    chat_id: ChatId,
+    arg: &str,
+) -> Option<(i64, String)> {
+    let arg = arg.trim();
+    if let Ok(user_id) = arg.parse::<i64>() {
+        return Some((user_id, format!("User {}", user_id)));
+    }
+
+    let username = arg.trim_start_matches('@').to_string();
+    if username.is_empty() {
+        return None;
+    }
+    let name = format!("@{}", username);
+
+    let lookup = username.clone();
+    let stored = db
+        .call(move |conn| db::get_user_by_username(conn, chat_id.0, &lookup))
+        .await
+        .ok()
+        .flatten()
+        .or_else(|| {
+            state.ephemeral_users(chat_id).into_iter().find(|u| {
+                u.username
+                    .as_deref()
+                    .is_some_and(|known| known.eq_ignore_ascii_case(&username))
+            })
+        });
+    if let Some(user) = stored {
+        return Some((user.user_id, name));
+    }
+
+    match bot.get_chat(Recipient::ChannelUsername(name.clone())).await {
+        Ok(chat) if chat.is_private() => Some((chat.id.0, name)),
+        Ok(_) => None,
+        Err(e) => {
+            log::debug!("[{}] Couldn't resolve {}: {}", chat_id.0, name, e);
+            None
+        }
+    }
+}
+
+/// Handles the /whois command - shows everything stored about the replied or named user (admin only)
+async fn handle_whois_command(
+    bot: Bot,
+    msg: Message,
+    arg: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "whois").await? {
+        return Ok(());
+    }
+
+    let Some(target) = lookup_target_user(&bot, &db, state, &msg, &arg).await else {
+        bot.send_message(
+            msg.chat.id,
+            "Reply to a message of the user or use /whois @username or /whois <user id>.",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+    let user = match target.tracked() {
+        Ok(user) => user,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // The live status is best effort, the stored data is still worth showing without it
+    let status = match bot
+        .get_chat_member(msg.chat.id, UserId(user.user_id as u64))
+        .await
+    {
+        Ok(member) => match member.kind {
+            ChatMemberKind::Owner(_) => "owner",
+            ChatMemberKind::Administrator(_) => "admin",
+            ChatMemberKind::Member(_) => "member",
+            ChatMemberKind::Restricted(_) => "restricted member",
+            ChatMemberKind::Left => "left the chat",
+            ChatMemberKind::Banned(_) => "banned",
+        },
+        Err(e) => {
+            log::debug!("Couldn't check user {}: {}", user.user_id, e);
+            "unknown"
+        }
+    };
+
+    let mut reply = format!("User {}", user.user_id);
+    reply.push_str(&format!("\nName: {}", user.first_name));
+    if let Some(override_name) = &user.override_name {
+        reply.push_str(&format!(" (tagged as {})", override_name));
+    }
+    reply.push_str(&format!(
+        "\nUsername: {}",
+        user.username.as_deref().unwrap_or("none")
+    ));
+    if let Some(label) = &user.label {
+        reply.push_str(&format!("\nLabel: {}", label));
+    }
+    reply.push_str(&format!("\nStatus: {}", status));
+    reply.push_str(&format!("\nTag preference: {}", user.notify_pref));
+    reply.push_str(&format!("\nMessages counted: {}", user.message_count));
+    reply.push_str(&format!(
+        "\nId mentions: {}",
+        match user.id_mention_works {
+            Some(true) => "notify them",
+            Some(false) => "don't notify them, their username is used instead",
+            None => "not tested",
+        }
+    ));
+    if let Some(cohort) = &user.cohort {
+        reply.push_str(&format!("\nCohort: {}", cohort));
+    }
+    if user.always_tag {
+        reply.push_str("\nAlways tagged: yes, filters don't leave them out");
+    }
+    if user.blocked_bot {
+        reply.push_str("\nPrivate messages: they blocked the bot");
+    }
+    reply.push_str(&format!(
+        "\nJoined: {}",
+        user.joined_at
+            .map(|at| format!("{} UTC", export::format_timestamp(at)))
+            .unwrap_or_else(|| "not recorded".to_string())
+    ));
+    reply.push_str(&format!(
+        "\nLast active: {}",
+        user.last_seen
+            .map(|at| format_ago(db::unix_now() - at))
+            .unwrap_or_else(|| "not recorded".to_string())
+    ));
+    if let Some(note) = &user.note {
+        reply.push_str(&format!("\nNote: {}", note));
+    }
+
+    // Plain text so names and usernames don't ping anyone
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Describes how long ago something happened, e.g. "3 hours ago"
+fn format_ago(seconds: i64) -> String {
+    let (amount, unit) = match seconds.max(0) {
+        s if s < 60 => return "just now".to_string(),
+        s if s < 3600 => (s / 60, "minute"),
+        s if s < 86400 => (s / 3600, "hour"),
+        s if s < 30 * 86400 => (s / 86400, "day"),
+        s if s < 365 * 86400 => (s / (30 * 86400), "month"),
+        s => (s / (365 * 86400), "year"),
+    };
+
+    format!(
+        "{} {}{} ago",
+        amount,
+        unit,
+        if amount == 1 { "" } else { "s" }
+    )
+}
+
+/// Handles the /exportcsv command - sends the roster as a CSV document (admin only)
+async fn handle_exportcsv_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "exportcsv").await? {
+        return Ok(());
+    }
+
+    let users = load_chat_users(&db, state, msg.chat.id).await;
+
+    let csv = match export::roster_csv(&users) {
+        Ok(csv) => csv,
+        Err(e) => {
+            log::error!("[{}] Failed to build CSV export: {}", chat_name, e);
+            bot.send_message(msg.chat.id, "Failed to build the export.")
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    bot.send_document(
+        msg.chat.id,
+        InputFile::memory(csv).file_name(format!("roster_{}.csv", msg.chat.id.0)),
+    )
+    .reply_parameters(ReplyParameters::new(msg.id))
+    .await?;
+
+    log::info!("[{}] Exported {} users as CSV", chat_name, users.len());
+
+    Ok(())
+}
+
+/// Handles the /config command - shows or changes the chat settings (admin only)
+async fn handle_config_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "config").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let args = args.trim();
+    let reply = if args.is_empty() {
+        db.call(move |conn| db::get_chat_settings(conn, chat_id))
+            .await
+            .unwrap_or_default()
+            .describe()
+    } else {
+        let (key, value) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let (key, mut value) = (key.to_string(), value.trim().to_string());
+
+        // Turning ephemeral mode on deletes the stored users, so it has to be confirmed
+        if key == "ephemeral" {
+            let (flag, token) = value
+                .split_once(char::is_whitespace)
+                .unwrap_or((&value, ""));
+            let (flag, token) = (flag.to_string(), token.to_string());
+            if settings::parse_bool(&flag) == Ok(true) {
+                let already_on = db
+                    .call(move |conn| {
+                        db::get_chat_settings(conn, chat_id).is_ok_and(|s| s.ephemeral)
+                    })
+                    .await;
+                if !already_on
+                    && !require_confirmation(&bot, &msg, state, "config ephemeral true", &token)
+                        .await?
+                {
+                    return Ok(());
+                }
+            }
+            value = flag;
+        }
+
+        // Read and write back in one go so concurrent changes don't overwrite each other
+        let result = {
+            let (key, value) = (key.clone(), value.clone());
+            db.call(move |conn| {
+                let mut settings = db::get_chat_settings(conn, chat_id).unwrap_or_default();
+                if let Err(reason) = settings.set(&key, &value) {
+                    return Ok(Err(reason));
+                }
+                db::save_chat_settings(conn, chat_id, &settings).map(|()| Ok(settings))
+            })
+            .await
+        };
+
+        match result {
+            Ok(Ok(settings)) => {
+                log::info!("[{}] Setting {} changed to {}", chat_name, key, value);
+                let mut reply = format!("{} set to {}.", key, value);
+                if key == "ephemeral" {
+                    reply.push(' ');
+                    reply.push_str(
+                        &switch_ephemeral(&db, config, state, msg.chat.id, settings.ephemeral)
+                            .await,
+                    );
+                }
+                reply
+            }
+            Ok(Err(reason)) => reason,
+            Err(e) => {
+                log::error!("[{}] Failed to save settings: {}", chat_name, e);
+                "Failed to save the settings.".to_string()
+            }
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /timezone command - shows or sets the time zone of the chat (admin only)
+async fn handle_timezone_command(
+    bot: Bot,
+    msg: Message,
+    zone: String,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "timezone").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let zone = zone.trim().to_string();
+    let changed = !zone.is_empty();
+    let result = {
+        let zone = zone.clone();
+        db.call(move |conn| {
+            let mut settings = db::get_chat_settings(conn, chat_id).unwrap_or_default();
+            if !changed {
+                return Ok(Ok(settings));
+            }
+            if let Err(reason) = settings.set("timezone", &zone) {
+                return Ok(Err(reason));
+            }
+            db::save_chat_settings(conn, chat_id, &settings).map(|()| Ok(settings))
+        })
+        .await
+    };
+
+    let reply = match result {
+        Ok(Ok(settings)) if changed => {
+            log::info!("[{}] Time zone changed to {}", chat_name, zone);
+            format!(
+                "This chat now uses {}, it's {} there now.",
+                settings.timezone_name(),
+                settings.local_now().format("%H:%M")
+            )
+        }
+        Ok(Ok(settings)) => format!(
+            "This chat uses {}, it's {} there now. Change it with /timezone <zone>, such as \
+             /timezone Europe/Rome.",
+            settings.timezone_name(),
+            settings.local_now().format("%H:%M")
+        ),
+        Ok(Err(reason)) => reason,
+        Err(e) => {
+            log::error!("[{}] Failed to save settings: {}", chat_name, e);
+            "Failed to save the settings.".to_string()
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Moves the users of a chat between the database and memory when ephemeral mode is toggled
+async fn switch_ephemeral(
+    db: &Db,
+    config: &Config,
+    state: &State,
+    chat: ChatId,
+    ephemeral: bool,
+) -> String {
+    let chat_id = chat.0;
+    if ephemeral {
+        let users = db
+            .call(move |conn| db::take_chat_users(conn, chat_id))
+            .await
+            .unwrap_or_default();
+        let moved = users.len();
+        for user in users {
+            state.remember_ephemeral(chat, user);
+        }
+        log::info!("[{}] Moved {} users to memory", chat_id, moved);
+        format!(
+            "The {} users stored for this chat were deleted from the database and are kept in \
+             memory instead, until the bot restarts.",
+            moved
+        )
+    } else {
+        let users = state.take_ephemeral_users(chat);
+        let moved = users.len();
+        let (max_users, stored) = (config.max_users_per_chat, users.clone());
+        if let Err(e) = db
+            .call(move |conn| db::restore_chat_users(conn, chat_id, &stored, max_users))
+            .await
+        {
+            log::error!(
+                "[{}] Failed to store the users kept in memory: {}",
+                chat_id,
+                e
+            );
+            for user in users {
+                state.remember_ephemeral(chat, user);
+            }
+            return "Couldn't store the users kept in memory, they stay there until the bot \
+                    restarts. Set ephemeral to false again to retry."
+                .to_string();
+        }
+        log::info!("[{}] Stored {} users from memory", chat_id, moved);
+        format!(
+            "The {} users kept in memory are now stored in the database.",
+            moved
+        )
+    }
+}
+
+/// Handles the /testtag command - mentions only the caller, the same way /all would
+async fn handle_testtag_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    let user = match &msg.from {
+        Some(u) if is_trackable(u) => u,
+        _ => return Ok(()),
+    };
+
+    // Prefer the stored row so overrides are honored exactly like in /all
+    let (chat_id, user_id) = (msg.chat.id.0, user.id.0 as i64);
+    let stored = db
+        .call(move |conn| db::get_user(conn, chat_id, user_id))
+        .await
+        .ok()
+        .flatten();
+    let target = stored.unwrap_or_else(|| {
+        db::User::new(user.id.0 as i64, &user.first_name, user.username.as_deref())
+    });
+
+    log::info!(
+        "[{}] Test tag for {} (ID: {})",
+        chat_name,
+        user.first_name,
+        user.id.0
+    );
+
+    let format = chat_tag_format(&db, msg.chat.id).await;
+    let header = format
+        .markup
+        .escape("Test tag, you should have received a notification:");
+    // A test isn't a tag /undo should delete, it keeps the last real one
+    deliver_tag(
+        &bot,
+        msg.chat.id,
+        Some(msg.id),
+        state,
+        &header,
+        &[target],
+        format,
+    )
+    .await
+    .result?;
+
+    Ok(())
+}
+
+/// Prefix of the callback data of the /cantag buttons, followed by the user id and the answer
+const CANTAG_CALLBACK_PREFIX: &str = "cantag";
+
+/// Handles the /cantag command - mentions the replied user alone through an id link and asks them
+/// whether they got notified, answered with the buttons below the message (admin only)
+async fn handle_cantag_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "cantag").await? {
+        return Ok(());
+    }
+
+    let Some(target) = lookup_target_user(&bot, &db, state, &msg, "").await else {
+        bot.send_message(msg.chat.id, "Reply to a message of the user to test.")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    };
+    let mut user = match target.tracked() {
+        Ok(user) => user,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // Always test the id link, even for users already known to need their username
+    user.id_mention_works = None;
+    let format = TagFormat {
+        use_usernames: false,
+        ..chat_tag_format(&db, msg.chat.id).await
+    };
+    let text = format!(
+        "{}\n{}",
+        mentions::best_mention(&user, format),
+        format
+            .markup
+            .escape("Did you get a notification for this message? Only you can answer.")
+    );
+
+    let button = |label: &str, answer: &str| {
+        InlineKeyboardButton::callback(
+            label,
+            format!("{}:{}:{}", CANTAG_CALLBACK_PREFIX, user.user_id, answer),
+        )
+    };
+    let keyboard = InlineKeyboardMarkup::new([[
+        button("Yes, I was notified", "yes"),
+        button("No notification", "no"),
+    ]]);
+
+    log::info!(
+        "[{}] Mention test for {} (ID: {})",
+        chat_name,
+        user.first_name,
+        user.user_id
+    );
+
+    bot.send_message(msg.chat.id, text)
+        .parse_mode(format.markup.parse_mode())
+        .reply_markup(keyboard)
+        .await?;
+
+    Ok(())
+}
+
+/// Handles button presses, recording the answers to /cantag from the tested user only
+async fn callback_query_handler(
+    bot: Bot,
+    query: CallbackQuery,
+    db: Db,
+    state: Arc<State>,
+) -> ResponseResult<()> {
+    let answer = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix(CANTAG_CALLBACK_PREFIX))
+        .and_then(|data| data.strip_prefix(':'))
+        .and_then(|data| data.split_once(':'))
+        .and_then(|(user_id, answer)| Some((user_id.parse::<i64>().ok()?, answer == "yes")));
+    let (Some((user_id, works)), Some(message)) = (answer, &query.message) else {
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
+    };
+
+    if query.from.id.0 as i64 != user_id {
+        bot.answer_callback_query(query.id)
+            .text("Only the tested user can answer.")
+            .await?;
+        return Ok(());
+    }
+
+    let chat_id = message.chat().id;
+    let chat_name = message.chat().title().unwrap_or("Unknown").to_string();
+    if !state.update_ephemeral(chat_id, user_id, |u| u.id_mention_works = Some(works)) {
+        let _ = db
+            .call(move |conn| db::set_id_mention_works(conn, chat_id.0, user_id, works))
+            .await;
+    }
+    log::info!(
+        "[{}] {} (ID: {}) reported id mentions {}",
+        chat_name,
+        query.from.first_name,
+        user_id,
+        if works {
+            "notify them"
+        } else {
+            "don't notify them"
+        }
+    );
+
+    let result = if works {
+        format!(
+            "{} got notified, mentions work for them.",
+            query.from.first_name
+        )
+    } else {
+        format!(
+            "{} didn't get notified, their username will be used in tags when they have one.",
+            query.from.first_name
+        )
+    };
+    bot.answer_callback_query(query.id).text("Thanks!").await?;
+    // Replacing the text also drops the buttons, so the test can't be answered twice
+    if let Err(e) = bot.edit_message_text(chat_id, message.id(), result).await {
+        log::debug!("[{}] Couldn't update the mention test: {}", chat_name, e);
+    }
+
+    Ok(())
+}
+
+/// Handles the /rollcall command - tags everyone, who confirm they're here by reacting (admin only)
+async fn handle_rollcall_command(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "rollcall").await? {
+        return Ok(());
+    }
+
+    if let Some(reason) = quota_refusal(msg.chat.id, chat_name, &db, config).await {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = lock_tag(&bot, &msg, state).await? else {
+        return Ok(());
+    };
+
+    let targets = gather_tag_targets(
+        &bot,
+        msg.chat.id,
+        chat_name,
+        &db,
+        config,
+        state,
+        &AllOptions::default(),
+    )
+    .await;
+    if report_empty_targets(&bot, &msg, &targets).await? {
+        return Ok(());
+    }
+
+    log::info!(
+        "[{}] Starting roll call of {} users",
+        chat_name,
+        targets.users.len()
+    );
+
+    let mut header = tag_header(&text, &targets.skipped, targets.format);
+    if !header.is_empty() {
+        header.push('\n');
+    }
+    header.push_str(
+        &targets
+            .format
+            .markup
+            .escape("React to this message to confirm you're here!"),
+    );
+    let sent = send_tag(
+        &bot,
+        msg.chat.id,
+        Some(msg.id),
+        state,
+        &header,
+        &targets.users,
+        targets.format,
+    )
+    .await?;
+    log_sent_tag(msg.chat.id, chat_name, &targets.users, &db, state).await;
+
+    let chat_id = msg.chat.id.0;
+    let message_ids: Vec<i32> = sent.iter().map(|c| c.message.id.0).collect();
+    let user_ids: Vec<i64> = targets.users.iter().map(|u| u.user_id).collect();
+    if let Err(e) = db
+        .call(move |conn| db::start_rollcall(conn, chat_id, &message_ids, &user_ids))
+        .await
+    {
+        log::error!("[{}] Failed to store roll call: {}", chat_name, e);
+    }
+
+    Ok(())
+}
+
+/// Handles the /rollcallstatus command - lists the users that didn't react to the last roll call (admin only)
+async fn handle_rollcallstatus_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "rollcallstatus").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let rollcall = db
+        .call(move |conn| db::get_rollcall(conn, chat_id))
+        .await
+        .ok()
+        .flatten();
+
+    let reply = match rollcall {
+        None => "There was no roll call in this chat yet, start one with /rollcall.".to_string(),
+        Some(rollcall) => {
+            let mut reply = format!(
+                "Roll call sent {}: {} of {} users reacted.",
+                format_ago(db::unix_now() - rollcall.started_at),
+                rollcall.present.len(),
+                rollcall.present.len() + rollcall.missing.len()
+            );
+            if !rollcall.missing.is_empty() {
+                reply.push_str("\n\nNot reacted yet:");
+                for user in rollcall.missing.iter().take(ROLLCALL_MAX_NAMES) {
+                    reply.push_str(&format!("\n- {}", user.display_name()));
+                    if let Some(username) = &user.username {
+                        reply.push_str(&format!(" ({})", username));
+                    }
+                }
+                if rollcall.missing.len() > ROLLCALL_MAX_NAMES {
+                    reply.push_str(&format!(
+                        "\n...and {} more",
+                        rollcall.missing.len() - ROLLCALL_MAX_NAMES
+                    ));
+                }
+            }
+            reply
+        }
+    };
+
+    // Plain text without @ keeps the list from pinging anyone
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /tagreactors command - tags the users that reacted to the replied message (admin only)
+async fn handle_tagreactors_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "tagreactors").await? {
+        return Ok(());
+    }
+
+    let Some(reacted_to) = msg.reply_to_message().map(|m| m.id) else {
+        bot.send_message(
+            msg.chat.id,
+            "Reply to a message with /tagreactors [flags] [message] to tag the users that reacted to it.",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+
+    let (options, text) = match AllOptions::parse(&args) {
+        Ok((options, _)) if options.next.is_some() => {
+            bot.send_message(msg.chat.id, "--next can't be used with /tagreactors.")
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+        Ok(parsed) => parsed,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let chat_id = msg.chat.id.0;
+    let reactors: HashSet<i64> = db
+        .call(move |conn| db::get_message_reactors(conn, chat_id, reacted_to.0))
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    if reactors.is_empty() {
+        // Reactions from before the bot recorded them, or from before it was an admin, are unknown
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "No reactions to that message were recorded. The bot only learns about reactions \
+                 while it's an admin, and keeps them for {} days.",
+                db::REACTION_RETENTION_DAYS
+            ),
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    }
+
+    if let Some(reason) = quota_refusal(msg.chat.id, chat_name, &db, config).await {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = lock_tag(&bot, &msg, state).await? else {
+        return Ok(());
+    };
+
+    let mut targets =
+        gather_tag_targets(&bot, msg.chat.id, chat_name, &db, config, state, &options).await;
+    targets.users.retain(|u| reactors.contains(&u.user_id));
+    let left_out = reactors.len().saturating_sub(targets.users.len());
+    if left_out > 0 {
+        targets.skipped.push(format!(
+            "{} that reacted not tracked or filtered out",
+            left_out
+        ));
+    }
+    if report_empty_targets(&bot, &msg, &targets).await? {
+        return Ok(());
+    }
+
+    log::info!(
+        "[{}] Tagging {} users that reacted to message {}",
+        chat_name,
+        targets.users.len(),
+        reacted_to.0
+    );
+
+    let header = all_header(text, &targets.skipped, &options, targets.format);
+    let sent = send_tag(
+        &bot,
+        msg.chat.id,
+        Some(msg.id),
+        state,
+        &header,
+        &targets.users,
+        targets.format,
+    )
+    .await?;
+    log_sent_tag(msg.chat.id, chat_name, &targets.users, &db, state).await;
+
+    if options.pin
+        && let Some(first) = sent.first()
+    {
+        pin_tag_message(&bot, &msg, &first.message).await?;
+    }
+
+    if let Some(ttl) = options.ttl {
+        tokio::spawn(self_destruct(bot.clone(), sent, ttl, targets.format.markup));
+    }
+
+    Ok(())
+}
+
+/// Records reactions to messages for /tagreactors, and to roll calls, where removing every
+/// reaction takes the confirmation back
+async fn reaction_handler(reaction: MessageReactionUpdated, db: Db) -> ResponseResult<()> {
+    let Some(user) = reaction.user().filter(|u| is_trackable(u)) else {
+        return Ok(());
+    };
+
+    let (chat_id, message_id, user_id) =
+        (reaction.chat.id.0, reaction.message_id.0, user.id.0 as i64);
+    let reacted = !reaction.new_reaction.is_empty();
+    let counted = db
+        .call(move |conn| {
+            let _ = db::set_message_reaction(conn, chat_id, message_id, user_id, reacted);
+            db::set_rollcall_reaction(conn, chat_id, message_id, user_id, reacted)
+        })
+        .await
+        .unwrap_or(false);
+    if counted {
+        log::debug!(
+            "[{}] Roll call reaction of {} set to {}",
+            reaction.chat.title().unwrap_or("Unknown"),
+            user_id,
+            reacted
+        );
+    }
+
+    Ok(())
+}
+
+/// Handles the /pollall command - tags all users and posts a poll for them to answer (admin only)
+async fn handle_pollall_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "pollall").await? {
+        return Ok(());
+    }
+
+    let mut parts = args.split('|').map(str::trim);
+    let question = parts.next().unwrap_or_default().to_string();
+    let mut options: Vec<String> = parts.filter(|o| !o.is_empty()).map(String::from).collect();
+    if options.is_empty() {
+        options = vec!["Yes".to_string(), "No".to_string()];
+    }
+
+    if question.is_empty() || options.len() < 2 || options.len() > 10 {
+        bot.send_message(
+            msg.chat.id,
+            "Usage: /pollall <question> | <option> | <option> ... (2 to 10 options, Yes/No if omitted)",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    }
+
+    if let Some(reason) = quota_refusal(msg.chat.id, chat_name, &db, config).await {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = lock_tag(&bot, &msg, state).await? else {
+        return Ok(());
+    };
+
+    let targets = gather_tag_targets(
+        &bot,
+        msg.chat.id,
+        chat_name,
+        &db,
+        config,
+        state,
+        &AllOptions::default(),
+    )
+    .await;
+    if report_empty_targets(&bot, &msg, &targets).await? {
+        return Ok(());
+    }
+
+    log::info!(
+        "[{}] Tagging {} users with poll: {}",
+        chat_name,
+        targets.users.len(),
+        question
+    );
+
+    let header = tag_header(&question, &targets.skipped, targets.format);
+    let sent = send_tag(
+        &bot,
+        msg.chat.id,
+        Some(msg.id),
+        state,
+        &header,
+        &targets.users,
+        targets.format,
+    )
+    .await?;
+    log_sent_tag(msg.chat.id, chat_name, &targets.users, &db, state).await;
+
+    let reply_to = sent.first().map(|c| c.message.id).unwrap_or(msg.id);
+    let _permit = state.send_permit().await;
+    bot.send_poll(
+        msg.chat.id,
+        question,
+        options.into_iter().map(InputPollOption::new),
+    )
+    .is_anonymous(false)
+    .reply_parameters(ReplyParameters::new(reply_to))
+    .await?;
+
+    Ok(())
+}
+
+/// Handles the /version command - tells which build of the bot is running
+async fn handle_version_command(bot: Bot, msg: Message) -> ResponseResult<()> {
+    let built_at = env!("BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map(|at| at.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let reply = format!(
+        "telegram_tagger {}\nCommit: {}\nBuilt: {}",
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_COMMIT"),
+        built_at
+    );
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /reload command - re-reads the .env file and applies the settings that don't need a restart (owner only)
+async fn handle_reload_command(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    shared_config: &SharedConfig,
+) -> ResponseResult<()> {
+    if !ensure_owner(&bot, &msg, config, "reload").await? {
+        return Ok(());
+    }
+
+    // Variables set outside of the file keep their value, the file just takes precedence
+    if let Err(e) = dotenvy::dotenv_override() {
+        log::warn!(
+            "Couldn't read the .env file, reloading from the environment only: {}",
+            e
+        );
+    }
+    let (reloaded, needs_restart) = shared_config.get().reloaded(Config::from_env());
+    shared_config.set(reloaded);
+    log::info!("Configuration reloaded by {}", describe_sender(&msg));
+
+    let mut reply = "Configuration reloaded.".to_string();
+    if !needs_restart.is_empty() {
+        reply.push_str(&format!(
+            "\nChanges to {} only apply after a restart.",
+            needs_restart.join(", ")
+        ));
+    }
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /ratelimit command - reports recent Telegram rate limits and how busy sending is (owner only)
+async fn handle_ratelimit_command(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    if !ensure_owner(&bot, &msg, config, "ratelimit").await? {
+        return Ok(());
+    }
+
+    let (available, total) = state.send_permits_available();
+    let hits = state.rate_limit_hits();
+
+    let mut reply = format!(
+        "Send slots in use: {}/{}\nNo delay between messages, sends are only capped by MAX_CONCURRENT_SENDS",
+        total - available,
+        total
+    );
+    if hits.is_empty() {
+        reply.push_str("\nNo rate limits hit since startup.");
+    } else {
+        reply.push_str(&format!("\nLast {} rate limits:", hits.len()));
+        for hit in hits.iter().rev() {
+            reply.push_str(&format!(
+                "\n- {}s ago in chat {}, asked to wait {}s",
+                hit.at.elapsed().as_secs(),
+                hit.chat_id.0,
+                hit.retry_after.as_secs()
+            ));
+        }
+    }
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /integritycheck command - runs SQLite's integrity and foreign key checks (owner only)
+async fn handle_integritycheck_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    if !ensure_owner(&bot, &msg, config, "integritycheck").await? {
+        return Ok(());
+    }
+
+    let result = db
+        .call(|conn| -> rusqlite::Result<_> {
+            Ok((db::integrity_check(conn)?, db::foreign_key_check(conn)?))
+        })
+        .await;
+
+    let reply = match result {
+        Ok((integrity, foreign_keys)) if integrity.is_empty() && foreign_keys.is_empty() => {
+            log::info!("Database integrity check passed");
+            "Integrity check: ok\nForeign key check: ok".to_string()
+        }
+        Ok((integrity, foreign_keys)) => {
+            log::warn!(
+                "Database integrity check found {} problems and {} broken references",
+                integrity.len(),
+                foreign_keys.len()
+            );
+            let mut reply = String::new();
+            for (check, problems) in [
+                ("Integrity check", &integrity),
+                ("Foreign key check", &foreign_keys),
+            ] {
+                if !reply.is_empty() {
+                    reply.push('\n');
+                }
+                if problems.is_empty() {
+                    reply.push_str(&format!("{}: ok", check));
+                    continue;
+                }
+                reply.push_str(&format!("{}: {} problems", check, problems.len()));
+                for problem in problems.iter().take(INTEGRITY_MAX_PROBLEMS) {
+                    reply.push_str(&format!("\n- {}", problem));
+                }
+                if problems.len() > INTEGRITY_MAX_PROBLEMS {
+                    reply.push_str(&format!(
+                        "\n...and {} more",
+                        problems.len() - INTEGRITY_MAX_PROBLEMS
+                    ));
+                }
+            }
+            reply.push_str("\n\nRestore a backup (see /backup) before relying on the database.");
+            reply
+        }
+        Err(e) => {
+            log::error!("Failed to check the database: {}", e);
+            format!("Couldn't run the check: {}", e)
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /chats command - lists every chat in the database (owner only)
+async fn handle_chats_command(
+    bot: Bot,
+    msg: Message,
+    page: String,
+    db: Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    if !ensure_owner(&bot, &msg, config, "chats").await? {
+        return Ok(());
+    }
+
+    let chats = db.call(db::list_chats).await.unwrap_or_default();
+
+    if chats.is_empty() {
+        bot.send_message(msg.chat.id, "No chats tracked yet.")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let pages = chats.len().div_ceil(CHATS_PER_PAGE);
+    let page = page.trim().parse::<usize>().unwrap_or(1).clamp(1, pages);
+
+    let mut reply = format!("Tracked chats ({}), page {}/{}:", chats.len(), page, pages);
+    for chat in chats
+        .iter()
+        .skip((page - 1) * CHATS_PER_PAGE)
+        .take(CHATS_PER_PAGE)
+    {
+        reply.push_str(&format!(
+            "\n{} {}: {} users",
+            chat.chat_id,
+            chat.title.as_deref().unwrap_or("(unknown title)"),
+            chat.user_count
+        ));
+    }
+    if page < pages {
+        reply.push_str(&format!("\nUse /chats {} for the next page.", page + 1));
+    }
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /prefs command - lets users choose whether they are tagged with sound, silently or not at all
+async fn handle_prefs_command(
+    bot: Bot,
+    msg: Message,
+    pref: String,
+    db: Db,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    let user = match &msg.from {
+        Some(u) if is_trackable(u) && msg.sender_chat.is_none() => u,
+        _ => return Ok(()),
+    };
+    let (chat_id, user_id) = (msg.chat.id.0, user.id.0 as i64);
+    // Users of ephemeral chats only exist in memory, the database has no row to change
+    let ephemeral = is_ephemeral(&db, msg.chat.id).await;
+
+    let pref = pref.trim();
+    let reply = if pref.is_empty() {
+        let current = if ephemeral {
+            state.ephemeral_user(msg.chat.id, user_id)
+        } else {
+            db.call(move |conn| db::get_user(conn, chat_id, user_id))
+                .await
+                .ok()
+                .flatten()
+        }
+        .map(|u| u.notify_pref)
+        .unwrap_or_default();
+        format!(
+            "Your tag preference is {}. Use /prefs loud, /prefs silent or /prefs off to change it.",
+            current
+        )
+    } else {
+        match pref.parse::<db::NotifyPref>() {
+            Ok(notify_pref) => {
+                let tracked = if ephemeral {
+                    state.update_ephemeral(msg.chat.id, user_id, |u| u.notify_pref = notify_pref)
+                } else {
+                    db.call(move |conn| db::set_notify_pref(conn, chat_id, user_id, notify_pref))
+                        .await
+                        .unwrap_or(false)
+                };
+                if tracked {
+                    log::info!(
+                        "[{}] {} (ID: {}) set tag preference to {}",
+                        chat_name,
+                        user.first_name,
+                        user.id.0,
+                        notify_pref
+                    );
+                    match notify_pref {
+                        db::NotifyPref::Loud => "You will be tagged with a notification.",
+                        db::NotifyPref::Silent => "You will be tagged without notification sound.",
+                        db::NotifyPref::Off => "You won't be tagged anymore.",
+                    }
+                    .to_string()
+                } else {
+                    "You aren't tracked in this chat yet, send a message first and try again."
+                        .to_string()
+                }
+            }
+            Err(()) => "Usage: /prefs loud|silent|off".to_string(),
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /reconcile command - reports discrepancies between tracked users and actual members (admin only)
+///
+/// The Bot API can't list every member, so only admins and recently active users are checked.
+async fn handle_reconcile_command(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "reconcile").await? {
+        return Ok(());
+    }
+
+    let users = load_chat_users(&db, state, msg.chat.id).await;
+
+    // Admins the bot doesn't track yet
+    let admins = bot.get_chat_administrators(msg.chat.id).await?;
+    let untracked: Vec<String> = admins
+        .iter()
+        .filter(|admin| is_trackable(&admin.user))
+        .filter(|admin| !users.iter().any(|u| u.user_id == admin.user.id.0 as i64))
+        .map(|admin| admin.user.first_name.clone())
+        .collect();
+
+    // Recently active tracked users that are no longer in the chat
+    let cutoff = db::unix_now() - RECONCILE_ACTIVE_DAYS * 24 * 3600;
+    let mut checked = 0;
+    let mut gone = Vec::new();
+    for user in users
+        .iter()
+        .filter(|u| u.last_seen.is_some_and(|last_seen| last_seen >= cutoff))
+    {
+        match bot
+            .get_chat_member(msg.chat.id, UserId(user.user_id as u64))
+            .await
+        {
+            Ok(member) if !is_member_kind(&member.kind) => gone.push(user.first_name.clone()),
+            Ok(_) => {}
+            Err(e) => log::debug!(
+                "[{}] Couldn't check user {}: {}",
+                chat_name,
+                user.user_id,
+                e
+            ),
+        }
+        checked += 1;
+        tokio::time::sleep(MEMBER_CHECK_DELAY).await;
+    }
+
+    log::info!(
+        "[{}] Reconcile: {} untracked admins, {} tracked users gone out of {} checked",
+        chat_name,
+        untracked.len(),
+        gone.len(),
+        checked
+    );
+
+    let mut reply = format!(
+        "Checked {} admins and {} users active in the last {} days.",
+        admins.len(),
+        checked,
+        RECONCILE_ACTIVE_DAYS
+    );
+    for (label, names) in [
+        ("Admins not tracked", &untracked),
+        ("Tracked but no longer in the chat", &gone),
+    ] {
+        if names.is_empty() {
+            continue;
+        }
+        reply.push_str(&format!("\n\n{} ({}):", label, names.len()));
+        for name in names.iter().take(RECONCILE_MAX_NAMES) {
+            reply.push_str(&format!("\n- {}", name));
+        }
+        if names.len() > RECONCILE_MAX_NAMES {
+            reply.push_str(&format!(
+                "\n...and {} more",
+                names.len() - RECONCILE_MAX_NAMES
+            ));
+        }
+    }
+    if untracked.is_empty() && gone.is_empty() {
+        reply.push_str("\nNo discrepancies found.");
+    }
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Handles the /backup command - copies the live database to a timestamped file (owner only)
+async fn handle_backup_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    if !ensure_owner(&bot, &msg, config, "backup").await? {
+        return Ok(());
+    }
+
+    if !state.start_backup() {
+        bot.send_message(msg.chat.id, "A backup is already running.")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let path = PathBuf::from(BACKUP_DIR).join(format!(
+        "tagger-{}.db",
+        chrono::Utc::now().format("%Y%m%d-%H%M%S")
+    ));
+
+    // The backup is synchronous, keep it off the async workers
+    let db_file_mode = config.db_file_mode;
+    let result = {
+        let (path, db) = (path.clone(), db.clone());
+        tokio::task::spawn_blocking(move || -> Result<u64, String> {
+            std::fs::create_dir_all(BACKUP_DIR).map_err(|e| e.to_string())?;
+            db.blocking_call(|conn| db::backup_to(conn, &path))
+                .map_err(|e| e.to_string())?;
+            db::secure_file(&path, db_file_mode).map_err(|e| e.to_string())?;
+            std::fs::metadata(&path)
+                .map(|m| m.len())
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()))
+    };
+    state.finish_backup();
+
+    let size = match result {
+        Ok(size) => size,
+        Err(e) => {
+            log::error!("Backup to {} failed: {}", path.display(), e);
+            bot.send_message(msg.chat.id, format!("Backup failed: {}", e))
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    log::info!("Backed up database to {} ({} bytes)", path.display(), size);
+
+    bot.send_message(
+        msg.chat.id,
+        format!("Backed up database to {} ({} bytes).", path.display(), size),
+    )
+    .reply_parameters(ReplyParameters::new(msg.id))
+    .await?;
+
+    if args.trim() == "send"
+        && let Some(owner_id) = config.owner_id
+    {
+        // Always sent in private, the roster shouldn't end up in a group
+        if let Err(e) = bot
+            .send_document(ChatId::from(owner_id), InputFile::file(&path))
+            .await
+        {
+            log::warn!("Couldn't send backup to the owner: {}", e);
+            handle_undeliverable(&db, owner_id, &e).await;
+            bot.send_message(
+                msg.chat.id,
+                "Couldn't send you the backup, make sure you started a private chat with the bot.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Flags accepted at the start of the /all argument
+#[derive(Debug, Default)]
+struct AllOptions {
+    /// Only tag users with a username, mentioning them as @username
+    usernames_only: bool,
+    /// Delete the tag after this many seconds, showing a countdown meanwhile
+    ttl: Option<u64>,
+    /// Pin the first tag message once sent
+    pin: bool,
+    /// Split the tag in alphabetical sections
+    sections: bool,
+    /// Only tag the members of this tag group
+    group: Option<String>,
+    /// Only tag the users of this cohort
+    cohort: Option<String>,
+    /// Only tag users that wrote within this many days
+    active_days: Option<i64>,
+    /// Only tag this many users, the ones tagged longest ago (or never) first, to take turns
+    next: Option<usize>,
+    /// Only consider the users that joined last, this many of them, as /newcomers does
+    newest: Option<usize>,
+}
+
+impl AllOptions {
+    /// Splits the leading `--flags` off the /all argument, returning the options and the remaining text
+    ///
+    /// Fails with a message for the user when a flag is given an invalid value.
+    fn parse(text: &str) -> Result<(Self, &str), String> {
+        let mut options = Self::default();
+        let mut rest = text.trim_start();
+
+        while let Some(flag) = rest.split_whitespace().next() {
+            match flag {
+                "--usernames-only" => options.usernames_only = true,
+                "--pin" => options.pin = true,
+                "--sections" => options.sections = true,
+                "--ttl" => {
+                    rest = rest[flag.len()..].trim_start();
+                    let value = rest.split_whitespace().next().unwrap_or_default();
+                    match value.parse::<u64>() {
+                        Ok(ttl) if (1..=MAX_TTL_SECS).contains(&ttl) => options.ttl = Some(ttl),
+                        _ => {
+                            return Err(format!(
+                                "--ttl expects a number of seconds between 1 and {}",
+                                MAX_TTL_SECS
+                            ));
+                        }
+                    }
+                    rest = rest[value.len()..].trim_start();
+                    continue;
+                }
+                "--next" => {
+                    rest = rest[flag.len()..].trim_start();
+                    let value = rest.split_whitespace().next().unwrap_or_default();
+                    match value.parse::<usize>() {
+                        Ok(count) if count > 0 => options.next = Some(count),
+                        _ => return Err("--next expects a number of users".to_string()),
+                    }
+                    rest = rest[value.len()..].trim_start();
+                    continue;
+                }
+                "--active" => {
+                    rest = rest[flag.len()..].trim_start();
+                    let value = rest.split_whitespace().next().unwrap_or_default();
+                    match value.parse::<i64>() {
+                        Ok(days) if (1..=INACTIVE_MAX_DAYS).contains(&days) => {
+                            options.active_days = Some(days)
+                        }
+                        _ => {
+                            return Err(format!(
+                                "--active expects a number of days between 1 and {}",
+                                INACTIVE_MAX_DAYS
+                            ));
+                        }
+                    }
+                    rest = rest[value.len()..].trim_start();
+                    continue;
+                }
+                _ => break,
+            }
+            rest = rest[flag.len()..].trim_start();
+        }
+
+        if options.pin && options.ttl.is_some() {
+            return Err(
+                "--pin and --ttl can't be used together, the pinned tag would be deleted."
+                    .to_string(),
+            );
+        }
+
+        Ok((options, rest))
+    }
+}
+
+async fn handle_all_command(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    // Only works in groups/supergroups
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        log::debug!("Command /all used outside of group, ignoring");
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    let (options, text) = match AllOptions::parse(&text) {
+        Ok(parsed) => parsed,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    log::info!(
+        "[{}] /all command invoked by {}",
+        chat_name,
+        describe_sender(&msg)
+    );
+
+    // Check if user is admin
+    if !ensure_admin(&bot, &msg, config, "all").await? {
+        return Ok(());
+    }
+
+    let chat_id = msg.chat.id.0;
+    let settings = db
+        .call(move |conn| db::get_chat_settings(conn, chat_id).unwrap_or_default())
+        .await;
+    if let Some(reason) =
+        tag_refusal(msg.chat.id, chat_name, text, &settings, &db, config, state).await
+    {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = lock_tag(&bot, &msg, state).await? else {
+        return Ok(());
+    };
+
+    let targets =
+        gather_tag_targets(&bot, msg.chat.id, chat_name, &db, config, state, &options).await;
+    if report_empty_targets(&bot, &msg, &targets).await? {
+        return Ok(());
+    }
+    let TagTargets {
+        mut users,
+        skipped,
+        format,
+        ..
+    } = targets;
+
+    // The reply to the command already notifies its author
+    if settings.reply_to_command
+        && settings.skip_author
+        && let Some(author) = msg.from.as_ref().filter(|_| msg.sender_chat.is_none())
+    {
+        users.retain(|u| u.user_id != author.id.0 as i64);
+    }
+
+    log::info!(
+        "[{}] Tagging {} users{}",
+        chat_name,
+        users.len(),
+        if text.trim().is_empty() {
+            String::new()
+        } else {
+            format!(" with message: {}", text.trim())
+        }
+    );
+
+    let header = all_header(text, &skipped, &options, format);
+
+    // Every message of the tag, the progress one included, follows the setting
+    let sent = match send_tag(
+        &bot,
+        msg.chat.id,
+        settings.reply_to_command.then_some(msg.id),
+        state,
+        &header,
+        &users,
+        format,
+    )
+    .await
+    {
+        Ok(sent) => sent,
+        Err(e) if is_missing_send_rights(&e) => {
+            report_missing_send_rights(&bot, &msg, chat_name, &db).await;
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    log::info!("[{}] Successfully sent tag message", chat_name);
+    finish_tag(msg.chat.id, chat_name, &users, &db, config, state).await;
+
+    if options.pin
+        && let Some(first) = sent.first()
+    {
+        pin_tag_message(&bot, &msg, &first.message).await?;
+    }
+
+    if let Some(ttl) = options.ttl {
+        tokio::spawn(self_destruct(bot.clone(), sent, ttl, format.markup));
+    }
+
+    Ok(())
+}
+
+/// Why everyone in a chat can't be tagged right now: a message /all refuses, the daily quota
+/// of tags or the cooldown of the last tag, `None` when the tag can go ahead
+async fn tag_refusal(
+    chat_id: ChatId,
+    chat_name: &str,
+    text: &str,
+    settings: &settings::ChatSettings,
+    db: &Db,
+    config: &Config,
+    state: &State,
+) -> Option<String> {
+    if let Err(reason) = check_tag_text(text, settings.parse_mode, config) {
+        log::info!("[{}] Rejected tag message: {}", chat_name, reason);
+        return Some(reason);
+    }
+
+    if let Some(reason) = quota_refusal(chat_id, chat_name, db, config).await {
+        return Some(reason);
+    }
+
+    state.cooldown_remaining(chat_id, "all").map(|remaining| {
+        format!(
+            "Everyone was tagged recently, try again in {}.",
+            format_remaining(remaining)
+        )
+    })
+}
+
+/// Counts a tag of everyone towards the daily quota, marks its users as tagged and starts the cooldown
+async fn finish_tag(
+    chat_id: ChatId,
+    chat_name: &str,
+    users: &[db::User],
+    db: &Db,
+    config: &Config,
+    state: &State,
+) {
+    log_sent_tag(chat_id, chat_name, users, db, state).await;
+
+    let cooldown = config.tag_cooldown(users.len());
+    if !cooldown.is_zero() {
+        log::info!(
+            "[{}] Tags of everyone on cooldown for {} seconds",
+            chat_name,
+            cooldown.as_secs()
+        );
+        state.start_cooldown(chat_id, "all", cooldown);
+    }
+}
+
+/// The reply refusing a tag once the chat sent its daily quota of tags, `None` while it has some left
+async fn quota_refusal(
+    chat_id: ChatId,
+    chat_name: &str,
+    db: &Db,
+    config: &Config,
+) -> Option<String> {
+    let max_tags = config.max_tags_per_day?;
+    let id = chat_id.0;
+    let (sent_today, settings) = db
+        .call(move |conn| {
+            let sent_today = db::tags_today(conn, id).unwrap_or(0);
+            (
+                sent_today,
+                db::get_chat_settings(conn, id).unwrap_or_default(),
+            )
+        })
+        .await;
+    if sent_today < max_tags as i64 {
+        return None;
+    }
+
+    log::info!(
+        "[{}] Daily quota of {} tags reached, refusing the tag",
+        chat_name,
+        max_tags
+    );
+    Some(format!(
+        "This chat already sent {} tags today, the daily limit. Try again after midnight ({}).",
+        sent_today,
+        settings.timezone_name()
+    ))
+}
+
+/// Counts a sent tag towards the daily quota and marks its users as tagged, in memory for ephemeral chats
+async fn log_sent_tag(
+    chat_id: ChatId,
+    chat_name: &str,
+    users: &[db::User],
+    db: &Db,
+    state: &State,
+) {
+    let now = db::unix_now();
+    for user in users {
+        state.update_ephemeral(chat_id, user.user_id, |u| u.last_tagged = Some(now));
+    }
+
+    let (id, user_ids): (i64, Vec<i64>) = (chat_id.0, users.iter().map(|u| u.user_id).collect());
+    if let Err(e) = db
+        .call(move |conn| {
+            db::log_tag(conn, id, user_ids.len())?;
+            db::mark_tagged(conn, id, &user_ids)
+        })
+        .await
+    {
+        log::error!("[{}] Failed to log tag: {}", chat_name, e);
+    }
+}
+
+/// Header of an /all tag: the message, the skipped users and the countdown of `--ttl`
+fn all_header(text: &str, skipped: &[String], options: &AllOptions, format: TagFormat) -> String {
+    let mut header = tag_header(text, skipped, format);
+    if let Some(ttl) = options.ttl {
+        if !header.is_empty() {
+            header.push('\n');
+        }
+        header.push_str(&countdown_line(format.markup, ttl));
+    }
+    header
+}
+
+/// Handles the /preview command - sends the admin in private the messages /all would send (admin only)
+async fn handle_preview_command(
+    bot: Bot,
+    msg: Message,
+    text: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "preview").await? {
+        return Ok(());
+    }
+    let Some(admin) = &msg.from else {
+        return Ok(());
+    };
+
+    let (options, text) = match AllOptions::parse(&text) {
+        Ok(parsed) => parsed,
+        Err(reason) => {
+            bot.send_message(msg.chat.id, reason)
+                .reply_parameters(ReplyParameters::new(msg.id))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let markup = chat_tag_format(&db, msg.chat.id).await.markup;
+    if let Err(reason) = check_tag_text(text, markup, config) {
+        bot.send_message(
+            msg.chat.id,
+            format!("/all would refuse this message: {}", reason),
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    }
+
+    let targets =
+        gather_tag_targets(&bot, msg.chat.id, chat_name, &db, config, state, &options).await;
+    let header = all_header(text, &targets.skipped, &options, targets.format);
+    let chunks = mentions::build_tag_messages(&header, &targets.users, targets.format);
+
+    log::info!(
+        "[{}] Sending {} a preview of {} tag messages",
+        chat_name,
+        describe_sender(&msg),
+        chunks.len()
+    );
+
+    // Mentions in a private chat don't notify the mentioned users, only the admin sees the preview
+    let summary = format!(
+        "Preview of /all in {}: {} users in {} messages, nobody was tagged.",
+        chat_name,
+        targets.users.len(),
+        chunks.len()
+    );
+    let result = async {
+        bot.send_message(admin.id, summary).await?;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let _permit = state.send_permit().await;
+            match bot
+                .send_message(admin.id, chunk.text)
+                .parse_mode(targets.format.markup.parse_mode())
+                .disable_notification(true)
+                .await
+            {
+                Err(RequestError::Api(ApiError::CantParseEntities(e))) => {
+                    let note = format!(
+                        "Telegram can't parse message {} ({}), /all would send it as plain text:",
+                        i + 1,
+                        e
+                    );
+                    bot.send_message(admin.id, note).await?;
+                    bot.send_message(admin.id, chunk.plain)
+                        .disable_notification(true)
+                        .await?;
+                }
+                result => {
+                    result?;
+                }
+            }
+        }
+        Ok::<_, RequestError>(())
+    }
+    .await;
+
+    let reply = match result {
+        Ok(()) => "Sent you the preview in private.",
+        Err(e) => {
+            log::warn!("[{}] Couldn't send the preview: {}", chat_name, e);
+            handle_undeliverable(&db, admin.id, &e).await;
+            match e {
+                RequestError::Api(ApiError::CantInitiateConversation | ApiError::BotBlocked) => {
+                    "I can't write to you in private, start a chat with me first and try again."
+                }
+                _ => "Failed to send you the preview.",
+            }
+        }
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Formats a remaining cooldown such as "45 seconds" or "3 minutes", rounding up
+fn format_remaining(remaining: Duration) -> String {
+    let secs = remaining.as_secs_f64().ceil() as u64;
+    if secs < 60 {
+        return format!("{} second{}", secs, if secs == 1 { "" } else { "s" });
+    }
+    let minutes = secs.div_ceil(60);
+    format!("{} minute{}", minutes, if minutes == 1 { "" } else { "s" })
+}
+
+/// Whether a send failed because the bot is muted or not allowed to write in the chat
+fn is_missing_send_rights(error: &RequestError) -> bool {
+    match error {
+        RequestError::Api(ApiError::NotEnoughRightsToPostMessages) => true,
+        RequestError::Api(ApiError::Unknown(description)) => {
+            description.contains("not enough rights to send")
+        }
+        _ => false,
+    }
+}
+
+/// Tells the invoking admin in a private chat that the bot can't write in the tagged chat
+async fn report_missing_send_rights(bot: &Bot, msg: &Message, chat_name: &str, db: &Db) {
+    log::warn!(
+        "[{}] Can't send messages, the bot is muted or lacks the permission to send messages",
+        chat_name
+    );
+
+    let Some(user) = &msg.from else {
+        return;
+    };
+    let text = format!(
+        "I couldn't tag everyone in {} because I'm not allowed to send messages there. \
+         Unmute me or give me the permission to send messages, then try again.",
+        chat_name
+    );
+    if let Err(e) = bot.send_message(user.id, text).await {
+        log::debug!(
+            "[{}] Couldn't tell {} about the missing permission: {}",
+            chat_name,
+            user.id,
+            e
+        );
+        handle_undeliverable(db, user.id, &e).await;
+    }
+}
+
+/// Updates the tracking of a user a private message couldn't reach, see `db::mark_and_maybe_delete`
+async fn handle_undeliverable(db: &Db, user_id: UserId, error: &RequestError) {
+    let reason = match error {
+        RequestError::Api(ApiError::UserDeactivated) => db::Undeliverable::Deactivated,
+        RequestError::Api(ApiError::BotBlocked) => db::Undeliverable::BlockedBot,
+        _ => return,
+    };
+
+    let id = user_id.0 as i64;
+    match db
+        .call(move |conn| db::mark_and_maybe_delete(conn, id, reason))
+        .await
+    {
+        Ok(0) => {}
+        Ok(chats) => log::info!("Removed deactivated user {} from {} chats", user_id, chats),
+        Err(e) => log::error!("Failed to update undeliverable user {}: {}", user_id, e),
+    }
+}
+
+/// Pins a tag message, telling the admin when the bot isn't allowed to
+async fn pin_tag_message(bot: &Bot, msg: &Message, tag: &Message) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    // Members were already notified by the tag itself
+    match bot
+        .pin_chat_message(tag.chat.id, tag.id)
+        .disable_notification(true)
+        .await
+    {
+        Ok(_) => log::info!("[{}] Pinned tag message", chat_name),
+        Err(e) => {
+            log::warn!("[{}] Failed to pin tag message: {}", chat_name, e);
+            bot.send_message(
+                msg.chat.id,
+                "I couldn't pin the tag, make sure I have the permission to pin messages.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Handles the /tagin command - tags everyone in another chat, for admins of both chats
+async fn handle_tagin_command(
+    bot: Bot,
+    msg: Message,
+    args: String,
+    db: Db,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let args = args.trim();
+    let (target, text) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+    let Ok(target) = target.parse::<i64>().map(ChatId) else {
+        bot.send_message(msg.chat.id, "Usage: /tagin <chat id> <message>")
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    };
+
+    // The sender has to be identifiable to be checked in the target chat
+    let user = match &msg.from {
+        Some(u) if msg.sender_chat.is_none() => u,
+        _ => {
+            bot.send_message(
+                msg.chat.id,
+                "Send /tagin from your own account, anonymous admins can't be verified in the other chat.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(());
+        }
+    };
+
+    if (msg.chat.is_group() || msg.chat.is_supergroup())
+        && !ensure_admin(&bot, &msg, config, "tagin").await?
+    {
+        return Ok(());
+    }
+
+    let target_title = db
+        .call(move |conn| db::get_chat_title(conn, target.0))
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| target.0.to_string());
+
+    if !config.is_owner(user.id) {
+        let is_target_admin = match bot.get_chat_member(target, user.id).await {
+            Ok(member) => matches!(
+                member.kind,
+                ChatMemberKind::Administrator(_) | ChatMemberKind::Owner(_)
+            ),
+            Err(e) => {
+                log::warn!(
+                    "[{}] Couldn't check {} (ID: {}) for /tagin: {}",
+                    target_title,
+                    user.first_name,
+                    user.id.0,
+                    e
+                );
+                false
+            }
+        };
+        if !is_target_admin {
+            log::warn!(
+                "[{}] {} (ID: {}) attempted /tagin without being an admin there",
+                target_title,
+                user.first_name,
+                user.id.0
+            );
+            bot.send_message(
+                msg.chat.id,
+                "You need to be an admin of that chat, and the bot a member of it.",
+            )
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+            return Ok(());
+        }
+    }
+
+    log::info!(
+        "[{}] /tagin invoked by {} from {}",
+        target_title,
+        describe_sender(&msg),
+        msg.chat.id.0
+    );
+
+    let settings = db
+        .call(move |conn| db::get_chat_settings(conn, target.0).unwrap_or_default())
+        .await;
+    if let Some(reason) =
+        tag_refusal(target, &target_title, text, &settings, &db, config, state).await
+    {
+        bot.send_message(msg.chat.id, reason)
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let Some(_tag_guard) = state.try_lock_tag(target) else {
+        bot.send_message(
+            msg.chat.id,
+            "A tag is already in progress in that chat, try again once it's done.",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+
+    let targets = gather_tag_targets(
+        &bot,
+        target,
+        &target_title,
+        &db,
+        config,
+        state,
+        &AllOptions::default(),
+    )
+    .await;
+    if targets.users.is_empty() {
+        bot.send_message(msg.chat.id, format!("Nobody to tag in {}.", target_title))
+            .reply_parameters(ReplyParameters::new(msg.id))
+            .await?;
+        return Ok(());
+    }
+
+    let header = tag_header(text, &targets.skipped, targets.format);
+    match send_tag(
+        &bot,
+        target,
+        None,
+        state,
+        &header,
+        &targets.users,
+        targets.format,
+    )
+    .await
+    {
+        Ok(_) => {}
+        Err(e) if is_missing_send_rights(&e) => {
+            report_missing_send_rights(&bot, &msg, &target_title, &db).await;
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+
+    log::info!(
+        "[{}] Tagged {} users on behalf of {}",
+        target_title,
+        targets.users.len(),
+        msg.chat.id.0
+    );
+    finish_tag(target, &target_title, &targets.users, &db, config, state).await;
+
+    bot.send_message(
+        msg.chat.id,
+        format!("Tagged {} users in {}.", targets.users.len(), target_title),
+    )
+    .reply_parameters(ReplyParameters::new(msg.id))
+    .await?;
+
+    Ok(())
+}
+
+/// Handles the /undo command - deletes the messages of the last tag sent in the chat (admin only)
+async fn handle_undo_command(
+    bot: Bot,
+    msg: Message,
+    config: &Config,
+    state: &State,
+) -> ResponseResult<()> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !msg.chat.is_group() && !msg.chat.is_supergroup() {
+        bot.send_message(msg.chat.id, "This command only works in groups.")
+            .await?;
+        return Ok(());
+    }
+
+    if !ensure_admin(&bot, &msg, config, "undo").await? {
+        return Ok(());
+    }
+
+    let Some(message_ids) = state.take_last_tag(msg.chat.id, UNDO_WINDOW) else {
+        bot.send_message(
+            msg.chat.id,
+            format!(
+                "There is no tag from the last {} minutes to undo.",
+                UNDO_WINDOW.as_secs() / 60
+            ),
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+        return Ok(());
+    };
+
+    let mut deleted = 0;
+    for &message_id in &message_ids {
+        match bot.delete_message(msg.chat.id, message_id).await {
+            Ok(_) => deleted += 1,
+            Err(e) => log::warn!(
+                "[{}] Failed to delete tag message {}: {}",
+                chat_name,
+                message_id.0,
+                e
+            ),
+        }
+    }
+
+    log::info!(
+        "[{}] Undid last tag, deleted {}/{} messages",
+        chat_name,
+        deleted,
+        message_ids.len()
+    );
+
+    let reply = if deleted == message_ids.len() {
+        "Tag deleted.".to_string()
+    } else {
+        format!(
+            "Deleted {} of {} tag messages, make sure I have the permission to delete messages.",
+            deleted,
+            message_ids.len()
+        )
+    };
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(())
+}
+
+/// Line telling how long a self-destructing tag has left
+fn countdown_line(markup: Markup, remaining_secs: u64) -> String {
+    markup.italic(&format!("disappears in {}s", remaining_secs))
+}
+
+/// Counts down on the first tag message and deletes every tag message once the time is up
+async fn self_destruct(bot: Bot, sent: Vec<SentChunk>, ttl: u64, markup: Markup) {
+    let Some(first) = sent.first() else {
+        return;
+    };
+    let initial_line = countdown_line(markup, ttl);
+    // Only a message sent with the parse mode can be edited with it, the others keep their first countdown
+    let counts_down = first.mode == SentMode::Markup;
+
+    let mut remaining = ttl;
+    while remaining > 0 {
+        let step = remaining.min(TTL_EDIT_INTERVAL_SECS);
+        tokio::time::sleep(Duration::from_secs(step)).await;
+        remaining -= step;
+
+        if remaining > 0 && counts_down {
+            let text = first
+                .text
+                .replacen(&initial_line, &countdown_line(markup, remaining), 1);
+            if let Err(e) = bot
+                .edit_message_text(first.message.chat.id, first.message.id, text)
+                .parse_mode(markup.parse_mode())
+                .await
+            {
+                log::debug!("Failed to update self-destruct countdown: {}", e);
+            }
+        }
+    }
+
+    for chunk in &sent {
+        if let Err(e) = bot
+            .delete_message(chunk.message.chat.id, chunk.message.id)
+            .await
+        {
+            // Most likely the bot lacks the permission to delete messages, don't insist
+            log::warn!(
+                "[{}] Couldn't delete self-destructing tag, skipping deletion: {}",
+                chunk.message.chat.id.0,
+                e
+            );
+            return;
+        }
+    }
+
+    log::info!(
+        "[{}] Deleted self-destructing tag after {}s",
+        first.message.chat.id.0,
+        ttl
+    );
+}
+
+/// Takes the tag lock of the chat, telling the caller to wait if another tag is in progress
+async fn lock_tag(
+    bot: &Bot,
+    msg: &Message,
+    state: &State,
+) -> ResponseResult<Option<OwnedMutexGuard<()>>> {
+    let guard = state.try_lock_tag(msg.chat.id);
+
+    if guard.is_none() {
+        log::info!(
+            "[{}] Tag already in progress, rejecting new one",
+            msg.chat.title().unwrap_or("Unknown")
+        );
+        bot.send_message(
+            msg.chat.id,
+            "A tag is already in progress in this chat, try again once it's done.",
+        )
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+    }
+
+    Ok(guard)
+}
+
+/// Users selected for a tag, along with why some of the tracked users were left out
+struct TagTargets {
+    users: Vec<db::User>,
+    /// How many users are tracked in the chat before any filtering
+    tracked: usize,
+    /// Reasons some of the tracked users were left out, reported along with the tag
+    skipped: Vec<String>,
+    /// How the tag should be written, from the chat settings
+    format: TagFormat,
+    /// Reply when nobody is tracked in the chat, from the chat settings
+    empty_reply: String,
+}
+
+/// Syncs the chat admins and gathers the users a tag should mention, honoring options and settings
+async fn gather_tag_targets(
+    bot: &Bot,
+    chat: ChatId,
+    chat_name: &str,
+    db: &Db,
+    config: &Config,
+    state: &State,
+    options: &AllOptions,
+) -> TagTargets {
+    // Fetch all administrators and add them to the database
+    // This ensures we at least have all admins tracked
+    match sync_admins(bot, db, config, state, chat).await {
+        Ok(sync) => {
+            log::info!("[{}] Synced {} admins to database", chat_name, sync.total());
+        }
+        // Tag the users tracked so far rather than failing the whole tag
+        Err(e) => log::warn!("[{}] Failed to sync admins, skipping: {}", chat_name, e),
+    }
+
+    load_tag_targets(chat, chat_name, db, state, options).await
+}
+
+/// Gathers the users a tag should mention from the database, without syncing the admins first
+async fn load_tag_targets(
+    chat: ChatId,
+    chat_name: &str,
+    db: &Db,
+    state: &State,
+    options: &AllOptions,
+) -> TagTargets {
+    // Get all tracked users for this chat, or of the tag group
+    let chat_id = chat.0;
+    let (group, cohort, newest) = (
+        options.group.clone(),
+        options.cohort.clone(),
+        options.newest,
+    );
+    let (mut users, settings) = db
+        .call(move |conn| {
+            let users = match (&group, &cohort, newest) {
+                (Some(name), _, _) => db::get_group_users(conn, chat_id, name),
+                (None, Some(cohort), _) => db::get_cohort_users(conn, chat_id, cohort),
+                (None, None, Some(count)) => db::get_newest_users(conn, chat_id, count),
+                (None, None, None) => db::get_users_for_chat(conn, chat_id),
+            };
+            (
+                users.unwrap_or_default(),
+                db::get_chat_settings(conn, chat_id).unwrap_or_default(),
+            )
+        })
+        .await;
+    // Users of ephemeral chats only live in memory, tag groups can't hold them
+    if settings.ephemeral && options.group.is_none() {
+        let memory = state.ephemeral_users(chat).into_iter().filter(|u| {
+            options
+                .cohort
+                .as_ref()
+                .is_none_or(|cohort| u.cohort.as_ref() == Some(cohort))
+        });
+        merge_ephemeral_users(&mut users, memory.collect());
+    }
+
+    // Newcomers are picked first, the filters below then report the ones they leave out
+    if let Some(count) = options.newest {
+        users.retain(|u| u.joined_at.is_some());
+        users.sort_by_key(|u| std::cmp::Reverse(u.joined_at));
+        users.truncate(count);
+    }
+
+    let tracked = users.len();
+    let mut skipped = Vec::new();
+
+    let before = users.len();
+    users.retain(|u| u.notify_pref != db::NotifyPref::Off);
+    if before > users.len() {
+        skipped.push(format!("{} users opted out", before - users.len()));
+    }
+
+    if options.usernames_only {
+        let before = users.len();
+        users.retain(|u| u.username.is_some());
+        if before > users.len() {
+            skipped.push(format!(
+                "{} users skipped for lacking a username",
+                before - users.len()
+            ));
+        }
+    }
+
+    if settings.skip_deleted_accounts {
+        let before = users.len();
+        users.retain(|u| !u.looks_deleted());
+        if before > users.len() {
+            skipped.push(format!("{} deleted accounts skipped", before - users.len()));
+        }
+    }
+
+    if let Some(days) = options.active_days {
+        let cutoff = db::unix_now() - days * 86400;
+        let before = users.len();
+        users.retain(|u| u.always_tag || u.last_seen.is_some_and(|last_seen| last_seen >= cutoff));
+        if before > users.len() {
+            skipped.push(format!(
+                "{} users not active in the last {} days",
+                before - users.len(),
+                days
+            ));
+        }
+    }
+
+    if settings.min_membership_hours > 0 {
+        let cutoff = db::unix_now() - settings.min_membership_hours * 3600;
+        let before = users.len();
+        users.retain(|u| u.always_tag || u.joined_at.is_none_or(|joined_at| joined_at <= cutoff));
+        if before > users.len() {
+            skipped.push(format!("{} users skipped as too new", before - users.len()));
+        }
+    }
+
+    if let Some(pattern) = &settings.exclude_pattern {
+        match state.exclude_regex(pattern) {
+            Some(regex) => {
+                let before = users.len();
+                users.retain(|u| u.always_tag || !matches_exclude_pattern(&regex, u));
+                if before > users.len() {
+                    skipped.push(format!(
+                        "{} users skipped by the exclude pattern",
+                        before - users.len()
+                    ));
+                }
+            }
+            None => log::warn!(
+                "[{}] Ignoring invalid exclude pattern {}",
+                chat_name,
+                pattern
+            ),
+        }
+    }
+
+    // Rounds go through everyone before starting over, since the users tagged longest ago come first
+    if let Some(count) = options.next
+        && users.len() > count
+    {
+        users.sort_by_key(|u| u.last_tagged);
+        skipped.push(format!(
+            "{} users left for the next rounds",
+            users.len() - count
+        ));
+        users.truncate(count);
+    }
+
+    for reason in &skipped {
+        log::info!("[{}] {}", chat_name, reason);
+    }
+
+    TagTargets {
+        users,
+        tracked,
+        skipped,
+        format: TagFormat {
+            use_usernames: options.usernames_only,
+            sections: options.sections,
+            ..settings.tag_format()
+        },
+        empty_reply: settings.empty_roster_reply(chat_name),
+    }
+}
+
+/// Tells the caller when there is nobody to tag, returning whether a reply was sent
+async fn report_empty_targets(
+    bot: &Bot,
+    msg: &Message,
+    targets: &TagTargets,
+) -> ResponseResult<bool> {
+    let chat_name = msg.chat.title().unwrap_or("Unknown");
+
+    if !targets.users.is_empty() {
+        return Ok(false);
+    }
+
+    let reply = if targets.tracked > 0 {
+        format!("Nobody left to tag: {}.", targets.skipped.join(", "))
+    } else {
+        log::warn!("[{}] No users tracked yet", chat_name);
+        targets.empty_reply.clone()
+    };
+
+    bot.send_message(msg.chat.id, reply)
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .await?;
+
+    Ok(true)
+}
+
+/// Rejects tag messages that are too long or that would blow up once escaped, such as a wall of special characters
+fn check_tag_text(text: &str, markup: Markup, config: &Config) -> Result<(), String> {
+    let text = text.trim();
+    let len = text.chars().count();
+    if len > config.max_tag_text_len {
+        return Err(format!(
+            "The message is too long ({} characters), the limit is {}.",
+            len, config.max_tag_text_len
+        ));
+    }
+
+    let escaped = markup.escape(text).len();
+    if text.len() >= ESCAPE_GROWTH_MIN_LEN && escaped as f64 > text.len() as f64 * MAX_ESCAPE_GROWTH
+    {
+        return Err("The message contains too many special characters.".to_string());
+    }
+
+    Ok(())
+}
+
+/// Builds the escaped header of a tag message from the text and the skipped users notes,
+/// which are left out in silent mode
+fn tag_header(text: &str, skipped: &[String], format: TagFormat) -> String {
+    let markup = format.markup;
+    let mut header = markup.escape(text.trim());
+    if !skipped.is_empty() && format.verbosity != Verbosity::Silent {
+        if !header.is_empty() {
+            header.push('\n');
+        }
+        header.push_str(&markup.escape(&format!("({})", skipped.join(", "))));
+    }
+    header
+}
+
+/// Reads how tags should be written in a chat from its settings
+async fn chat_tag_format(db: &Db, chat_id: ChatId) -> TagFormat {
+    db.call(move |conn| db::get_chat_settings(conn, chat_id.0))
+        .await
+        .unwrap_or_default()
+        .tag_format()
+}
+
+/// How a tag message was sent, edits have to be sent the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SentMode {
+    /// With the parse mode of the chat
+    Markup,
+    /// With custom emoji entities, whose offsets an edit of the text would shift
+    CustomEmoji,
+    /// As plain text, after Telegram couldn't parse the formatted one
+    Plain,
+}
+
+/// A tag message that was sent, along with the text it was sent with
+struct SentChunk {
+    message: Message,
+    text: String,
+    mode: SentMode,
+}
+
+/// What `deliver_tag` managed to send, the messages sent before a failure included
+struct DeliveredTag {
+    chunks: Vec<SentChunk>,
+    status: Option<Message>,
+    result: ResponseResult<()>,
+}
+
+/// Sends the tag messages for the given users, as replies to the command message if there is one,
+/// and remembers them for /undo, even when a failure stopped the tag halfway
+async fn send_tag(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    state: &State,
+    header: &str,
+    users: &[db::User],
+    format: TagFormat,
+) -> ResponseResult<Vec<SentChunk>> {
+    let delivered = deliver_tag(bot, chat_id, reply_to, state, header, users, format).await;
+
+    let mut message_ids: Vec<MessageId> = delivered.chunks.iter().map(|c| c.message.id).collect();
+    message_ids.extend(delivered.status.as_ref().map(|s| s.id));
+    if !message_ids.is_empty() {
+        state.record_tag(chat_id, message_ids);
+    }
+
+    delivered.result.map(|()| delivered.chunks)
+}
+
+/// Sends the tag messages for the given users, stopping at the first message that can't be sent
+///
+/// Tags spanning several messages also get a status message that is edited to show progress.
+async fn deliver_tag(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: Option<MessageId>,
+    state: &State,
+    header: &str,
+    users: &[db::User],
+    format: TagFormat,
+) -> DeliveredTag {
+    let chunks = mentions::build_tag_messages(header, users, format);
+    let mut sent = Vec::new();
+
+    // Progress for tags split in several messages, and for every tag in verbose mode
+    let show_status = match format.verbosity {
+        Verbosity::Silent => false,
+        Verbosity::Normal => chunks.len() > 1,
+        Verbosity::Verbose => true,
+    };
+    let status = if show_status {
+        let _permit = state.send_permit().await;
+        let mut request = bot.send_message(chat_id, format!("Tagging 0/{}…", users.len()));
+        if let Some(reply_to) = reply_to {
+            request = request.reply_parameters(ReplyParameters::new(reply_to));
+        }
+        request.await.ok()
+    } else {
+        None
+    };
+
+    let mut tagged = 0;
+    let mut result = Ok(());
+    for chunk in chunks {
+        let permit = state.send_permit().await;
+        let custom_emoji = match &chunk.custom_emoji {
+            Some(custom) => {
+                let mut request = bot
+                    .send_message(chat_id, custom.text.clone())
+                    .entities(custom.entities.clone())
+                    .disable_notification(chunk.silent);
+                if let Some(reply_to) = reply_to {
+                    request = request.reply_parameters(ReplyParameters::new(reply_to));
+                }
+                match request.await {
+                    // Unknown emoji ids, or ones the bot isn't allowed to use, get their alt instead
+                    Err(RequestError::Api(e)) => {
+                        log::warn!(
+                            "[{}] Custom emoji refused, sending the tag without them: {}",
+                            chat_id.0,
+                            e
+                        );
+                        None
+                    }
+                    result => Some(result),
+                }
+            }
+            None => None,
+        };
+        let (sent_chunk, mode) = match custom_emoji {
+            Some(result) => (result, SentMode::CustomEmoji),
+            None => {
+                let mut request = bot
+                    .send_message(chat_id, chunk.text.clone())
+                    .parse_mode(format.markup.parse_mode())
+                    .disable_notification(chunk.silent);
+                if let Some(reply_to) = reply_to {
+                    request = request.reply_parameters(ReplyParameters::new(reply_to));
+                }
+                (request.await, SentMode::Markup)
+            }
+        };
+        let sent_chunk = match sent_chunk {
+            // Last resort for escaping bugs, a tag that doesn't notify everyone beats no tag at all
+            Err(RequestError::Api(ApiError::CantParseEntities(e))) => {
+                log::warn!(
+                    "[{}] Tag message couldn't be parsed, resending as plain text: {}",
+                    chat_id.0,
+                    e
+                );
+                let mut request = bot
+                    .send_message(chat_id, chunk.plain.clone())
+                    .disable_notification(chunk.silent);
+                if let Some(reply_to) = reply_to {
+                    request = request.reply_parameters(ReplyParameters::new(reply_to));
+                }
+                request
+                    .await
+                    .map(|message| (message, chunk.plain, SentMode::Plain))
+            }
+            sent_chunk => sent_chunk.map(|message| (message, chunk.text, mode)),
+        };
+        drop(permit);
+        let (message, text, mode) = match sent_chunk {
+            Ok(sent_chunk) => sent_chunk,
+            Err(e) => {
+                note_rate_limit(state, chat_id, &e);
+                result = Err(e);
+                break;
+            }
+        };
+        sent.push(SentChunk {
+            message,
+            text,
+            mode,
+        });
+        tagged += chunk.user_count;
+
+        if let Some(status) = &status {
+            let text = format!("Tagging {}/{}…", tagged, users.len());
+            if let Err(e) = bot.edit_message_text(status.chat.id, status.id, text).await {
+                // Progress updates are best effort, skip them while rate limited
+                note_rate_limit(state, chat_id, &e);
+                log::debug!("Failed to update tag progress: {}", e);
+            }
+        }
+    }
+
+    // A failed tag still ends its status, so it doesn't look like it's still going
+    if let Some(status) = &status {
+        let text = match &result {
+            Ok(()) => format!("Tagged {} users.", users.len()),
+            Err(_) => format!("Stopped after {}/{} users.", tagged, users.len()),
+        };
+        finalize_status(bot, state, status, text).await;
+    }
+
+    DeliveredTag {
+        chunks: sent,
+        status,
+        result,
+    }
+}
+
+/// Remembers the error for /ratelimit if Telegram answered with a rate limit
+fn note_rate_limit(state: &State, chat_id: ChatId, error: &RequestError) {
+    if let RequestError::RetryAfter(retry_after) = error {
+        log::warn!(
+            "[{}] Rate limited, retry after {}s",
+            chat_id.0,
+            retry_after.seconds()
+        );
+        state.record_rate_limit(chat_id, retry_after.duration());
+    }
+}
+
+/// Edits a status message to its final text, waiting out a rate limit once if needed
+async fn finalize_status(bot: &Bot, state: &State, status: &Message, text: String) {
+    let result = match bot
+        .edit_message_text(status.chat.id, status.id, text.clone())
+        .await
+    {
+        Err(RequestError::RetryAfter(retry_after)) => {
+            state.record_rate_limit(status.chat.id, retry_after.duration());
+            tokio::time::sleep(retry_after.duration()).await;
+            bot.edit_message_text(status.chat.id, status.id, text).await
+        }
+        result => result,
+    };
+
+    if let Err(e) = result {
+        log::warn!("Failed to finalize tag status message: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teloxide::types::{ChatMember, Member, User};
+
+    fn test_db() -> Db {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::create_schema(&conn).unwrap();
+        Db::new(conn)
+    }
+
+    fn tg_user(id: u64, is_bot: bool) -> User {
+        User {
+            id: UserId(id),
+            is_bot,
+            first_name: format!("User {}", id),
+            last_name: None,
+            username: None,
+            language_code: None,
+            is_premium: false,
+            added_to_attachment_menu: false,
+        }
+    }
+
+    /// A bot and the Telegram placeholder accounts: the service account, anonymous admins and channels
+    fn untrackable_users() -> Vec<User> {
+        vec![
+            tg_user(42, true),
+            tg_user(777000, false),
+            tg_user(1087968824, false),
+            tg_user(136817688, false),
+        ]
+    }
+
+    /// A message of `ESCAPE_GROWTH_MIN_LEN` characters, `specials` of them being `special`
+    fn text_with_specials(special: char, specials: usize) -> String {
+        let mut text = special.to_string().repeat(specials);
+        text.push_str(&"a".repeat(ESCAPE_GROWTH_MIN_LEN - specials));
+        text
+    }
+
+    #[test]
+    fn check_tag_text_allows_markdown_v2_up_to_the_growth_limit() {
+        let config = Config::default();
+        // Every `!` takes a backslash, 16 of 32 characters make the message exactly 1.5 times longer
+        assert!(check_tag_text(&text_with_specials('!', 16), Markup::MarkdownV2, &config).is_ok());
+        assert!(check_tag_text(&text_with_specials('!', 17), Markup::MarkdownV2, &config).is_err());
+    }
+
+    #[test]
+    fn check_tag_text_allows_html_up_to_the_growth_limit() {
+        let config = Config::default();
+        // Every `<` becomes `&lt;`, 5 of 32 characters add 15 of the 16 allowed bytes
+        assert!(check_tag_text(&text_with_specials('<', 5), Markup::Html, &config).is_ok());
+        assert!(check_tag_text(&text_with_specials('<', 6), Markup::Html, &config).is_err());
+    }
+
+    #[test]
+    fn check_tag_text_rejects_long_messages_of_only_special_characters() {
+        let config = Config::default();
+        let specials = "_*[]()~`>#+-=|{}.!".repeat(4);
+        assert!(check_tag_text(&specials, Markup::MarkdownV2, &config).is_err());
+        let specials = "<>&\"".repeat(16);
+        assert!(check_tag_text(&specials, Markup::Html, &config).is_err());
+
+        // Short ones can't grow enough to matter
+        assert!(check_tag_text("!!!", Markup::MarkdownV2, &config).is_ok());
+        assert!(check_tag_text("<&>", Markup::Html, &config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn untrackable_users_are_never_stored() {
+        let (db, config) = (test_db(), Config::default());
+        let state = State::new(&config);
+        let (chat, ephemeral_chat) = (ChatId(-1), ChatId(-2));
+        let settings = settings::ChatSettings {
+            ephemeral: true,
+            ..Default::default()
+        };
+        db.call(move |conn| db::save_chat_settings(conn, ephemeral_chat.0, &settings))
+            .await
+            .unwrap();
+
+        for chat in [chat, ephemeral_chat] {
+            for user in untrackable_users() {
+                assert_eq!(
+                    store_user(&db, &config, &state, chat, &user).await,
+                    StoredUser::Rejected
+                );
+                remember_ephemeral_join(&state, chat, &user, true, None);
+            }
+            let admins = untrackable_users()
+                .into_iter()
+                .map(|user| ChatMember {
+                    user,
+                    kind: ChatMemberKind::Member(Member { until_date: None }),
+                })
+                .collect();
+            assert_eq!(
+                store_admins(&db, &config, &state, chat, admins)
+                    .await
+                    .total(),
+                0
+            );
+
+            let stored = db
+                .call(move |conn| db::get_users_for_chat(conn, chat.0))
+                .await
+                .unwrap();
+            assert!(stored.is_empty());
+            assert!(state.ephemeral_users(chat).is_empty());
+        }
+
+        // The same paths do store people
+        let person = tg_user(5, false);
+        assert_eq!(
+            store_user(&db, &config, &state, chat, &person).await,
+            StoredUser::Stored
+        );
+        assert_eq!(
+            store_user(&db, &config, &state, ephemeral_chat, &person).await,
+            StoredUser::Ephemeral
+        );
+        let stored = db
+            .call(move |conn| db::get_users_for_chat(conn, chat.0))
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(state.ephemeral_users(ephemeral_chat).len(), 1);
+    }
+
+    #[test]
+    fn restoring_a_backup_leaves_placeholder_accounts_out() {
+        let mut backup = export::parse_json_backup(
+            r#"{"chats": [{
+                "chat_id": -1,
+                "users": [
+                    {"user_id": 777000, "first_name": "Telegram"},
+                    {"user_id": 1087968824, "first_name": "Group"},
+                    {"user_id": 136817688, "first_name": "Channel"},
+                    {"user_id": 5, "first_name": "Alice"}
+                ],
+                "groups": {"devs": [777000, 5]}
+            }]}"#,
+        )
+        .unwrap();
+        assert_eq!(drop_untrackable(&mut backup), 3);
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        db::create_schema(&conn).unwrap();
+        db::import_json(&conn, &backup).unwrap();
+        let users = db::get_users_for_chat(&conn, -1).unwrap();
+        assert_eq!(users.iter().map(|u| u.user_id).collect::<Vec<_>>(), [5]);
+        let devs = db::get_group_users(&conn, -1, "devs").unwrap();
+        assert_eq!(devs.iter().map(|u| u.user_id).collect::<Vec<_>>(), [5]);
+    }
+
+    #[test]
+    fn multi_line_all_keeps_every_line() {
+        for text in [
+            "/all line 1\nline 2.\nline 3!",
+            "/all\nline 1\nline 2.\nline 3!",
+        ] {
+            let Some(Command::All(args)) = parse_command_text(text, "tagger_bot") else {
+                panic!("{:?} isn't parsed as /all", text);
+            };
+            assert_eq!(args, "line 1\nline 2.\nline 3!");
+
+            let (options, message) = AllOptions::parse(&args).unwrap();
+            assert_eq!(
+                all_header(message, &[], &options, TagFormat::default()),
+                "line 1\nline 2\\.\nline 3\\!"
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_sample_picks_distinct_users_favoring_the_weighting() {
+        use rand::{SeedableRng, rngs::StdRng};
+
+        let users: Vec<db::User> = (1..=10)
+            .map(|id| {
+                let mut user = db::User::new(id, &format!("User {}", id), None);
+                user.message_count = (id - 1) * 20;
+                user
+            })
+            .collect();
+        let mut rng = StdRng::seed_from_u64(184);
+
+        for weighting in [
+            RandomWeighting::Uniform,
+            RandomWeighting::Active,
+            RandomWeighting::Quiet,
+        ] {
+            // Never the same user twice, nor more users than there are
+            for count in [3, 10, 25] {
+                let picked = weighted_sample(users.clone(), count, weighting, &mut rng);
+                let ids: HashSet<i64> = picked.iter().map(|u| u.user_id).collect();
+                assert_eq!(picked.len(), count.min(users.len()));
+                assert_eq!(ids.len(), picked.len());
+            }
+        }
+
+        let mut picks = |weighting| {
+            let mut picks = HashMap::<i64, usize>::new();
+            for _ in 0..2000 {
+                for user in weighted_sample(users.clone(), 3, weighting, &mut rng) {
+                    *picks.entry(user.user_id).or_default() += 1;
+                }
+            }
+            let count = |id| picks.get(&id).copied().unwrap_or(0);
+            (count(1), count(10))
+        };
+        let (quietest, busiest) = picks(RandomWeighting::Active);
+        assert!(
+            busiest > quietest * 2,
+            "active: {} vs {}",
+            busiest,
+            quietest
+        );
+        let (quietest, busiest) = picks(RandomWeighting::Quiet);
+        assert!(quietest > busiest * 2, "quiet: {} vs {}", quietest, busiest);
+    }
+
+    const GROUP_ID: i64 = -1001234567890;
+
+    /// A group message such as Telegram sends it, with the given fields on top
+    fn group_message(fields: serde_json::Value) -> Message {
+        let mut message = serde_json::json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": GROUP_ID, "type": "supergroup", "title": "Group"},
+            "text": "/all",
+        });
+        for (key, value) in fields.as_object().unwrap() {
+            message[key] = value.clone();
+        }
+        serde_json::from_value(message).unwrap()
+    }
+
+    #[test]
+    fn classify_sender_tells_users_anonymous_admins_and_chats_apart() {
+        let user = group_message(serde_json::json!({
+            "from": {"id": 5, "is_bot": false, "first_name": "Alice"},
+        }));
+        assert!(matches!(classify_sender(&user), Sender::User(u) if u.id == UserId(5)));
+
+        let anonymous_admin = group_message(serde_json::json!({
+            "from": {"id": 1087968824, "is_bot": true, "first_name": "Group", "username": "GroupAnonymousBot"},
+            "sender_chat": {"id": GROUP_ID, "type": "supergroup", "title": "Group"},
+        }));
+        assert!(matches!(
+            classify_sender(&anonymous_admin),
+            Sender::AnonymousAdmin
+        ));
+
+        let channel = group_message(serde_json::json!({
+            "from": {"id": 136817688, "is_bot": true, "first_name": "Channel", "username": "Channel_Bot"},
+            "sender_chat": {"id": -1009876543210_i64, "type": "channel", "title": "Channel"},
+        }));
+        assert!(matches!(
+            classify_sender(&channel),
+            Sender::Chat(chat) if chat.id == ChatId(-1009876543210)
+        ));
+    }
+
+    #[tokio::test]
+    async fn anonymous_admins_are_authorized_without_asking_telegram() {
+        let anonymous_admin = group_message(serde_json::json!({
+            "from": {"id": 1087968824, "is_bot": true, "first_name": "Group"},
+            "sender_chat": {"id": GROUP_ID, "type": "supergroup", "title": "Group"},
+        }));
+        // The token is never used, any request would fail the test
+        let bot = Bot::new("0:test");
+        assert!(
+            ensure_admin(&bot, &anonymous_admin, &Config::default(), "all")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_without_any_sender_are_dropped_quietly() {
+        let nobody = group_message(serde_json::json!({}));
+        assert!(matches!(classify_sender(&nobody), Sender::Unknown));
+        assert_eq!(describe_sender(&nobody), "Unknown sender");
+
+        // Refused without a reply, there's nobody to send it to
+        let bot = Bot::new("0:test");
+        assert!(
+            !ensure_admin(&bot, &nobody, &Config::default(), "all")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn automatic_forwards_and_placeholder_senders_are_not_tracked() {
+        let forward = group_message(serde_json::json!({
+            "from": {"id": 777000, "is_bot": false, "first_name": "Telegram"},
+            "sender_chat": {"id": -1009876543210_i64, "type": "channel", "title": "Channel"},
+            "is_automatic_forward": true,
+        }));
+        assert!(forward.is_automatic_forward());
+        assert!(tracked_sender(&forward).is_err());
+
+        // The service account isn't flagged as a bot
+        let telegram = group_message(serde_json::json!({
+            "from": {"id": 777000, "is_bot": false, "first_name": "Telegram"},
+        }));
+        assert!(tracked_sender(&telegram).is_err());
+
+        let user = group_message(serde_json::json!({
+            "from": {"id": 5, "is_bot": false, "first_name": "Alice"},
+        }));
+        assert!(tracked_sender(&user).is_ok_and(|u| u.id == UserId(5)));
+    }
+
+    #[tokio::test]
+    async fn lookup_target_user_finds_users_of_ephemeral_chats_in_memory() {
+        let (db, bot) = (test_db(), Bot::new("0:test"));
+        let state = State::new(&Config::default());
+        let mut alice = tg_user(5, false);
+        alice.username = Some("Alice".to_string());
+        remember_ephemeral_join(&state, ChatId(GROUP_ID), &alice, true, None);
+
+        let msg = group_message(serde_json::json!({
+            "text": "/whois",
+            "reply_to_message": {
+                "message_id": 0,
+                "date": 0,
+                "chat": {"id": GROUP_ID, "type": "supergroup", "title": "Group"},
+                "from": {"id": 5, "is_bot": false, "first_name": "User 5"},
+                "text": "hi",
+            },
+        }));
+        for arg in ["", "@alice", "5"] {
+            let target = lookup_target_user(&bot, &db, &state, &msg, arg)
+                .await
+                .unwrap();
+            assert_eq!(target.user_id, Some(5), "{}", arg);
+            assert_eq!(target.tracked().unwrap().user_id, 5, "{}", arg);
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_users_include_the_ones_kept_in_memory() {
+        let db = test_db();
+        let state = State::new(&Config::default());
+        let chat = ChatId(GROUP_ID);
+        db.call(move |conn| {
+            for user_id in [1, 2] {
+                db::upsert_user(conn, chat.0, user_id, "Stored", None, None).unwrap();
+            }
+        })
+        .await;
+        // User 2 is both stored and kept in memory, and is counted once
+        for user in [tg_user(2, false), tg_user(3, false)] {
+            remember_ephemeral_join(&state, chat, &user, true, None);
+        }
+
+        let mut ids: Vec<i64> = load_chat_users(&db, &state, chat)
+            .await
+            .iter()
+            .map(|u| u.user_id)
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [1, 2, 3]);
+
+        let memory = ephemeral_user_ids(&state, chat);
+        let snapshot = db
+            .call(move |conn| db::current_snapshot(conn, chat.0, &memory))
+            .await
+            .unwrap();
+        assert_eq!(snapshot.user_count, 3);
+    }
+
+    #[tokio::test]
+    async fn inline_results_leave_out_chats_that_would_refuse_the_tag() {
+        let (db, config) = (test_db(), Config::default());
+        let state = State::new(&config);
+        db.call(|conn| {
+            for chat_id in [-1, -2] {
+                db::upsert_chat(conn, chat_id, Some("Group")).unwrap();
+                db::upsert_user(conn, chat_id, 5, "Alice", None, None).unwrap();
+            }
+        })
+        .await;
+
+        assert_eq!(
+            inline_tag_results(&db, &config, &state, "hi").await.len(),
+            2
+        );
+
+        state.start_cooldown(ChatId(-1), "all", Duration::from_secs(60));
+        assert_eq!(
+            inline_tag_results(&db, &config, &state, "hi").await.len(),
+            1
+        );
+
+        let too_long = "a".repeat(config.max_tag_text_len + 1);
+        assert!(
+            inline_tag_results(&db, &config, &state, &too_long)
+                .await
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn split_group_arg_reads_the_group_before_the_user() {
+        assert_eq!(
+            split_group_arg(" devs  @alice ", "addtag").unwrap(),
+            ("devs".to_string(), " @alice")
+        );
+        assert_eq!(
+            split_group_arg("devs", "addtag").unwrap(),
+            ("devs".to_string(), "")
+        );
+        assert!(split_group_arg("", "addtag").is_err());
+        assert!(split_group_arg("bad/name @alice", "remtag").is_err());
+    }
+
+    #[tokio::test]
+    async fn lookup_target_user_ignores_replies_to_placeholder_accounts() {
+        let (db, bot) = (test_db(), Bot::new("0:test"));
+        let state = State::new(&Config::default());
+        let msg = group_message(serde_json::json!({
+            "text": "/whois",
+            "reply_to_message": {
+                "message_id": 0,
+                "date": 0,
+                "chat": {"id": GROUP_ID, "type": "supergroup", "title": "Group"},
+                "from": {"id": 777000, "is_bot": false, "first_name": "Telegram"},
+                "text": "forwarded post",
+            },
+        }));
+        assert!(
+            lookup_target_user(&bot, &db, &state, &msg, "")
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn newest_option_picks_newcomers_and_reports_the_filtered_ones() {
+        let db = test_db();
+        let state = State::new(&Config::default());
+        let chat = ChatId(GROUP_ID);
+        db.call(move |conn| {
+            for user_id in 1..=4 {
+                db::upsert_user(conn, chat.0, user_id, "User", None, None).unwrap();
+                conn.execute(
+                    "UPDATE users SET joined_at = ?1 WHERE chat_id = ?2 AND user_id = ?3",
+                    rusqlite::params![100 + user_id, chat.0, user_id],
+                )
+                .unwrap();
+            }
+            db::upsert_user(conn, chat.0, 5, "Never joined", None, None).unwrap();
+            db::set_notify_pref(conn, chat.0, 4, db::NotifyPref::Off).unwrap();
+        })
+        .await;
+
+        let options = AllOptions {
+            newest: Some(2),
+            ..AllOptions::default()
+        };
+        let targets = load_tag_targets(chat, "Group", &db, &state, &options).await;
+        assert_eq!(targets.tracked, 2);
+        let ids: Vec<i64> = targets.users.iter().map(|u| u.user_id).collect();
+        assert_eq!(ids, [3]);
+        assert_eq!(targets.skipped, ["1 users opted out"]);
+    }
+
+    #[tokio::test]
+    async fn bot_sweep_keeps_chats_it_couldnt_check_for_the_next_start() {
+        let db = test_db();
+        db.call(|conn| db::upsert_user(conn, GROUP_ID, 5, "Alice", None, None).unwrap())
+            .await;
+        // Nothing listens there, so every get_chat_member fails
+        let bot = Bot::new("0:test").set_api_url("http://127.0.0.1:1/".parse().unwrap());
+
+        sweep_bots_once(bot, db.clone()).await;
+
+        let (done, pending) = db
+            .call(|conn| {
+                (
+                    db::get_meta(conn, BOT_SWEEP_META_KEY).unwrap(),
+                    db::get_meta(conn, BOT_SWEEP_PENDING_META_KEY).unwrap(),
+                )
+            })
+            .await;
+        assert_eq!(done, None);
+        assert_eq!(pending, Some(GROUP_ID.to_string()));
+    }
 }
@@ -0,0 +1,1047 @@
+use crate::{db, settings::Verbosity};
+use std::{collections::HashMap, fmt, str::FromStr};
+use teloxide::types::{CustomEmojiId, MessageEntity, MessageEntityKind, ParseMode, UserId};
+
+/// Telegram only notifies a limited number of mentions per message, so tags are split in chunks
+pub const MENTIONS_PER_MESSAGE: usize = 50;
+/// Longest name shown in a mention, in characters, so absurdly long names can't bloat a tag
+const MAX_MENTION_NAME_CHARS: usize = 64;
+/// Shown in place of a custom emoji by clients that can't display it, when the placeholder has none
+const DEFAULT_EMOJI_ALT: &str = "⭐";
+
+/// Markup language the tag messages are written in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Markup {
+    #[default]
+    MarkdownV2,
+    Html,
+}
+
+impl Markup {
+    pub fn parse_mode(self) -> ParseMode {
+        match self {
+            Markup::MarkdownV2 => ParseMode::MarkdownV2,
+            Markup::Html => ParseMode::Html,
+        }
+    }
+
+    /// Escapes user provided text so it's shown as is
+    pub fn escape(self, text: &str) -> String {
+        match self {
+            Markup::MarkdownV2 => escape_markdown_v2(text),
+            Markup::Html => escape_html(text),
+        }
+    }
+
+    /// Hides already escaped text behind a spoiler
+    pub fn spoiler(self, inner: &str) -> String {
+        match self {
+            Markup::MarkdownV2 => format!("||{}||", inner),
+            Markup::Html => format!("<tg-spoiler>{}</tg-spoiler>", inner),
+        }
+    }
+
+    pub fn bold(self, inner: &str) -> String {
+        match self {
+            Markup::MarkdownV2 => format!("*{}*", inner),
+            Markup::Html => format!("<b>{}</b>", inner),
+        }
+    }
+
+    pub fn italic(self, inner: &str) -> String {
+        match self {
+            Markup::MarkdownV2 => format!("_{}_", inner),
+            Markup::Html => format!("<i>{}</i>", inner),
+        }
+    }
+
+    /// Links an already escaped label to a user, which notifies them even without a username
+    pub fn user_link(self, label: &str, user_id: i64) -> String {
+        match self {
+            Markup::MarkdownV2 => format!("[{}](tg://user?id={})", label, user_id),
+            Markup::Html => format!("<a href=\"tg://user?id={}\">{}</a>", user_id, label),
+        }
+    }
+}
+
+impl FromStr for Markup {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "markdown" | "markdownv2" => Ok(Markup::MarkdownV2),
+            "html" => Ok(Markup::Html),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Markup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Markup::MarkdownV2 => "markdown",
+            Markup::Html => "html",
+        })
+    }
+}
+
+/// Styling applied to the visible label of each mention
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MentionStyle {
+    #[default]
+    Plain,
+    Bold,
+    Italic,
+}
+
+impl MentionStyle {
+    /// Wraps an already escaped label, escaping always has to happen first
+    pub fn apply(self, markup: Markup, label: &str) -> String {
+        match self {
+            MentionStyle::Plain => label.to_string(),
+            MentionStyle::Bold => markup.bold(label),
+            MentionStyle::Italic => markup.italic(label),
+        }
+    }
+}
+
+impl FromStr for MentionStyle {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "plain" => Ok(MentionStyle::Plain),
+            "bold" => Ok(MentionStyle::Bold),
+            "italic" => Ok(MentionStyle::Italic),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for MentionStyle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MentionStyle::Plain => "plain",
+            MentionStyle::Bold => "bold",
+            MentionStyle::Italic => "italic",
+        })
+    }
+}
+
+/// How tag messages are written, from the chat settings
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TagFormat {
+    pub markup: Markup,
+    pub style: MentionStyle,
+    /// Mention users as @username when they have one
+    pub use_usernames: bool,
+    /// Sort users by name and split them in alphabetical sections with a heading each
+    pub sections: bool,
+    /// How much is written around the mentions, from progress messages to notes
+    pub verbosity: Verbosity,
+    /// Show only the initials of users mentioned by link, to keep big tags short
+    pub compact: bool,
+    /// Deal the users out over the messages round-robin, so every message starts with early users
+    pub interleave: bool,
+}
+
+/// Builds the safest mention of a user for the tag format, followed by their /nick label if any
+///
+/// Every mention goes through here so escaping is handled in a single place.
+pub fn best_mention(user: &db::User, format: TagFormat) -> String {
+    let mention = match mention_username(user, format) {
+        Some(username) => {
+            let label = format.markup.escape(&format!("@{}", username));
+            format.style.apply(format.markup, &label)
+        }
+        // tg://user?id= links work for all users, even without a username
+        None => {
+            let label = if format.compact {
+                initials(&link_label(user))
+            } else {
+                link_label(user)
+            };
+            let label = format.markup.escape(&label);
+            let label = format.style.apply(format.markup, &label);
+            format.markup.user_link(&label, user.user_id)
+        }
+    };
+
+    match &user.label {
+        // Labels would undo the point of compact mentions
+        Some(_) if format.compact => mention,
+        Some(label) => format!(
+            "{} {}",
+            mention,
+            format.markup.escape(&format!("({})", label))
+        ),
+        None => mention,
+    }
+}
+
+/// How likely a tag is to notify a user, depending on how they're mentioned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reach {
+    /// Mentioned as @username, which always notifies
+    Username,
+    /// Mentioned by id link, and /cantag confirmed it notifies them
+    ConfirmedLink,
+    /// Mentioned by id link, which Telegram may not deliver depending on the user's privacy settings
+    UncertainLink,
+    /// Mentioned by id link although /cantag found it doesn't notify them, with no username to use
+    UnlikelyLink,
+}
+
+/// How likely the mention `best_mention` builds for a user is to notify them
+pub fn reach(user: &db::User, format: TagFormat) -> Reach {
+    if mention_username(user, format).is_some() {
+        return Reach::Username;
+    }
+    match user.id_mention_works {
+        Some(true) => Reach::ConfirmedLink,
+        Some(false) => Reach::UnlikelyLink,
+        None => Reach::UncertainLink,
+    }
+}
+
+/// Username a user is mentioned with, if theirs can be linked and the format calls for usernames
+/// or /cantag found that id link mentions don't notify them
+fn mention_username(user: &db::User, format: TagFormat) -> Option<&str> {
+    let prefer_username = format.use_usernames || user.id_mention_works == Some(false);
+    user.username
+        .as_deref()
+        .filter(|name| prefer_username && is_valid_username(name))
+}
+
+/// Mention of a user without any markup, as @username when possible or just their name otherwise
+///
+/// Only used as a fallback when a formatted message is refused, names alone don't notify anyone.
+fn plain_mention(user: &db::User) -> String {
+    let mention = match user
+        .username
+        .as_deref()
+        .filter(|name| is_valid_username(name))
+    {
+        Some(username) => format!("@{}", username),
+        None => link_label(user),
+    };
+
+    match &user.label {
+        Some(label) => format!("{} ({})", mention, label),
+        None => mention,
+    }
+}
+
+/// Whether a username can be mentioned as @username, Telegram only links 5 to 32 letters, digits or underscores
+fn is_valid_username(username: &str) -> bool {
+    (5..=32).contains(&username.len())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Text of a link mention, never blank since Telegram drops links without text
+fn link_label(user: &db::User) -> String {
+    [user.display_name(), user.first_name.as_str()]
+        .into_iter()
+        .map(str::trim)
+        .find(|name| !name.is_empty())
+        .map(truncate_name)
+        .unwrap_or_else(|| format!("user {}", user.user_id))
+}
+
+/// Initials of a name, such as "JD" for "John Doe", up to three letters
+///
+/// Names without any alphanumeric word start, like a lone emoji, keep their first character.
+fn initials(name: &str) -> String {
+    let initials: String = name
+        .split_whitespace()
+        .filter_map(|word| word.chars().find(|c| c.is_alphanumeric()))
+        .take(3)
+        .flat_map(char::to_uppercase)
+        .collect();
+    if initials.is_empty() {
+        name.chars().take(1).collect()
+    } else {
+        initials
+    }
+}
+
+/// Cuts a name to `MAX_MENTION_NAME_CHARS`, ending it with an ellipsis when it was longer
+fn truncate_name(name: &str) -> String {
+    truncate_to(name, MAX_MENTION_NAME_CHARS)
+}
+
+/// Cuts a name to `max` characters, ending it with an ellipsis when it was longer
+fn truncate_to(name: &str, max: usize) -> String {
+    match name.char_indices().nth(max) {
+        Some((end, _)) => format!("{}…", name[..end].trim_end()),
+        None => name.to_string(),
+    }
+}
+
+/// Numbers the users mentioned by link under the same name, such as "Alex", "Alex #2" and "Alex #3"
+///
+/// Users are numbered by id so the same user keeps the same number from one tag to the next. The
+/// number only changes the visible name, the link still points to the user's id.
+fn disambiguate(users: &[db::User], format: TagFormat) -> Vec<db::User> {
+    let mut users = users.to_vec();
+    // Initials are meant to be ambiguous, numbering them would undo compact mentions
+    if format.compact {
+        return users;
+    }
+
+    let mut by_name: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, user) in users.iter().enumerate() {
+        if mention_username(user, format).is_none() {
+            by_name
+                .entry(link_label(user).to_lowercase())
+                .or_default()
+                .push(i);
+        }
+    }
+
+    for mut same_name in by_name.into_values().filter(|same| same.len() > 1) {
+        same_name.sort_by_key(|&i| users[i].user_id);
+        for (n, &i) in same_name.iter().enumerate().skip(1) {
+            let suffix = format!(" #{}", n + 1);
+            let base = link_label(&users[i]);
+            let base = truncate_to(&base, MAX_MENTION_NAME_CHARS - suffix.chars().count());
+            users[i].override_name = Some(format!("{}{}", base, suffix));
+        }
+    }
+
+    users
+}
+
+/// Builds the space separated mention list for the given users
+pub fn build_mentions(users: &[db::User], format: TagFormat) -> String {
+    let mentions: Vec<String> = users.iter().map(|u| best_mention(u, format)).collect();
+
+    mentions.join(" ")
+}
+
+/// A single message of a tag
+#[derive(Debug, Clone)]
+pub struct TagChunk {
+    /// Text of the message, in the markup of the tag format
+    pub text: String,
+    /// The same message without markup, sent if Telegram refuses to parse `text`
+    pub plain: String,
+    /// How many users are mentioned in the message
+    pub user_count: usize,
+    /// Whether the message should be sent without notification sound
+    pub silent: bool,
+    /// The message as plain text and entities, only when the header has custom emoji, which
+    /// have to be sent as entities
+    pub custom_emoji: Option<EntityText>,
+}
+
+/// Text along with the entities that format it, for messages sent without a parse mode
+#[derive(Debug, Clone)]
+pub struct EntityText {
+    pub text: String,
+    pub entities: Vec<MessageEntity>,
+}
+
+/// Builds the tag messages, the (already escaped) header goes on top of the first one
+///
+/// Users that prefer silent tags are mentioned in separate messages, after everyone else. Users
+/// sharing a name are numbered across the whole tag, not just within each message.
+pub fn build_tag_messages(header: &str, users: &[db::User], format: TagFormat) -> Vec<TagChunk> {
+    let users = disambiguate(users, format);
+    let mut chunks = if format.sections {
+        section_chunks(&users, format)
+    } else {
+        mention_chunks(&users, format)
+    };
+
+    if !header.is_empty()
+        && let Some(first) = chunks.first_mut()
+    {
+        let (header, emoji) = replace_custom_emoji(format.markup, header);
+        first.text = format!("{}\n{}", header, first.text);
+        first.plain = format!("{}\n{}", to_plain(format.markup, &header), first.plain);
+
+        if !emoji.is_empty() {
+            let mut custom = to_entities(format.markup, &first.text);
+            // The header starts the message, so offsets within it hold for the whole message
+            for (offset, alt, id) in emoji {
+                let Some(before) = custom.text.get(..offset) else {
+                    continue;
+                };
+                if custom.text[offset..].starts_with(&alt) {
+                    custom.entities.push(MessageEntity::custom_emoji(
+                        CustomEmojiId(id),
+                        utf16_len(before),
+                        utf16_len(&alt),
+                    ));
+                }
+            }
+            custom.entities.sort_by_key(|e| e.offset);
+            first.custom_emoji = Some(custom);
+        }
+    }
+
+    chunks
+}
+
+/// Replaces the `{emoji:<id>}` and `{emoji:<id>:<alt>}` placeholders of an already escaped
+/// header with their alt emoji
+///
+/// Returns the placeholders found, as the byte offset of their alt in the plain header, the alt
+/// and the custom emoji id. Placeholders without a numeric id are left as they are.
+fn replace_custom_emoji(markup: Markup, header: &str) -> (String, Vec<(usize, String, String)>) {
+    let plain = to_plain(markup, header);
+    let mut header = header.to_string();
+    let mut emoji = Vec::new();
+    let mut shift: isize = 0;
+
+    let mut rest = plain.as_str();
+    let mut consumed = 0;
+    while let Some(start) = rest.find("{emoji:") {
+        let body = &rest[start + "{emoji:".len()..];
+        let placeholder = body.find('}').map(|end| &body[..end]).and_then(|inner| {
+            let (id, alt) = inner.split_once(':').unwrap_or((inner, DEFAULT_EMOJI_ALT));
+            let valid_id = (1..=20).contains(&id.len()) && id.chars().all(|c| c.is_ascii_digit());
+            let valid_alt =
+                !alt.is_empty() && alt.chars().count() <= 8 && !alt.contains(char::is_whitespace);
+            (valid_id && valid_alt).then(|| (inner.len(), id.to_string(), alt.to_string()))
+        });
+
+        let Some((inner_len, id, alt)) = placeholder else {
+            consumed += start + 1;
+            rest = &rest[start + 1..];
+            continue;
+        };
+        let full_len = "{emoji:".len() + inner_len + 1;
+        let literal = &rest[start..start + full_len];
+
+        // Escaping works character by character, so the escaped placeholder is in the header as is
+        header = header.replacen(&markup.escape(literal), &markup.escape(&alt), 1);
+        let offset = (consumed + start) as isize + shift;
+        shift += alt.len() as isize - full_len as isize;
+        emoji.push((offset as usize, alt, id));
+
+        consumed += start + full_len;
+        rest = &rest[start + full_len..];
+    }
+
+    (header, emoji)
+}
+
+/// Length of text in UTF-16 code units, the unit entity offsets are counted in
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Turns text in the markup into plain text and the entities formatting it
+///
+/// Only the formatting the bot writes itself is understood: styles, spoilers and user links.
+pub fn to_entities(markup: Markup, text: &str) -> EntityText {
+    let mut plain = String::with_capacity(text.len());
+    let mut entities = Vec::new();
+    // Open styles, with the UTF-16 offset they started at and the link target for links
+    let mut open: Vec<(String, usize, Option<String>)> = Vec::new();
+
+    let mut close = |name: &str, start: usize, target: Option<String>, end: usize| {
+        let length = end - start;
+        let kind = match name {
+            "*" | "b" | "strong" => MessageEntityKind::Bold,
+            "_" | "i" | "em" => MessageEntityKind::Italic,
+            "__" | "u" | "ins" => MessageEntityKind::Underline,
+            "~" | "s" | "strike" | "del" => MessageEntityKind::Strikethrough,
+            "||" | "tg-spoiler" => MessageEntityKind::Spoiler,
+            "[" | "a" => {
+                let user_id = target
+                    .as_deref()
+                    .and_then(|url| url.strip_prefix("tg://user?id="))
+                    .and_then(|id| id.parse::<u64>().ok());
+                match user_id {
+                    Some(user_id) if length > 0 => {
+                        entities.push(MessageEntity::text_mention_id(
+                            UserId(user_id),
+                            start,
+                            length,
+                        ));
+                    }
+                    _ => {}
+                }
+                return;
+            }
+            _ => return,
+        };
+        if length > 0 {
+            entities.push(MessageEntity::new(kind, start, length));
+        }
+    };
+
+    match markup {
+        Markup::MarkdownV2 => {
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                let delimiter = match c {
+                    '\\' => {
+                        plain.extend(chars.next());
+                        continue;
+                    }
+                    '[' => {
+                        open.push(("[".to_string(), utf16_len(&plain), None));
+                        continue;
+                    }
+                    ']' if chars.peek() == Some(&'(') => {
+                        chars.next();
+                        let mut url = String::new();
+                        while let Some(c) = chars.next() {
+                            match c {
+                                ')' => break,
+                                '\\' => url.extend(chars.next()),
+                                c => url.push(c),
+                            }
+                        }
+                        if let Some(pos) = open.iter().rposition(|(name, ..)| name == "[") {
+                            let (name, start, _) = open.remove(pos);
+                            close(&name, start, Some(url), utf16_len(&plain));
+                        }
+                        continue;
+                    }
+                    '*' => "*",
+                    '~' => "~",
+                    '_' if chars.peek() == Some(&'_') => {
+                        chars.next();
+                        "__"
+                    }
+                    '_' => "_",
+                    '|' if chars.peek() == Some(&'|') => {
+                        chars.next();
+                        "||"
+                    }
+                    c => {
+                        plain.push(c);
+                        continue;
+                    }
+                };
+
+                match open.iter().rposition(|(name, ..)| name == delimiter) {
+                    Some(pos) => {
+                        let (name, start, _) = open.remove(pos);
+                        close(&name, start, None, utf16_len(&plain));
+                    }
+                    None => open.push((delimiter.to_string(), utf16_len(&plain), None)),
+                }
+            }
+        }
+        Markup::Html => {
+            let mut rest = text;
+            while let Some(pos) = rest.find(['<', '&']) {
+                plain.push_str(&rest[..pos]);
+                let tail = &rest[pos..];
+                let end = if tail.starts_with('<') {
+                    tail.find('>')
+                } else {
+                    tail.find(';')
+                };
+                let Some(end) = end else {
+                    rest = tail;
+                    break;
+                };
+                let token = &tail[..=end];
+                if let Some(tag) = token.strip_prefix("</") {
+                    let name = tag.trim_end_matches('>').trim();
+                    if let Some(pos) = open.iter().rposition(|(open, ..)| open == name) {
+                        let (name, start, target) = open.remove(pos);
+                        close(&name, start, target, utf16_len(&plain));
+                    }
+                } else if let Some(tag) = token.strip_prefix('<') {
+                    let tag = tag.trim_end_matches('>');
+                    let name = tag.split_whitespace().next().unwrap_or_default();
+                    let href = tag
+                        .split_once("href=\"")
+                        .and_then(|(_, rest)| rest.split_once('"'))
+                        .map(|(href, _)| to_plain(Markup::Html, href));
+                    open.push((name.to_string(), utf16_len(&plain), href));
+                } else {
+                    plain.push_str(&to_plain(Markup::Html, token));
+                }
+                rest = &tail[end + 1..];
+            }
+            plain.push_str(rest);
+        }
+    }
+
+    entities.sort_by_key(|e| e.offset);
+    EntityText {
+        text: plain,
+        entities,
+    }
+}
+
+/// Splits the mentions in messages, loud users first and silent ones after them
+fn mention_chunks(users: &[db::User], format: TagFormat) -> Vec<TagChunk> {
+    let (silent, loud): (Vec<db::User>, Vec<db::User>) = users
+        .iter()
+        .cloned()
+        .partition(|u| u.notify_pref == db::NotifyPref::Silent);
+
+    let mut chunks = Vec::new();
+    for (group, is_silent) in [(loud, false), (silent, true)] {
+        for chunk in split_users(&group, format.interleave) {
+            let plain: Vec<String> = chunk.iter().map(plain_mention).collect();
+            chunks.push(TagChunk {
+                text: format.markup.spoiler(&build_mentions(&chunk, format)),
+                plain: plain.join(" "),
+                user_count: chunk.len(),
+                silent: is_silent,
+                custom_emoji: None,
+            });
+        }
+    }
+
+    chunks
+}
+
+/// Splits users in messages of at most `MENTIONS_PER_MESSAGE`, either in consecutive runs or
+/// dealt out round-robin, keeping the same number of messages either way
+fn split_users(users: &[db::User], interleave: bool) -> Vec<Vec<db::User>> {
+    if !interleave {
+        return users
+            .chunks(MENTIONS_PER_MESSAGE)
+            .map(<[db::User]>::to_vec)
+            .collect();
+    }
+
+    let count = users.len().div_ceil(MENTIONS_PER_MESSAGE);
+    let mut chunks = vec![Vec::new(); count];
+    for (i, user) in users.iter().enumerate() {
+        chunks[i % count].push(user.clone());
+    }
+    chunks
+}
+
+/// Splits the mentions in alphabetical sections such as "A–F", each headed by its letter range
+///
+/// Whole letters are packed together while they fit in a single message, so a section never
+/// splits a letter unless that letter alone has too many users.
+fn section_chunks(users: &[db::User], format: TagFormat) -> Vec<TagChunk> {
+    let mut sorted: Vec<(char, String, &db::User)> = users
+        .iter()
+        .map(|user| {
+            let name = mention_username(user, format)
+                .map(String::from)
+                .unwrap_or_else(|| link_label(user))
+                .to_lowercase();
+            (section_letter(&name), name, user)
+        })
+        .collect();
+    sorted.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+
+    let mut letters: Vec<(char, Vec<db::User>)> = Vec::new();
+    for (letter, _, user) in sorted {
+        match letters.last_mut() {
+            Some((last, group)) if *last == letter => group.push(user.clone()),
+            _ => letters.push((letter, vec![user.clone()])),
+        }
+    }
+
+    let mut sections: Vec<(char, char, Vec<db::User>)> = Vec::new();
+    for (letter, group) in letters {
+        match sections.last_mut() {
+            Some((_, last, section)) if section.len() + group.len() <= MENTIONS_PER_MESSAGE => {
+                *last = letter;
+                section.extend(group);
+            }
+            _ => sections.push((letter, letter, group)),
+        }
+    }
+
+    let mut chunks = Vec::new();
+    for (first, last, section) in sections {
+        let range = if first == last {
+            first.to_string()
+        } else {
+            format!("{}–{}", first, last)
+        };
+        let heading = format.markup.bold(&format.markup.escape(&range));
+
+        // Dealing users out would scramble the alphabetical order of the section
+        let format = TagFormat {
+            interleave: false,
+            ..format
+        };
+        for mut chunk in mention_chunks(&section, format) {
+            chunk.text = format!("{}\n{}", heading, chunk.text);
+            chunk.plain = format!("{}\n{}", range, chunk.plain);
+            chunks.push(chunk);
+        }
+    }
+
+    chunks
+}
+
+/// Letter a (lowercased) name is filed under in sections, `#` when it doesn't start with a letter
+fn section_letter(name: &str) -> char {
+    name.chars()
+        .next()
+        .filter(|c| c.is_alphabetic())
+        .and_then(|c| c.to_uppercase().next())
+        .unwrap_or('#')
+}
+
+/// Strips the markup from already formatted text, undoing the escaping and dropping links and styling
+pub fn to_plain(markup: Markup, text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+
+    match markup {
+        Markup::MarkdownV2 => {
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => plain.extend(chars.next()),
+                    // The url of a link isn't part of the visible text
+                    ']' if chars.peek() == Some(&'(') => {
+                        for c in chars.by_ref() {
+                            if c == ')' {
+                                break;
+                            }
+                        }
+                    }
+                    '*' | '_' | '~' | '|' | '`' | '[' => {}
+                    _ => plain.push(c),
+                }
+            }
+        }
+        Markup::Html => {
+            let mut rest = text;
+            while let Some(pos) = rest.find(['<', '&']) {
+                plain.push_str(&rest[..pos]);
+                let tail = &rest[pos..];
+                let end = if tail.starts_with('<') {
+                    tail.find('>')
+                } else {
+                    tail.find(';')
+                };
+                let Some(end) = end else {
+                    rest = tail;
+                    break;
+                };
+                match &tail[..=end] {
+                    "&lt;" => plain.push('<'),
+                    "&gt;" => plain.push('>'),
+                    "&amp;" => plain.push('&'),
+                    "&quot;" => plain.push('"'),
+                    entity if entity.starts_with("&#") => {
+                        plain.extend(entity[2..end].parse::<u32>().ok().and_then(char::from_u32))
+                    }
+                    _ => {}
+                }
+                rest = &tail[end + 1..];
+            }
+            plain.push_str(rest);
+        }
+    }
+
+    plain
+}
+
+/// Checks that text would be accepted by Telegram's parser for the markup, without sending it
+///
+/// This mirrors the parser rules closely enough to catch badly escaped stored names.
+pub fn validate_markup(markup: Markup, text: &str) -> Result<(), String> {
+    match markup {
+        Markup::MarkdownV2 => validate_markdown_v2(text),
+        Markup::Html => validate_html(text),
+    }
+}
+
+fn validate_markdown_v2(text: &str) -> Result<(), String> {
+    let mut open: Vec<&str> = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line_start = true;
+
+    while let Some(c) = chars.next() {
+        let at_line_start = std::mem::replace(&mut line_start, c == '\n');
+        let delimiter = match c {
+            '\\' => {
+                if chars.next().is_none() {
+                    return Err("text ends with a lone backslash".to_string());
+                }
+                continue;
+            }
+            '*' => "*",
+            '~' => "~",
+            '`' => "`",
+            '_' if chars.peek() == Some(&'_') => {
+                chars.next();
+                "__"
+            }
+            '_' => "_",
+            '|' if chars.peek() == Some(&'|') => {
+                chars.next();
+                "||"
+            }
+            '[' => {
+                open.push("[");
+                continue;
+            }
+            ']' => {
+                if open.pop() != Some("[") || chars.next() != Some('(') {
+                    return Err("malformed link".to_string());
+                }
+                // Inside the url only ')' and '\' have to be escaped
+                loop {
+                    match chars.next() {
+                        Some(')') => break,
+                        Some('\\') => {
+                            chars.next();
+                        }
+                        Some(_) => {}
+                        None => return Err("unclosed link".to_string()),
+                    }
+                }
+                continue;
+            }
+            '>' if at_line_start => continue,
+            '(' | ')' | '>' | '#' | '+' | '-' | '=' | '|' | '{' | '}' | '.' | '!' => {
+                return Err(format!("character '{}' is not escaped", c));
+            }
+            _ => continue,
+        };
+
+        if open.last() == Some(&delimiter) {
+            open.pop();
+        } else if open.contains(&delimiter) {
+            return Err(format!("overlapping '{}' entities", delimiter));
+        } else {
+            open.push(delimiter);
+        }
+    }
+
+    match open.last() {
+        Some(delimiter) => Err(format!("'{}' is never closed", delimiter)),
+        None => Ok(()),
+    }
+}
+
+fn validate_html(text: &str) -> Result<(), String> {
+    const TAGS: &[&str] = &[
+        "b",
+        "strong",
+        "i",
+        "em",
+        "u",
+        "ins",
+        "s",
+        "strike",
+        "del",
+        "span",
+        "tg-spoiler",
+        "a",
+        "code",
+        "pre",
+        "blockquote",
+        "tg-emoji",
+    ];
+    const ENTITIES: &[&str] = &["lt", "gt", "amp", "quot"];
+
+    let mut open: Vec<String> = Vec::new();
+    let mut rest = text;
+
+    while let Some(pos) = rest.find(['<', '>', '&']) {
+        let tail = &rest[pos + 1..];
+        match rest.as_bytes()[pos] {
+            b'<' => {
+                let end = tail.find('>').ok_or("unclosed tag")?;
+                let tag = &tail[..end];
+                match tag.strip_prefix('/') {
+                    Some(name) => {
+                        if open.pop().as_deref() != Some(name.trim()) {
+                            return Err(format!("unexpected closing tag </{}>", name));
+                        }
+                    }
+                    None => {
+                        let name = tag.split_whitespace().next().unwrap_or_default();
+                        if !TAGS.contains(&name) {
+                            return Err(format!("unsupported tag <{}>", name));
+                        }
+                        open.push(name.to_string());
+                    }
+                }
+                rest = &tail[end + 1..];
+            }
+            b'&' => {
+                let end = tail.find(';').ok_or("unterminated '&' entity")?;
+                let entity = &tail[..end];
+                let numeric = entity
+                    .strip_prefix('#')
+                    .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+                if !numeric && !ENTITIES.contains(&entity) {
+                    return Err(format!("unknown entity &{};", entity));
+                }
+                rest = &tail[end + 1..];
+            }
+            _ => return Err("character '>' is not escaped".to_string()),
+        }
+    }
+
+    match open.last() {
+        Some(tag) => Err(format!("<{}> is never closed", tag)),
+        None => Ok(()),
+    }
+}
+
+/// Escapes special characters for MarkdownV2 parsing
+pub fn escape_markdown_v2(text: &str) -> String {
+    let special_chars = [
+        '\\', '_', '*', '[', ']', '(', ')', '~', '`', '>', '#', '+', '-', '=', '|', '{', '}', '.',
+        '!',
+    ];
+    let mut result = String::with_capacity(text.len() * 2);
+
+    for c in text.chars() {
+        if special_chars.contains(&c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+
+    result
+}
+
+/// Escapes special characters for HTML parsing
+pub fn escape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(user_id: i64, first_name: &str, username: Option<&str>) -> db::User {
+        db::User::new(user_id, first_name, username)
+    }
+
+    fn format(markup: Markup) -> TagFormat {
+        TagFormat {
+            markup,
+            ..TagFormat::default()
+        }
+    }
+
+    #[test]
+    fn best_mention_picks_the_safest_mention_for_every_combination() {
+        for markup in [Markup::MarkdownV2, Markup::Html] {
+            for username in [Some("alex_r"), None] {
+                for id_mention_works in [Some(true), Some(false), None] {
+                    for use_usernames in [true, false] {
+                        let mut alex = user(7, "Alex", username);
+                        alex.id_mention_works = id_mention_works;
+                        let format = TagFormat {
+                            use_usernames,
+                            ..format(markup)
+                        };
+
+                        // Usernames when asked for, or when /cantag found links don't notify
+                        let by_username = username.is_some()
+                            && (use_usernames || id_mention_works == Some(false));
+                        let expected = match (markup, by_username) {
+                            (Markup::MarkdownV2, true) => r"@alex\_r",
+                            (Markup::Html, true) => "@alex_r",
+                            (Markup::MarkdownV2, false) => "[Alex](tg://user?id=7)",
+                            (Markup::Html, false) => "<a href=\"tg://user?id=7\">Alex</a>",
+                        };
+                        let case = format!(
+                            "{:?}, username {:?}, id mention works {:?}, use usernames {}",
+                            markup, username, id_mention_works, use_usernames
+                        );
+                        assert_eq!(best_mention(&alex, format), expected, "{}", case);
+                        assert_eq!(validate_markup(markup, expected), Ok(()), "{}", case);
+
+                        let expected_reach = match (by_username, id_mention_works) {
+                            (true, _) => Reach::Username,
+                            (false, Some(true)) => Reach::ConfirmedLink,
+                            (false, Some(false)) => Reach::UnlikelyLink,
+                            (false, None) => Reach::UncertainLink,
+                        };
+                        assert_eq!(reach(&alex, format), expected_reach, "{}", case);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn styled_mentions_escape_the_name_before_wrapping_it() {
+        let tom = user(1, "<Tom & *Jerry*>", None);
+        let bold = |markup| TagFormat {
+            style: MentionStyle::Bold,
+            ..format(markup)
+        };
+
+        let html = best_mention(&tom, bold(Markup::Html));
+        assert_eq!(
+            html,
+            "<a href=\"tg://user?id=1\"><b>&lt;Tom &amp; *Jerry*&gt;</b></a>"
+        );
+        assert_eq!(validate_markup(Markup::Html, &html), Ok(()));
+
+        let markdown = best_mention(&tom, bold(Markup::MarkdownV2));
+        assert_eq!(markdown, r"[*<Tom & \*Jerry\*\>*](tg://user?id=1)");
+        assert_eq!(validate_markup(Markup::MarkdownV2, &markdown), Ok(()));
+    }
+
+    #[test]
+    fn users_sharing_a_name_get_distinct_labels_and_keep_their_links() {
+        let alexes = [
+            user(3, "Alex", None),
+            user(1, "Alex", None),
+            user(2, "Alex", None),
+        ];
+        let chunks = build_tag_messages("", &alexes, format(Markup::Html));
+
+        assert_eq!(chunks.len(), 1);
+        let text = &chunks[0].text;
+        // Numbered by id, whatever the order they're tagged in
+        for link in [
+            "<a href=\"tg://user?id=1\">Alex</a>",
+            "<a href=\"tg://user?id=2\">Alex #2</a>",
+            "<a href=\"tg://user?id=3\">Alex #3</a>",
+        ] {
+            assert!(text.contains(link), "{} not in {}", link, text);
+        }
+    }
+
+    #[test]
+    fn long_names_are_cut_whole_characters_before_escaping() {
+        // Multibyte letters next to characters both markups escape
+        let name = "é_&".repeat(167);
+        assert_eq!(name.chars().count(), 501);
+        let long = user(1, &name, None);
+
+        let kept: String = name.chars().take(MAX_MENTION_NAME_CHARS).collect();
+        for markup in [Markup::MarkdownV2, Markup::Html] {
+            let mention = best_mention(&long, format(markup));
+            let label = format!("{}…", kept);
+            assert_eq!(mention, markup.user_link(&markup.escape(&label), 1));
+            assert_eq!(validate_markup(markup, &mention), Ok(()));
+            assert_eq!(to_plain(markup, &mention), label);
+            assert!(
+                mention.len() < 256,
+                "{:?} mention is {} bytes",
+                markup,
+                mention.len()
+            );
+        }
+    }
+}
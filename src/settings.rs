@@ -0,0 +1,418 @@
+use crate::mentions::{Markup, MentionStyle, TagFormat};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use regex::{Regex, RegexBuilder};
+use std::{
+    fmt::{self, Write},
+    str::FromStr,
+};
+
+/// Largest UTC offset accepted, real time zones stay within ±14 hours
+const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+/// Longest exclude pattern accepted
+const MAX_EXCLUDE_PATTERN_LEN: usize = 200;
+/// Cap on the compiled size of an exclude pattern, so huge repetitions are rejected up front
+const EXCLUDE_PATTERN_SIZE_LIMIT: usize = 1 << 20;
+/// Longest custom reply accepted for tags with nobody tracked
+const MAX_EMPTY_ROSTER_MESSAGE_LEN: usize = 500;
+/// Reply to tags when nobody is tracked in the chat yet, unless the chat set its own
+const DEFAULT_EMPTY_ROSTER_MESSAGE: &str =
+    "No users tracked yet. Users will be tracked as they send messages or join the group.";
+
+/// How much the bot writes around the tags themselves
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Only the tag messages, no progress messages or notes about skipped users
+    Silent,
+    /// Progress messages for tags split in several messages and notes about skipped users
+    #[default]
+    Normal,
+    /// A summary message after every tag, even the ones that fit in a single message
+    Verbose,
+}
+
+impl FromStr for Verbosity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "silent" => Ok(Verbosity::Silent),
+            "normal" => Ok(Verbosity::Normal),
+            "verbose" => Ok(Verbosity::Verbose),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Verbosity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Verbosity::Silent => "silent",
+            Verbosity::Normal => "normal",
+            Verbosity::Verbose => "verbose",
+        })
+    }
+}
+
+/// Per-chat settings, changed by admins with /config
+#[derive(Debug, Clone)]
+pub struct ChatSettings {
+    /// Members that joined less than this many hours ago are not tagged by /all
+    pub min_membership_hours: i64,
+    /// Leave out (and let /prune remove) users that look like deleted accounts
+    pub skip_deleted_accounts: bool,
+    /// Markup the tag messages are written in
+    pub parse_mode: Markup,
+    /// Styling applied to each mention label
+    pub mention_style: MentionStyle,
+    /// Offset from UTC, in minutes, of the chat's local time when it has no `timezone`
+    pub utc_offset_minutes: i32,
+    /// Time zone of the chat, used by schedules, quotas, cohorts and the activity chart
+    pub timezone: Option<Tz>,
+    /// Users whose name or username matches this regex are left out of tags
+    pub exclude_pattern: Option<String>,
+    /// Track users when they join or leave, not only when they write
+    pub track_membership: bool,
+    /// Put members that join in the cohort of their join month, such as 2024-09
+    pub auto_cohort: bool,
+    /// Send the messages of /all as replies to the command
+    pub reply_to_command: bool,
+    /// How much the bot writes around the tags
+    pub verbosity: Verbosity,
+    /// Leave the author of /all out of the tag when it replies to them, the reply already notifies them
+    pub skip_author: bool,
+    /// Mention users by their initials only, the mention still notifies them
+    pub compact_mentions: bool,
+    /// Keep the users of the chat in memory only, they are never written to the database
+    pub ephemeral: bool,
+    /// Reply to tags when nobody is tracked yet, `{chat}` is replaced by the chat title
+    pub empty_roster_message: Option<String>,
+    /// Spread the users over the messages of a tag round-robin instead of in consecutive runs
+    pub interleave: bool,
+    /// Refresh the admins of the chat in the background, every `ADMIN_SYNC_INTERVAL_MINS`
+    pub auto_admin_sync: bool,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            min_membership_hours: 0,
+            skip_deleted_accounts: true,
+            parse_mode: Markup::default(),
+            mention_style: MentionStyle::default(),
+            utc_offset_minutes: 0,
+            timezone: None,
+            exclude_pattern: None,
+            track_membership: true,
+            auto_cohort: false,
+            reply_to_command: true,
+            verbosity: Verbosity::default(),
+            skip_author: true,
+            compact_mentions: false,
+            ephemeral: false,
+            empty_roster_message: None,
+            interleave: false,
+            auto_admin_sync: false,
+        }
+    }
+}
+
+impl ChatSettings {
+    /// Names of the settings that can be changed with /config
+    pub const KEYS: &[&str] = &[
+        "min_membership_hours",
+        "skip_deleted_accounts",
+        "parse_mode",
+        "mention_style",
+        "utc_offset",
+        "timezone",
+        "exclude_pattern",
+        "track_membership",
+        "auto_cohort",
+        "reply_to_command",
+        "verbosity",
+        "skip_author",
+        "compact_mentions",
+        "ephemeral",
+        "empty_roster_message",
+        "interleave",
+        "auto_admin_sync",
+    ];
+
+    /// Updates a setting from its textual value, returning an error message for the user on failure
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "min_membership_hours" => {
+                self.min_membership_hours = parse_non_negative(value)?;
+            }
+            "skip_deleted_accounts" => {
+                self.skip_deleted_accounts = parse_bool(value)?;
+            }
+            "parse_mode" => {
+                self.parse_mode = value
+                    .parse()
+                    .map_err(|()| format!("Expected markdown or html, got {}", value))?;
+            }
+            "mention_style" => {
+                self.mention_style = value
+                    .parse()
+                    .map_err(|()| format!("Expected plain, bold or italic, got {}", value))?;
+            }
+            "utc_offset" => {
+                self.utc_offset_minutes = parse_utc_offset(value)?;
+            }
+            "timezone" => {
+                self.timezone = match value {
+                    "" | "none" => None,
+                    zone => Some(parse_timezone(zone)?),
+                };
+            }
+            "exclude_pattern" => {
+                self.exclude_pattern = match value {
+                    "" | "none" => None,
+                    pattern => {
+                        compile_exclude_pattern(pattern)?;
+                        Some(pattern.to_string())
+                    }
+                };
+            }
+            "track_membership" => {
+                self.track_membership = parse_bool(value)?;
+            }
+            "auto_cohort" => {
+                self.auto_cohort = parse_bool(value)?;
+            }
+            "reply_to_command" => {
+                self.reply_to_command = parse_bool(value)?;
+            }
+            "skip_author" => {
+                self.skip_author = parse_bool(value)?;
+            }
+            "compact_mentions" => {
+                self.compact_mentions = parse_bool(value)?;
+            }
+            "ephemeral" => {
+                self.ephemeral = parse_bool(value)?;
+            }
+            "interleave" => {
+                self.interleave = parse_bool(value)?;
+            }
+            "auto_admin_sync" => {
+                self.auto_admin_sync = parse_bool(value)?;
+            }
+            "empty_roster_message" => {
+                if value.chars().count() > MAX_EMPTY_ROSTER_MESSAGE_LEN {
+                    return Err(format!(
+                        "The message can be at most {} characters long",
+                        MAX_EMPTY_ROSTER_MESSAGE_LEN
+                    ));
+                }
+                self.empty_roster_message = match value {
+                    "" | "none" => None,
+                    message => Some(message.to_string()),
+                };
+            }
+            "verbosity" => {
+                self.verbosity = value
+                    .parse()
+                    .map_err(|()| format!("Expected silent, normal or verbose, got {}", value))?;
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown setting {}. Available settings: {}",
+                    key,
+                    Self::KEYS.join(", ")
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Offset of the chat's local time from UTC, when it has no `timezone`
+    pub fn utc_offset(&self) -> FixedOffset {
+        FixedOffset::east_opt(self.utc_offset_minutes * 60).unwrap_or_else(|| Utc.fix())
+    }
+
+    /// Name of the chat's time zone for replies, such as Europe/Rome or UTC+02:00
+    pub fn timezone_name(&self) -> String {
+        match self.timezone {
+            Some(tz) => tz.name().to_string(),
+            None => format!("UTC{}", self.utc_offset()),
+        }
+    }
+
+    /// A moment in the chat's local time
+    pub fn to_local(&self, at: DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self.timezone {
+            Some(tz) => at.with_timezone(&tz).fixed_offset(),
+            None => at.with_timezone(&self.utc_offset()),
+        }
+    }
+
+    /// The current time in the chat
+    pub fn local_now(&self) -> DateTime<FixedOffset> {
+        self.to_local(Utc::now())
+    }
+
+    /// Unix timestamp of a local time of the chat
+    ///
+    /// Times skipped by a daylight saving change are moved an hour later, repeated ones take the first.
+    pub fn local_timestamp(&self, local: NaiveDateTime) -> i64 {
+        match self.timezone {
+            Some(tz) => tz
+                .from_local_datetime(&local)
+                .earliest()
+                .or_else(|| {
+                    tz.from_local_datetime(&(local + Duration::hours(1)))
+                        .earliest()
+                })
+                .map(|at| at.timestamp())
+                .unwrap_or_else(|| local.and_utc().timestamp()),
+            None => local.and_utc().timestamp() - i64::from(self.utc_offset().local_minus_utc()),
+        }
+    }
+
+    /// Unix timestamp of the last midnight in the chat
+    pub fn local_midnight(&self) -> i64 {
+        let midnight = self
+            .local_now()
+            .date_naive()
+            .and_time(chrono::NaiveTime::MIN);
+        self.local_timestamp(midnight)
+    }
+
+    /// Number of the local day (days since the epoch) a unix timestamp falls on in the chat
+    pub fn local_day(&self, timestamp: i64) -> i64 {
+        let offset = DateTime::from_timestamp(timestamp, 0)
+            .map(|at| i64::from(self.to_local(at).offset().local_minus_utc()))
+            .unwrap_or_default();
+        (timestamp + offset).div_euclid(86400)
+    }
+
+    /// How tag messages should be written in the chat
+    pub fn tag_format(&self) -> TagFormat {
+        TagFormat {
+            markup: self.parse_mode,
+            style: self.mention_style,
+            use_usernames: false,
+            sections: false,
+            verbosity: self.verbosity,
+            compact: self.compact_mentions,
+            interleave: self.interleave,
+        }
+    }
+
+    /// Reply to tags when nobody is tracked in the chat yet
+    pub fn empty_roster_reply(&self, chat_title: &str) -> String {
+        self.empty_roster_message
+            .as_deref()
+            .unwrap_or(DEFAULT_EMPTY_ROSTER_MESSAGE)
+            .replace("{chat}", chat_title)
+    }
+
+    /// Human readable listing of every setting and its current value
+    pub fn describe(&self) -> String {
+        let mut out = String::from("Current settings:");
+        let _ = write!(out, "\nmin_membership_hours: {}", self.min_membership_hours);
+        let _ = write!(
+            out,
+            "\nskip_deleted_accounts: {}",
+            self.skip_deleted_accounts
+        );
+        let _ = write!(out, "\nparse_mode: {}", self.parse_mode);
+        let _ = write!(out, "\nmention_style: {}", self.mention_style);
+        let _ = write!(out, "\nutc_offset: {}", self.utc_offset());
+        match self.timezone {
+            Some(tz) => {
+                let _ = write!(out, "\ntimezone: {} (utc_offset is ignored)", tz.name());
+            }
+            None => {
+                let _ = write!(out, "\ntimezone: none");
+            }
+        }
+        let _ = write!(
+            out,
+            "\nexclude_pattern: {}",
+            self.exclude_pattern.as_deref().unwrap_or("none")
+        );
+        let _ = write!(out, "\ntrack_membership: {}", self.track_membership);
+        let _ = write!(out, "\nauto_cohort: {}", self.auto_cohort);
+        let _ = write!(out, "\nreply_to_command: {}", self.reply_to_command);
+        let _ = write!(out, "\nverbosity: {}", self.verbosity);
+        let _ = write!(out, "\nskip_author: {}", self.skip_author);
+        let _ = write!(out, "\ncompact_mentions: {}", self.compact_mentions);
+        let _ = write!(out, "\nephemeral: {}", self.ephemeral);
+        let _ = write!(
+            out,
+            "\nempty_roster_message: {}",
+            self.empty_roster_message.as_deref().unwrap_or("default")
+        );
+        let _ = write!(out, "\ninterleave: {}", self.interleave);
+        let _ = write!(out, "\nauto_admin_sync: {}", self.auto_admin_sync);
+        out
+    }
+}
+
+fn parse_non_negative(value: &str) -> Result<i64, String> {
+    match value.parse::<i64>() {
+        Ok(n) if n >= 0 => Ok(n),
+        _ => Err(format!("Expected a non-negative number, got {}", value)),
+    }
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "on" | "yes" | "1" => Ok(true),
+        "false" | "off" | "no" | "0" => Ok(false),
+        _ => Err(format!("Expected true or false, got {}", value)),
+    }
+}
+
+/// Compiles an exclude pattern, returning an error message for the user if it's invalid or too big
+pub fn compile_exclude_pattern(pattern: &str) -> Result<Regex, String> {
+    if pattern.len() > MAX_EXCLUDE_PATTERN_LEN {
+        return Err(format!(
+            "Patterns can be at most {} characters long",
+            MAX_EXCLUDE_PATTERN_LEN
+        ));
+    }
+
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(EXCLUDE_PATTERN_SIZE_LIMIT)
+        .build()
+        .map_err(|e| format!("Invalid pattern: {}", e))
+}
+
+/// Parses an IANA time zone name such as `Europe/Rome`, ignoring case
+fn parse_timezone(value: &str) -> Result<Tz, String> {
+    Tz::from_str_insensitive(value).map_err(|_| {
+        format!(
+            "Unknown time zone {}, expected a name like Europe/Rome or America/New_York",
+            value
+        )
+    })
+}
+
+/// Parses an offset such as `+02:00`, `-5` or `0` into minutes
+fn parse_utc_offset(value: &str) -> Result<i32, String> {
+    let invalid = || format!("Expected an offset like +02:00 or -5, got {}", value);
+
+    let (sign, rest) = match value.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours: i32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+    if !(0..60).contains(&minutes) {
+        return Err(invalid());
+    }
+
+    let offset = sign * (hours * 60 + minutes);
+    if hours < 0 || offset.abs() > MAX_UTC_OFFSET_MINUTES {
+        return Err(invalid());
+    }
+    Ok(offset)
+}
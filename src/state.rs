@@ -0,0 +1,400 @@
+use regex::Regex;
+use std::{
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    sync::{
+        Arc, Mutex as StdMutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use teloxide::types::{ChatId, MessageId, UserId};
+use tokio::sync::{Mutex, OwnedMutexGuard, Semaphore, SemaphorePermit};
+
+use crate::{config::Config, db, mentions::TagFormat, settings};
+
+/// How long a join is remembered, both the chat member update and the service message report it
+const JOIN_DEDUP_WINDOW: Duration = Duration::from_secs(10);
+/// How many rate limit hits are remembered for /ratelimit
+const RATE_LIMIT_HISTORY: usize = 20;
+/// How long a destructive command waits for its confirmation token
+const CONFIRMATION_WINDOW: Duration = Duration::from_secs(60);
+/// How often the number of messages tracked in a chat is logged
+const TRACKING_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// How long the chats offered to inline queries are reused instead of loaded on every keystroke
+const INLINE_CHATS_TTL: Duration = Duration::from_secs(30);
+
+/// Chat, user and command a confirmation token was handed out for
+type ConfirmationKey = (ChatId, UserId, &'static str);
+
+/// A `RetryAfter` answer received from Telegram
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitHit {
+    pub at: Instant,
+    pub chat_id: ChatId,
+    /// How long Telegram asked to wait
+    pub retry_after: Duration,
+}
+
+/// A chat offered in the inline tag results, with the users its tag mentions
+pub struct InlineChat {
+    pub chat_id: ChatId,
+    pub title: String,
+    pub users: Vec<db::User>,
+    pub format: TagFormat,
+}
+
+/// In-memory state shared by the handlers, lost on restart
+pub struct State {
+    /// Per-chat locks held while a tag is being sent, so tags in one chat don't interleave
+    tag_locks: StdMutex<HashMap<ChatId, Arc<Mutex<()>>>>,
+    /// Set while a /backup is running
+    backup_running: AtomicBool,
+    /// Caps how many tag messages are being sent at once across every chat
+    send_permits: Semaphore,
+    /// When each recent join was first seen, to log it only once
+    recent_joins: StdMutex<HashMap<(ChatId, UserId), Instant>>,
+    /// Compiled exclude patterns, so each one is only compiled once
+    exclude_patterns: StdMutex<HashMap<String, Regex>>,
+    /// Most recent rate limit hits, oldest first
+    rate_limit_hits: StdMutex<VecDeque<RateLimitHit>>,
+    /// Size of the send semaphore, to report how many permits are in use
+    max_concurrent_sends: usize,
+    /// When the cooldown of each command ends in each chat
+    cooldowns: StdMutex<HashMap<(ChatId, &'static str), Instant>>,
+    /// Messages of the last tag sent in each chat and when it was sent, for /undo
+    last_tags: StdMutex<HashMap<ChatId, (Instant, Vec<MessageId>)>>,
+    /// Token each user has to send back to run a destructive command, and when it expires
+    confirmations: StdMutex<HashMap<ConfirmationKey, (String, Instant)>>,
+    /// Messages tracked in each chat since the window started, logged once per window
+    tracked_messages: StdMutex<HashMap<ChatId, (Instant, usize)>>,
+    /// Users of chats in ephemeral mode, never written to the database
+    ephemeral_users: StdMutex<HashMap<ChatId, HashMap<i64, db::User>>>,
+    /// Chats last offered to inline queries and when they were loaded
+    inline_chats: StdMutex<Option<(Instant, Arc<Vec<InlineChat>>)>>,
+}
+
+impl State {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            tag_locks: StdMutex::default(),
+            backup_running: AtomicBool::default(),
+            send_permits: Semaphore::new(config.max_concurrent_sends),
+            recent_joins: StdMutex::default(),
+            exclude_patterns: StdMutex::default(),
+            rate_limit_hits: StdMutex::default(),
+            max_concurrent_sends: config.max_concurrent_sends,
+            cooldowns: StdMutex::default(),
+            last_tags: StdMutex::default(),
+            confirmations: StdMutex::default(),
+            tracked_messages: StdMutex::default(),
+            ephemeral_users: StdMutex::default(),
+            inline_chats: StdMutex::default(),
+        }
+    }
+
+    /// Waits for a free slot before sending a tag message, the slot is released when the permit drops
+    pub async fn send_permit(&self) -> SemaphorePermit<'_> {
+        self.send_permits
+            .acquire()
+            .await
+            .expect("send semaphore is never closed")
+    }
+
+    /// Sends permits currently free, and how many there are in total
+    pub fn send_permits_available(&self) -> (usize, usize) {
+        (
+            self.send_permits.available_permits(),
+            self.max_concurrent_sends,
+        )
+    }
+
+    /// Remembers that Telegram asked to slow down, forgetting the oldest hit past the history size
+    pub fn record_rate_limit(&self, chat_id: ChatId, retry_after: Duration) {
+        let mut hits = self
+            .rate_limit_hits
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if hits.len() >= RATE_LIMIT_HISTORY {
+            hits.pop_front();
+        }
+        hits.push_back(RateLimitHit {
+            at: Instant::now(),
+            chat_id,
+            retry_after,
+        });
+    }
+
+    /// The remembered rate limit hits, oldest first
+    pub fn rate_limit_hits(&self) -> Vec<RateLimitHit> {
+        self.rate_limit_hits
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Takes the tag lock of a chat, or returns `None` if a tag is already in progress there
+    pub fn try_lock_tag(&self, chat_id: ChatId) -> Option<OwnedMutexGuard<()>> {
+        let lock = self
+            .tag_locks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(chat_id)
+            .or_default()
+            .clone();
+
+        lock.try_lock_owned().ok()
+    }
+
+    /// Records a join, returning `false` if the same join was already seen moments ago
+    pub fn note_join(&self, chat_id: ChatId, user_id: UserId) -> bool {
+        let now = Instant::now();
+        let mut joins = self.recent_joins.lock().unwrap_or_else(|e| e.into_inner());
+        joins.retain(|_, seen| now.duration_since(*seen) < JOIN_DEDUP_WINDOW);
+
+        match joins.entry((chat_id, user_id)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+
+    /// Forgets a recent join once the user leaves, so a quick rejoin is reported again
+    pub fn forget_join(&self, chat_id: ChatId, user_id: UserId) {
+        self.recent_joins
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(chat_id, user_id));
+    }
+
+    /// Compiled form of an exclude pattern, `None` if it doesn't compile
+    pub fn exclude_regex(&self, pattern: &str) -> Option<Regex> {
+        let mut patterns = self
+            .exclude_patterns
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(regex) = patterns.get(pattern) {
+            return Some(regex.clone());
+        }
+
+        let regex = settings::compile_exclude_pattern(pattern).ok()?;
+        patterns.insert(pattern.to_string(), regex.clone());
+        Some(regex)
+    }
+
+    /// Chats offered to inline queries, if loaded recently enough to be reused
+    pub fn inline_chats(&self) -> Option<Arc<Vec<InlineChat>>> {
+        let cached = self.inline_chats.lock().unwrap_or_else(|e| e.into_inner());
+        cached
+            .as_ref()
+            .filter(|(loaded_at, _)| loaded_at.elapsed() < INLINE_CHATS_TTL)
+            .map(|(_, chats)| chats.clone())
+    }
+
+    /// Remembers the chats offered to inline queries, for the next keystrokes
+    pub fn cache_inline_chats(&self, chats: Vec<InlineChat>) -> Arc<Vec<InlineChat>> {
+        let chats = Arc::new(chats);
+        *self.inline_chats.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some((Instant::now(), chats.clone()));
+        chats
+    }
+
+    /// Time left before `command` can be used again in a chat, `None` if it can be used now
+    pub fn cooldown_remaining(&self, chat_id: ChatId, command: &'static str) -> Option<Duration> {
+        let cooldowns = self.cooldowns.lock().unwrap_or_else(|e| e.into_inner());
+        let ends_at = cooldowns.get(&(chat_id, command))?;
+        ends_at
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Starts a cooldown of the given length for `command` in a chat
+    pub fn start_cooldown(&self, chat_id: ChatId, command: &'static str, cooldown: Duration) {
+        self.cooldowns
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((chat_id, command), Instant::now() + cooldown);
+    }
+
+    /// Waits for a user to confirm `command` with `token`, replacing any previous token of theirs
+    pub fn request_confirmation(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        command: &'static str,
+        token: String,
+    ) {
+        let mut confirmations = self.confirmations.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        confirmations.retain(|_, (_, expires_at)| *expires_at > now);
+        confirmations.insert(
+            (chat_id, user_id, command),
+            (token, now + CONFIRMATION_WINDOW),
+        );
+    }
+
+    /// Whether `token` confirms the pending `command` of a user, using up the confirmation if so
+    pub fn confirm(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        command: &'static str,
+        token: &str,
+    ) -> bool {
+        let mut confirmations = self.confirmations.lock().unwrap_or_else(|e| e.into_inner());
+        let key = (chat_id, user_id, command);
+        let confirmed = confirmations
+            .get(&key)
+            .is_some_and(|(expected, expires_at)| {
+                expected.eq_ignore_ascii_case(token) && *expires_at > Instant::now()
+            });
+        if confirmed {
+            confirmations.remove(&key);
+        }
+        confirmed
+    }
+
+    /// Counts a message tracked in a chat, returning the count to log once `TRACKING_LOG_INTERVAL` passed
+    pub fn count_tracked_message(&self, chat_id: ChatId) -> Option<usize> {
+        let mut tracked = self
+            .tracked_messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let (started, count) = tracked.entry(chat_id).or_insert((Instant::now(), 0));
+        *count += 1;
+        if started.elapsed() < TRACKING_LOG_INTERVAL {
+            return None;
+        }
+
+        let logged = *count;
+        *started = Instant::now();
+        *count = 0;
+        Some(logged)
+    }
+
+    /// Tracks a user of an ephemeral chat in memory, keeping what was already known about them
+    pub fn remember_ephemeral(&self, chat_id: ChatId, user: db::User) {
+        let mut chats = self
+            .ephemeral_users
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        match chats.entry(chat_id).or_default().entry(user.user_id) {
+            Entry::Occupied(mut entry) => {
+                let known = entry.get_mut();
+                known.first_name = user.first_name;
+                known.username = user.username;
+                known.last_seen = user.last_seen.or(known.last_seen);
+                known.joined_at = user.joined_at.or(known.joined_at);
+                known.cohort = known.cohort.take().or(user.cohort);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(user);
+            }
+        }
+    }
+
+    /// Changes a user of an ephemeral chat in place, returning whether they were tracked
+    pub fn update_ephemeral(
+        &self,
+        chat_id: ChatId,
+        user_id: i64,
+        update: impl FnOnce(&mut db::User),
+    ) -> bool {
+        let mut chats = self
+            .ephemeral_users
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        match chats
+            .get_mut(&chat_id)
+            .and_then(|users| users.get_mut(&user_id))
+        {
+            Some(user) => {
+                update(user);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A user of an ephemeral chat, if tracked
+    pub fn ephemeral_user(&self, chat_id: ChatId, user_id: i64) -> Option<db::User> {
+        self.ephemeral_users
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&chat_id)
+            .and_then(|users| users.get(&user_id))
+            .cloned()
+    }
+
+    /// Forgets a user of an ephemeral chat, such as one that left
+    pub fn forget_ephemeral(&self, chat_id: ChatId, user_id: i64) {
+        let mut chats = self
+            .ephemeral_users
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(users) = chats.get_mut(&chat_id) {
+            users.remove(&user_id);
+        }
+    }
+
+    /// Forgets a user in every ephemeral chat, returning how many chats tracked them
+    pub fn forget_ephemeral_everywhere(&self, user_id: i64) -> usize {
+        self.ephemeral_users
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values_mut()
+            .filter_map(|users| users.remove(&user_id))
+            .count()
+    }
+
+    /// Users tracked in memory for a chat
+    pub fn ephemeral_users(&self, chat_id: ChatId) -> Vec<db::User> {
+        self.ephemeral_users
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&chat_id)
+            .map(|users| users.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Takes every user tracked in memory for a chat, when it leaves ephemeral mode
+    pub fn take_ephemeral_users(&self, chat_id: ChatId) -> Vec<db::User> {
+        self.ephemeral_users
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&chat_id)
+            .map(|users| users.into_values().collect())
+            .unwrap_or_default()
+    }
+
+    /// Remembers the messages of the tag just sent in a chat, replacing the previous one
+    pub fn record_tag(&self, chat_id: ChatId, message_ids: Vec<MessageId>) {
+        self.last_tags
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(chat_id, (Instant::now(), message_ids));
+    }
+
+    /// Takes the messages of the last tag sent in a chat, if it was sent less than `window` ago
+    pub fn take_last_tag(&self, chat_id: ChatId, window: Duration) -> Option<Vec<MessageId>> {
+        let (sent_at, message_ids) = self
+            .last_tags
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&chat_id)?;
+        (sent_at.elapsed() <= window).then_some(message_ids)
+    }
+
+    /// Marks a backup as running, returning `false` if one already is
+    pub fn start_backup(&self) -> bool {
+        !self.backup_running.swap(true, Ordering::SeqCst)
+    }
+
+    /// Marks the running backup as finished
+    pub fn finish_backup(&self) {
+        self.backup_running.store(false, Ordering::SeqCst);
+    }
+}